@@ -1,134 +1,207 @@
-use crate::util::MarketDays;
-use chrono::{Datelike, Duration, NaiveDate, Utc};
+use crate::{
+  auth::Clients,
+  batch::{batch, table_spec, Values},
+  config::DatasetConfig,
+  journal::Savepoint,
+  logging::Progress,
+  metrics,
+  report::{self, Phase},
+  retry::{give_up, retry},
+  shutdown,
+  sink::Sink,
+  symbols::lookup,
+  util::{sort_bars, split_provisional, Bar, MarketDays}
+};
+use chrono::{Datelike, Duration, NaiveDate, NaiveTime, Utc};
 use polygon_io::{
-  client::Client,
-  core::Candle,
   core::grouped::{Locale, Market, GroupedParams}
 };
 use std::{
-  cmp, process,
+  cmp,
+  collections::HashSet,
   sync::{Arc, Mutex},
   time::Instant
 };
 use threadpool::ThreadPool;
+use tracing::{debug, info, info_span, warn, Span};
 use zdb::{
   calendar::ToNaiveDateTime,
-  schema::{Column, ColumnType, PartitionBy, Schema},
+  schema::Schema,
   table::Table
 };
-use std::sync::atomic::{AtomicUsize, Ordering};
 
 fn download_agg1d_year(
   year: i32,
   thread_pool: &ThreadPool,
-  agg1d: &mut Table,
-  client: &Client
+  agg1d: &mut dyn Sink,
+  clients: &Clients,
+  config: &DatasetConfig
 ) {
   let now = Instant::now();
-  let from = match agg1d.partition_meta.get(&year.to_string()) {
-    Some(meta) => meta.to_ts.to_naive_date_time().date() + Duration::days(1),
+  let _span = info_span!("partition", partition = year).entered();
+  metrics::set_partition("agg1d", &year.to_string());
+  let from = match agg1d.partition_to_ts(&year.to_string()) {
+    Some(to_ts) => to_ts.to_naive_date_time().date() + Duration::days(1),
     None => NaiveDate::from_ymd(year, 1, 1)
   };
+  let from = cmp::max(from, config.from);
   let to = cmp::min(
     NaiveDate::from_ymd(year + 1, 1, 1),
-    Utc::now().naive_utc().date()
+    config.until(Utc::now().naive_utc().date())
   );
   let market_days = (MarketDays { from, to }).collect::<Vec<_>>();
   if from >= to || market_days.len() == 0 {
-    eprintln!("Already downloaded agg1d until {}!", from - Duration::days(1));
+    info!("Already downloaded until {}", from - Duration::days(1));
     return;
   }
-  let candles = Arc::new(Mutex::new(Vec::<Candle>::new()));
+  let candles = Arc::new(Mutex::new(Vec::<Bar>::new()));
 
-  eprintln!("Downloading agg1d in {}..{}", from, to);
-  let num_days = market_days.len();
-  let counter = Arc::new(AtomicUsize::new(0));
-  eprintln!("{:3} / {} days", 0, num_days);
+  info!("Downloading {}..{}", from, to);
+  let fetch_start = Instant::now();
+  let progress = Progress::new("days", market_days.len());
+  let span = Span::current();
   for day in market_days.into_iter() {
     let candles_year = Arc::clone(&candles);
-    let mut client = client.clone();
+    let mut client = clients.next();
     let grouped_params = GroupedParams::new().unadjusted(true).params;
-    let counter = counter.clone();
+    let progress = progress.clone();
+    let span = span.clone();
+    let policy = config.retry;
     thread_pool.execute(move || {
-      // Retry up to 10 times
-      for j in 0..10 {
-        match client.get_grouped(Locale::US, Market::Stocks, day, Some(&grouped_params)) {
-          Ok(mut resp) => {
-            candles_year.lock().unwrap().append(&mut resp.results);
-            counter.fetch_add(1, Ordering::Relaxed);
-            println!("\x1b[1A\x1b[K{:3} / {} days [{}, {}]", counter.load(Ordering::Relaxed), num_days, day, resp.results_count);
-            return;
-          }
-          Err(e) => {
-            eprintln!("{}: get_grouped retry {}: {}\n", day, j + 1, e.to_string());
-            std::thread::sleep(std::time::Duration::from_secs(j + 1));
-          }
+      let _span = info_span!(parent: &span, "fetch", %day).entered();
+      if shutdown::requested() {
+        return;
+      }
+      let res = retry("agg1d", "get_grouped", &policy, || {
+        client.get_grouped(Locale::US, Market::Stocks, day, Some(&grouped_params))
+      });
+      match res {
+        Ok(mut resp) => {
+          candles_year.lock().unwrap().extend(resp.results.drain(..).map(Bar::from));
+          progress.inc(&format!("{}, {}", day, resp.results_count));
         }
+        Err(e) => give_up("agg1d", "get_grouped", &e)
       }
-      eprintln!("{}: failure", &day);
-      process::exit(1);
     });
   }
   thread_pool.join();
+  report::phase("agg1d", Phase::Fetch, fetch_start.elapsed());
+  // Writing part of a partition would leave gaps that look downloaded
+  if !progress.is_done() {
+    shutdown::discard("agg1d", &year.to_string());
+    return;
+  }
 
-  // Sort by ts, symbol
   let mut candles = candles.lock().unwrap();
+  // Grouped bars can change until a while after the 16:00 close
+  let mut provisional =
+    split_provisional(&mut candles, config.provisional_from(NaiveTime::from_hms(16, 0, 0)));
+  write_agg1d(agg1d, &mut candles, &year.to_string());
+  if provisional.len() > 0 {
+    info!("Writing {} provisional candles", provisional.len());
+    let from_ts = provisional.iter().map(|c| c.ts).min().unwrap();
+    let to_ts = provisional.iter().map(|c| c.ts).max().unwrap();
+    for partition in table_spec("agg1d").unwrap().partitions(from_ts, to_ts) {
+      agg1d.savepoint(Savepoint::Provisional, &partition);
+    }
+    write_agg1d(agg1d, &mut provisional, &year.to_string());
+  }
+
+  info!("Done in {}s", now.elapsed().as_secs());
+}
+
+// Sorts, filters and writes candles then flushes. Shared by the REST and flat file importers.
+pub fn write_agg1d(agg1d: &mut dyn Sink, candles: &mut Vec<Bar>, label: &str) {
   let num_candles = candles.len();
-  eprintln!("{}: Sorting {} candles", year, num_candles);
-  candles.sort_unstable_by(|c1, c2| {
-    if c1.ts == c2.ts {
-      c1.symbol.cmp(&c2.symbol)
-    } else {
-      c1.ts.cmp(&c2.ts)
+  debug!(label, "Sorting {} candles", num_candles);
+  report::timed("agg1d", Phase::Sort, || sort_bars(candles));
+  // Filter out crazy tickers
+  // https://github.com/polygon-io/issues/issues/3
+  candles.retain(|c| {
+    let is_good = c.symbol.chars().all(|c| c.is_ascii_graphic());
+    if !is_good {
+      let date = c.ts.to_naive_date_time().date();
+      warn!(%date, symbol = %c.symbol, "Bad symbol");
     }
+    is_good
   });
-  eprintln!("{}: Writing {} candles", year, num_candles);
-  for c in candles.drain(..) {
-    // Filter out crazy tickers
-    // https://github.com/polygon-io/issues/issues/3
-    if !c.symbol.chars().all(|c| c.is_ascii_graphic()) {
-      let date = c.ts.to_naive_date_time().date();
-      eprintln!("{}: Bad symbol {}", date, c.symbol);
-      continue;
+  debug!(label, "Writing {} candles", candles.len());
+  let rows = batch(label, vec![
+    ("ts", Values::Timestamp(candles.iter().map(|c| c.ts).collect())),
+    ("sym", Values::Str(candles.iter_mut().map(|c| std::mem::take(&mut c.symbol)).collect())),
+    ("open", Values::F64(candles.iter().map(|c| c.open).collect())),
+    ("high", Values::F64(candles.iter().map(|c| c.high).collect())),
+    ("low", Values::F64(candles.iter().map(|c| c.low).collect())),
+    ("close", Values::F64(candles.iter().map(|c| c.close).collect())),
+    ("volume", Values::U64(candles.iter().map(|c| c.volume).collect())),
+  ]);
+  candles.clear();
+  report::timed("agg1d", Phase::Write, || agg1d.append(rows));
+  debug!(label, "Flushing {} candles", num_candles);
+  report::timed("agg1d", Phase::Flush, || agg1d.flush());
+}
+
+// Symbols with a candle in agg1d on days [from, to], which bounds what agg1m and trades have
+// for those days whether they come from the REST API or flat files. Only symbols that traded
+// when `traded`.
+pub fn agg1d_symbols(
+  agg1d: &Table,
+  from: NaiveDate,
+  to: NaiveDate,
+  traded: bool
+) -> HashSet<String> {
+  let mut res = HashSet::<String>::new();
+  let from_ts = from.and_hms(0, 0, 0).timestamp_nanos();
+  let to_ts = (to + Duration::days(1)).and_hms(0, 0, 0).timestamp_nanos();
+  for partition in agg1d.partition_iter(from_ts, to_ts, vec!["ts", "sym", "volume"]) {
+    let ts = partition[0].get_i64();
+    let sym_indexes = partition[1].get_u16();
+    let volumes = partition[2].get_u64();
+    for i in 0..ts.len() {
+      if ts[i] < from_ts || ts[i] >= to_ts || (traded && volumes[i] == 0) {
+        continue;
+      }
+      match lookup(&partition[1].symbols, sym_indexes[i] as usize) {
+        Some(sym) => {
+          res.insert(sym.clone());
+        }
+        None => warn!(sym_i = sym_indexes[i], "Bad agg1d symbol index")
+      }
     }
-    agg1d.put_timestamp(c.ts);
-    agg1d.put_symbol(c.symbol);
-    agg1d.put_f64(c.open);
-    agg1d.put_f64(c.high);
-    agg1d.put_f64(c.low);
-    agg1d.put_f64(c.close);
-    agg1d.put_u64(c.volume);
-    agg1d.write();
   }
-  eprintln!("{}: Flushing {} candles", year, num_candles);
-  agg1d.flush();
 
-  eprintln!("{}: done in {}s", year, now.elapsed().as_secs());
+  res
 }
 
-pub fn download_agg1d(thread_pool: &ThreadPool, client: &mut Client) {
-  let now = Instant::now();
-  // Setup DB
-  let schema = Schema::new("agg1d")
-    .add_cols(vec![
-      Column::new("ts", ColumnType::Timestamp).with_resolution(24 * 60 * 60 * 1_000_000_000),
-      Column::new("sym", ColumnType::Symbol16).with_sym_name("us_equities"),
-      Column::new("open", ColumnType::F64),
-      Column::new("high", ColumnType::F64),
-      Column::new("low", ColumnType::F64),
-      Column::new("close", ColumnType::F64),
-      Column::new("volume", ColumnType::U64)
-    ])
-    .partition_by(PartitionBy::Year);
+pub fn agg1d_schema() -> Schema { table_spec("agg1d").unwrap().schema(Vec::new()) }
 
-  let mut agg1d = Table::create_or_open(schema).expect("Could not open table");
-  let from = 2004;
-  let to = (Utc::now().date() - Duration::days(1)).year();
-  eprintln!("Downloading agg1d");
-  for i in (from..=to).rev() {
-    if agg1d.partition_meta.get(&format!("{}", i)).is_none() || i == to {
-      download_agg1d_year(i, &thread_pool, &mut agg1d, client);
+pub fn download_agg1d(
+  thread_pool: &ThreadPool,
+  clients: &Clients,
+  agg1d: &mut dyn Sink,
+  config: &DatasetConfig
+) {
+  let now = Instant::now();
+  let _span = info_span!("download", dataset = "agg1d").entered();
+  let from = config.from.year();
+  let to = (config.until(Utc::now().naive_utc().date()) - Duration::days(1)).year();
+  info!("Downloading");
+  let mut years = (from..=to).rev().collect::<Vec<_>>();
+  // Streams must be in ts order
+  if agg1d.is_stream() {
+    years.reverse();
+  }
+  for i in years {
+    if shutdown::requested() {
+      break;
+    }
+    let partition = i.to_string();
+    // Replaced partitions resume from before their provisional rows
+    let is_replaced = agg1d.replaced().contains(&partition);
+    if agg1d.partition_to_ts(&partition).is_none() || i == to || is_replaced {
+      download_agg1d_year(i, &thread_pool, agg1d, clients, config);
     }
   }
-  eprintln!("Downloaded agg1d in {}s", now.elapsed().as_secs());
+  agg1d.finish();
+  info!("Downloaded in {}s", now.elapsed().as_secs());
 }
@@ -1,26 +1,35 @@
 extern crate polygon_io;
-use chrono::{Datelike, Duration, NaiveDate, Utc};
+use crate::{
+  agg1d::agg1d_symbols,
+  auth::Clients,
+  batch::{batch, table_spec, Values},
+  config::DatasetConfig,
+  journal::Savepoint,
+  logging::Progress,
+  metrics,
+  report::{self, Phase},
+  retry::{give_up, retry, ErrorClass},
+  shutdown,
+  sink::Sink,
+  util::{sort_bars, split_provisional, Bar}
+};
+use chrono::{Datelike, Duration, NaiveDate, NaiveTime, Utc};
 use polygon_io::{
-  client::Client,
-  core::Candle,
   core::aggs::AggsParams,
   core::aggs::Timespan
 };
 use std::{
   cmp,
-  collections::HashSet,
-  io::ErrorKind,
-  process,
   sync::{Arc, Mutex},
   time::Instant
 };
 use threadpool::ThreadPool;
+use tracing::{debug, info, info_span, warn, Span};
 use zdb::{
   calendar::ToNaiveDateTime,
-  schema::{Column, ColumnType, PartitionBy, Schema},
+  schema::Schema,
   table::Table
 };
-use std::sync::atomic::{AtomicUsize, Ordering};
 
 fn add_month(date: &NaiveDate) -> NaiveDate {
   let mut to_year = date.year();
@@ -48,13 +57,34 @@ fn sub_month(date: &NaiveDate) -> NaiveDate {
   NaiveDate::from_ymd(to_year, to_month, date.day())
 }
 
+// The days of the month starting `month_start` that are left to download, both inclusive, after
+// the partition's last day `resumed`
+fn month_range(
+  month_start: NaiveDate,
+  resumed: Option<NaiveDate>,
+  config: &DatasetConfig,
+  today: NaiveDate
+) -> Option<(NaiveDate, NaiveDate)> {
+  let from = cmp::max(resumed.map_or(month_start, |last| last.succ()), config.from);
+  let mut to = cmp::min(add_month(&month_start) - Duration::days(1), today);
+  if let Some(last) = config.to {
+    to = cmp::min(to, last);
+  }
+  if from > to {
+    return None;
+  }
+
+  Some((from, to))
+}
+
 fn download_agg1m_month(
   year: i32,
   month: u32,
   thread_pool: &ThreadPool,
   agg1d: &Table,
-  agg1m: &mut Table,
-  client: &mut Client
+  agg1m: &mut dyn Sink,
+  clients: &Clients,
+  config: &DatasetConfig
 ) {
   // The US equity market is open from 4:00-20:00 which is 960 minutes.
   // We could download up to 50k bars/request with &limit=50000, which is 52 days.
@@ -63,165 +93,187 @@ fn download_agg1m_month(
   // go with months for now.
   let now = Instant::now();
   let month_format = format!("{}-{:02}", year, month);
-  let from = match agg1m.partition_meta.get(&month_format) {
-    Some(meta) => meta.to_ts.to_naive_date_time().date() + Duration::days(1),
-    None => NaiveDate::from_ymd(year, month, 1)
-  };
+  let _span = info_span!("partition", partition = %month_format).entered();
+  metrics::set_partition("agg1m", &month_format);
+  let resumed = agg1m.partition_to_ts(&month_format).map(|to_ts| to_ts.to_naive_date_time().date());
   let month_start = NaiveDate::from_ymd(year, month, 1);
-  let to = cmp::min(add_month(&month_start) - Duration::days(1), Utc::today().naive_utc());
-  if from >= to {
-    eprintln!("Already downloaded agg1m until {}!", from - Duration::days(1));
-    return;
-  }
-  eprintln!(
-    "{}: Scanning agg1d for symbols in {}..{}",
-    month_format, from, to
-  );
-  let mut symbols = HashSet::<String>::default();
-  let partitions = agg1d.partition_iter(
-    from.and_hms(0, 0, 0).timestamp_nanos(),
-    to.and_hms(0, 0, 0).timestamp_nanos(),
-    vec!["sym", "volume"],
-  );
-  for partition in partitions {
-    let sym_indexes = partition[0].get_u16();
-    let volumes = partition[1].get_u64();
-    volumes.iter().zip(sym_indexes.iter()).for_each(|(v, sym_i)| {
-      if *v > 0 {
-        let sym = partition[0].symbols[*sym_i as usize - 1].clone();
-        symbols.insert(sym);
-      }
-    });
+  let (from, to) = match month_range(month_start, resumed, config, Utc::today().naive_utc()) {
+    Some(range) => range,
+    None => {
+      info!(?resumed, "Nothing left to download");
+      return;
+    }
+  };
+  info!("Scanning agg1d for symbols in {}..{}", from, to);
+  let mut symbols = agg1d_symbols(agg1d, from, to, true);
+  if let Some(only) = &config.symbols {
+    symbols.retain(|sym| only.contains(sym));
   }
   if symbols.len() == 0{
-    eprintln!("{}: no agg1d", month_format);
+    warn!("No agg1d");
     return;
   }
 
-  eprintln!(
-    "{}: Downloading candles for {} symbols",
-    month_format,
-    symbols.len()
-  );
-  let candles = Arc::new(Mutex::new(Vec::<Candle>::new()));
-  let counter = Arc::new(AtomicUsize::new(0));
-  let num_syms = symbols.len();
-  eprintln!("{:5} / {:5} symbols", 0, num_syms);
+  info!("Downloading candles for {} symbols", symbols.len());
+  let candles = Arc::new(Mutex::new(Vec::<Bar>::new()));
+  let fetch_start = Instant::now();
+  let progress = Progress::new("symbols", symbols.len());
+  let span = Span::current();
   for sym in symbols.iter() {
     let month_format = month_format.clone();
     let sym = sym.clone();
     let candles_year = Arc::clone(&candles);
-    let mut client = client.clone();
+    let mut client = clients.next();
     let params = AggsParams::new().unadjusted(true).limit(50_000).params;
-    let counter = counter.clone();
+    let progress = progress.clone();
+    let span = span.clone();
+    let policy = config.retry;
     thread_pool.execute(move || {
-      // Retry up to 50 times
-      for j in 0..50 {
-        match client.get_aggs(&sym, 1, Timespan::Minute, from, to, Some(&params)) {
-          Ok(mut resp) => {
-            candles_year.lock().unwrap().append(&mut resp.results);
-            counter.fetch_add(1, Ordering::Relaxed);
-            println!("\x1b[1A\x1b[K{:5} / {:5} symbols [{}]", counter.load(Ordering::Relaxed), num_syms, sym);
-            return;
-          }
-          Err(e) => {
-            match e.kind() {
-              // Give up if there's no data. We'll get the ticks later.
-              ErrorKind::UnexpectedEof => {
-                eprintln!("{} {:6}: No data\n", month_format, sym);
-                return;
-              }
-              _ => {
-                eprintln!(
-                  "{} {:6}: get_agg1m retry {}: {}\n",
-                  month_format,
-                  sym,
-                  j + 1,
-                  e.to_string()
-                );
-                std::thread::sleep(std::time::Duration::from_secs(j + 1));
-              }
-            }
-          }
+      let _span = info_span!(parent: &span, "fetch", symbol = %sym).entered();
+      if shutdown::requested() {
+        return;
+      }
+      let res = retry("agg1m", "get_aggs", &policy, || {
+        client.get_aggs(&sym, 1, Timespan::Minute, from, to, Some(&params))
+      });
+      match res {
+        Ok(mut resp) => {
+          candles_year.lock().unwrap().extend(resp.results.drain(..).map(Bar::from));
+          progress.inc(&sym);
         }
+        // Give up if there's no data. We'll get the ticks later.
+        Err(e) if e.class == ErrorClass::NoData => {
+          debug!("No data");
+          report::no_data("agg1m", &month_format, &sym);
+          progress.inc(&sym);
+        }
+        Err(e) => give_up("agg1m", "get_aggs", &e)
       }
-      eprintln!("{} {:6}: failure", month_format, sym);
-      process::exit(1);
     });
   }
 
   thread_pool.join();
+  report::phase("agg1m", Phase::Fetch, fetch_start.elapsed());
+  // Writing part of a partition would leave gaps that look downloaded
+  if !progress.is_done() {
+    shutdown::discard("agg1m", &month_format);
+    return;
+  }
 
   let mut candles = candles.lock().unwrap();
-  let num_candles = candles.len();
-  // Sort by ts, symbol
-  eprintln!("{}: Sorting {} candles", month_format, num_candles);
-  candles.sort_unstable_by(|c1, c2| {
-    if c1.ts == c2.ts {
-      c1.symbol.cmp(&c2.symbol)
-    } else {
-      c1.ts.cmp(&c2.ts)
+  // Minute bars go until extended hours end at 20:00
+  let mut provisional =
+    split_provisional(&mut candles, config.provisional_from(NaiveTime::from_hms(20, 0, 0)));
+  write_agg1m(agg1m, &mut candles, &month_format);
+  if provisional.len() > 0 {
+    info!("Writing {} provisional candles", provisional.len());
+    // Evening bars are the next day in UTC, which can be in the next month's partition
+    let from_ts = provisional.iter().map(|c| c.ts).min().unwrap();
+    let to_ts = provisional.iter().map(|c| c.ts).max().unwrap();
+    for partition in table_spec("agg1m").unwrap().partitions(from_ts, to_ts) {
+      agg1m.savepoint(Savepoint::Provisional, &partition);
     }
-  });
-
-  eprintln!("{}: Writing {} candles", month_format, num_candles);
-  for c in candles.drain(..) {
-    agg1m.put_timestamp(c.ts);
-    agg1m.put_symbol(c.symbol);
-    agg1m.put_f64(c.open);
-    agg1m.put_f64(c.high);
-    agg1m.put_f64(c.low);
-    agg1m.put_f64(c.close);
-    agg1m.put_u32(c.volume as u32);
-    agg1m.write();
+    write_agg1m(agg1m, &mut provisional, &month_format);
   }
-  eprintln!("{}: Flushing {} candles", month_format, num_candles);
-  agg1m.flush();
-
-  eprintln!(
-    "{}: downloaded in {}s",
-    month_format,
-    now.elapsed().as_secs()
-  )
+
+  info!("Downloaded in {}s", now.elapsed().as_secs())
 }
 
-pub fn download_agg1m(thread_pool: &ThreadPool, client: &mut Client, column_dirs: Vec<&str>) {
+// Sorts and writes candles then flushes. Shared by the REST and flat file importers.
+pub fn write_agg1m(agg1m: &mut dyn Sink, candles: &mut Vec<Bar>, label: &str) {
+  let num_candles = candles.len();
+  debug!(label, "Sorting {} candles", num_candles);
+  report::timed("agg1m", Phase::Sort, || sort_bars(candles));
+  debug!(label, "Writing {} candles", num_candles);
+  let rows = batch(label, vec![
+    ("ts", Values::Timestamp(candles.iter().map(|c| c.ts).collect())),
+    ("sym", Values::Str(candles.iter_mut().map(|c| std::mem::take(&mut c.symbol)).collect())),
+    ("open", Values::F64(candles.iter().map(|c| c.open).collect())),
+    ("high", Values::F64(candles.iter().map(|c| c.high).collect())),
+    ("low", Values::F64(candles.iter().map(|c| c.low).collect())),
+    ("close", Values::F64(candles.iter().map(|c| c.close).collect())),
+    ("volume", Values::U32(candles.iter().map(|c| c.volume as u32).collect())),
+  ]);
+  candles.clear();
+  report::timed("agg1m", Phase::Write, || agg1m.append(rows));
+  debug!(label, "Flushing {} candles", num_candles);
+  report::timed("agg1m", Phase::Flush, || agg1m.flush());
+}
+
+pub fn agg1m_schema(column_dirs: Vec<&str>) -> Schema {
+  table_spec("agg1m").unwrap().schema(column_dirs)
+}
+
+pub fn download_agg1m(
+  thread_pool: &ThreadPool,
+  clients: &Clients,
+  agg1m: &mut dyn Sink,
+  config: &DatasetConfig
+) {
+  let _span = info_span!("download", dataset = "agg1m").entered();
   // Get existing symbols
   let agg1d =
     Table::open("agg1d").expect("Table agg1d must exist to load symbols to download in agg1m");
-  // Setup DB
-  let schema = Schema::new("agg1m")
-    .add_cols(vec![
-      Column::new("ts", ColumnType::Timestamp).with_resolution(60 * 1_000_000_000),
-      Column::new("sym", ColumnType::Symbol16).with_sym_name("us_equities"),
-      Column::new("open", ColumnType::F64),
-      Column::new("high", ColumnType::F64),
-      Column::new("low", ColumnType::F64),
-      Column::new("close", ColumnType::F64),
-      Column::new("volume", ColumnType::U32)
-    ])
-    .partition_dirs(column_dirs)
-    .partition_by(PartitionBy::Month);
-
-  let mut agg1m = Table::create_or_open(schema).expect("Could not open table");
-  let from = NaiveDate::from_ymd(2004, 1, 1);
+  let from = NaiveDate::from_ymd(config.from.year(), config.from.month(), 1);
   let today = Utc::now().naive_utc().date();
-  let to = NaiveDate::from_ymd(today.year(), today.month(), 1);
+  let last = config.to.map(|to| cmp::min(to, today)).unwrap_or(today);
+  let to = NaiveDate::from_ymd(last.year(), last.month(), 1);
+  let mut months = Vec::<NaiveDate>::new();
   let mut iter = to.clone();
-  while iter > from {
+  while iter >= from {
+    months.push(iter);
+    iter = sub_month(&iter);
+  }
+  // Streams must be in ts order
+  if agg1m.is_stream() {
+    months.reverse();
+  }
+  for iter in months {
+    if shutdown::requested() {
+      break;
+    }
     let formatted = format!("{}-{:02}", iter.year(), iter.month());
     let is_today = iter.year() == today.year() && iter.month() == today.month();
-    if agg1m.partition_meta.get(&formatted).is_none() || is_today {
-      eprintln!("Downloading agg1m {}", formatted);
+    // Replaced partitions resume from before their provisional rows
+    let is_replaced = agg1m.replaced().contains(&formatted);
+    if agg1m.partition_to_ts(&formatted).is_none() || is_today || is_replaced {
       download_agg1m_month(
         iter.year(),
         iter.month(),
         &thread_pool,
         &agg1d,
-        &mut agg1m,
-        client
+        agg1m,
+        clients,
+        config
       );
     }
-    iter = sub_month(&iter);
+  }
+  agg1m.finish();
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::retry::RetryPolicy;
+
+  #[test]
+  fn downloads_a_single_day() {
+    let day = |d| NaiveDate::from_ymd(2021, 3, d);
+    let mut config = DatasetConfig {
+      from:    day(4),
+      to:      Some(day(4)),
+      symbols: None,
+      retry:   RetryPolicy::for_dataset("agg1m"),
+      settle:  Duration::minutes(0)
+    };
+    let today = day(5);
+    assert_eq!(month_range(day(1), None, &config, today), Some((day(4), day(4))));
+    assert_eq!(month_range(day(1), Some(day(3)), &config, today), Some((day(4), day(4))));
+    assert_eq!(month_range(day(1), Some(day(4)), &config, today), None);
+
+    config.from = day(1);
+    config.to = None;
+    assert_eq!(month_range(day(1), Some(day(4)), &config, today), Some((day(5), day(5))));
+    assert_eq!(month_range(day(1), Some(day(5)), &config, today), None);
   }
 }
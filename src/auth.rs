@@ -0,0 +1,137 @@
+use crate::{
+  retry::{retry, status, ErrorClass, RetryPolicy},
+  util::MarketDays
+};
+use chrono::{Duration, Utc};
+use polygon_io::{
+  client::Client,
+  core::{
+    aggs::Timespan,
+    grouped::{Locale, Market}
+  }
+};
+use std::{
+  env, fs, process,
+  sync::atomic::{AtomicUsize, Ordering}
+};
+use tracing::{error, info, warn};
+
+// Keys from --api-key-file, one per line with # comments, or else POLYGON_KEY which may list
+// several separated by commas
+pub fn api_keys(key_file: Option<&str>) -> Vec<String> {
+  let (keys, source) = match key_file {
+    Some(path) => {
+      let data = fs::read_to_string(path).unwrap_or_else(|e| {
+        error!(path, error = %e, "Could not read API key file");
+        process::exit(1);
+      });
+      let keys = data
+        .lines()
+        .map(|l| l.split('#').next().unwrap().trim().to_string())
+        .collect::<Vec<_>>();
+      (keys, path.to_string())
+    }
+    None => {
+      let keys = env::var("POLYGON_KEY").unwrap_or_default();
+      (keys.split(',').map(|k| k.trim().to_string()).collect(), "POLYGON_KEY".to_string())
+    }
+  };
+  let keys = keys.into_iter().filter(|k| !k.is_empty()).collect::<Vec<_>>();
+  if keys.is_empty() {
+    error!(source = %source, "No Polygon API key. Set POLYGON_KEY or pass --api-key-file.");
+    process::exit(1);
+  }
+
+  keys
+}
+
+// Only shows enough of a key to tell which one it is
+fn key_name(key: &str) -> String { format!("{}...", key.chars().take(4).collect::<String>()) }
+
+const ENDPOINTS: [&str; 4] = ["grouped", "reference", "aggs", "trades"];
+
+// The endpoints each dataset needs
+pub fn endpoints(dataset: &str) -> &'static [&'static str] {
+  match dataset {
+    "agg1d" => &["grouped"],
+    "tickers" | "exchanges" => &["reference"],
+    "agg1m" => &["aggs"],
+    "trades" => &["trades"],
+    _ => &[]
+  }
+}
+
+// One cheap request per endpoint. BRK.A has few trades a day.
+fn probe(client: &mut Client, endpoint: &str) -> std::io::Result<()> {
+  let to = Utc::now().naive_utc().date();
+  let day = (MarketDays { from: to - Duration::days(10), to }).last().unwrap();
+  match endpoint {
+    "grouped" => client.get_grouped(Locale::US, Market::Stocks, day, None).map(|_| ()),
+    "reference" => client.get_exchanges().map(|_| ()),
+    "aggs" => client.get_aggs("AAPL", 1, Timespan::Day, day, day, None).map(|_| ()),
+    "trades" => client.get_all_trades("BRK.A", day).map(|_| ()),
+    _ => Ok(())
+  }
+}
+
+// A Client per key handed out in turn so requests spread across keys' rate limits
+pub struct Clients {
+  clients: Vec<Client>,
+  next:    AtomicUsize
+}
+
+impl Clients {
+  // Client::new reads polygon.json and the environment, so the key is swapped in after rather
+  // than through POLYGON_KEY, which every other thread would see
+  fn for_key(key: &str) -> Client {
+    let mut client = Client::new();
+    client.key = key.to_string();
+    client
+  }
+
+  // Logs which endpoints each key's plan can access and drops keys that are invalid or lack
+  // one of `needed`. Exits with one error if none are left.
+  pub fn validate(keys: &[String], needed: &[&str]) -> Clients {
+    let policy = RetryPolicy::for_dataset("auth");
+    let mut clients = Vec::<Client>::new();
+    for key in keys.iter() {
+      let key_name = key_name(key);
+      let mut client = Clients::for_key(key);
+      let mut allowed = Vec::<&str>::new();
+      let mut denied = Vec::<&str>::new();
+      for endpoint in ENDPOINTS.iter().copied() {
+        match retry("auth", endpoint, &policy, || probe(&mut client, endpoint)) {
+          Ok(_) => allowed.push(endpoint),
+          Err(e) if e.class == ErrorClass::NoData => allowed.push(endpoint),
+          Err(e) if status(&e.error) == Some(401) => {
+            error!(key = %key_name, error = %e.error, "Invalid API key");
+            denied.extend(ENDPOINTS.iter());
+            break;
+          }
+          Err(e) if e.class == ErrorClass::Client => denied.push(endpoint),
+          Err(e) => {
+            error!(key = %key_name, endpoint, error = %e, "Could not check API key");
+            process::exit(1);
+          }
+        }
+      }
+      if needed.iter().all(|e| allowed.contains(e)) {
+        info!(key = %key_name, ?allowed, ?denied, "API key ok");
+        clients.push(client);
+      } else {
+        warn!(key = %key_name, ?allowed, ?denied, ?needed, "Not using API key without access");
+      }
+    }
+    if clients.is_empty() {
+      error!(?needed, "No valid API key can access every endpoint needed");
+      process::exit(1);
+    }
+
+    Clients { clients, next: AtomicUsize::new(0) }
+  }
+
+  pub fn next(&self) -> Client {
+    let i = self.next.fetch_add(1, Ordering::Relaxed);
+    self.clients[i % self.clients.len()].clone()
+  }
+}
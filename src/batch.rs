@@ -0,0 +1,424 @@
+use arrow::{
+  array::{
+    ArrayRef, Float64Array, Int64Array, StringArray, TimestampNanosecondArray, UInt32Array,
+    UInt64Array, UInt8Array
+  },
+  datatypes::{DataType, Field, Schema as ArrowSchema, TimeUnit},
+  record_batch::RecordBatch
+};
+use crate::symbols::US_EQUITIES_TABLES;
+use std::sync::Arc;
+use zdb::{
+  calendar::ToNaiveDateTime,
+  schema::{Column, ColumnType, PartitionBy, Schema}
+};
+
+const DAY: i64 = 24 * 60 * 60 * 1_000_000_000;
+const MINUTE: i64 = 60 * 1_000_000_000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColumnKind {
+  Timestamp,
+  I64,
+  U64,
+  U32,
+  U8,
+  F64,
+  Symbol8,
+  Symbol16,
+  // Offset into the named StringHeap of the table
+  Heap(&'static str),
+  // Exchange ID which also gets exported as a "<col>_name" column
+  Exchange,
+  // Packed trade conditions which also get exported as a "<col>_names" column
+  Conditions
+}
+
+pub struct TableSpec {
+  pub name:             &'static str,
+  // How partitions are named in file names, which also decides how zdb partitions
+  pub partition_format: &'static str,
+  // Nanoseconds ts is rounded to, if any
+  pub ts_resolution:    Option<i64>,
+  pub columns:          Vec<(&'static str, ColumnKind)>
+}
+
+impl TableSpec {
+  // The zdb schema of these columns. Tables that can outgrow a disk take data dirs to put
+  // partitions in.
+  pub fn schema(&self, partition_dirs: Vec<&str>) -> Schema {
+    let columns = self
+      .columns
+      .iter()
+      .map(|(name, kind)| {
+        let shared = *name == "sym" && US_EQUITIES_TABLES.contains(&self.name);
+        let column = Column::new(name, match kind {
+          ColumnKind::Timestamp => ColumnType::Timestamp,
+          ColumnKind::I64 => ColumnType::I64,
+          ColumnKind::U64 | ColumnKind::Heap(_) => ColumnType::U64,
+          ColumnKind::U32 | ColumnKind::Conditions => ColumnType::U32,
+          ColumnKind::U8 | ColumnKind::Exchange => ColumnType::U8,
+          ColumnKind::F64 => ColumnType::F64,
+          ColumnKind::Symbol8 => ColumnType::Symbol8,
+          ColumnKind::Symbol16 => ColumnType::Symbol16
+        });
+        match (kind, self.ts_resolution) {
+          (ColumnKind::Timestamp, Some(resolution)) => column.with_resolution(resolution),
+          (ColumnKind::Symbol16, _) if shared => column.with_sym_name("us_equities"),
+          _ => column
+        }
+      })
+      .collect::<Vec<_>>();
+    let partition_by = match self.partition_format {
+      "%Y" => PartitionBy::Year,
+      "%Y-%m" => PartitionBy::Month,
+      _ => PartitionBy::Day
+    };
+    let schema = Schema::new(self.name).add_cols(columns);
+    let schema = match partition_dirs.len() {
+      0 => schema,
+      _ => schema.partition_dirs(partition_dirs)
+    };
+
+    schema.partition_by(partition_by)
+  }
+
+  // The partitions that rows in [from_ts, to_ts] fall in. That's the ones of the first and
+  // last, so ranges can't be longer than a partition.
+  pub fn partitions(&self, from_ts: i64, to_ts: i64) -> Vec<String> {
+    let mut res = vec![from_ts, to_ts]
+      .iter()
+      .map(|ts| ts.to_naive_date_time().format(self.partition_format).to_string())
+      .collect::<Vec<_>>();
+    res.dedup();
+
+    res
+  }
+}
+
+pub fn table_spec(table: &str) -> Option<TableSpec> {
+  use ColumnKind::*;
+  let res = match table {
+    "agg1d" => TableSpec {
+      name:             "agg1d",
+      partition_format: "%Y",
+      ts_resolution:    Some(DAY),
+      columns:          vec![
+        ("ts", Timestamp),
+        ("sym", Symbol16),
+        ("open", F64),
+        ("high", F64),
+        ("low", F64),
+        ("close", F64),
+        ("volume", U64),
+      ]
+    },
+    "agg1m" => TableSpec {
+      name:             "agg1m",
+      partition_format: "%Y-%m",
+      ts_resolution:    Some(MINUTE),
+      columns:          vec![
+        ("ts", Timestamp),
+        ("sym", Symbol16),
+        ("open", F64),
+        ("high", F64),
+        ("low", F64),
+        ("close", F64),
+        ("volume", U32),
+      ]
+    },
+    "trades" => TableSpec {
+      name:             "trades",
+      partition_format: "%Y-%m-%d",
+      ts_resolution:    None,
+      columns:          vec![
+        ("ts", Timestamp),
+        ("ts_participant", I64),
+        ("id", U64),
+        ("seq_id", U64),
+        ("sym", Symbol16),
+        ("size", U32),
+        ("price", F64),
+        ("cond", Conditions),
+        ("err", U8),
+        ("exchange", Exchange),
+        ("tape", U8),
+      ]
+    },
+    "quotes" => TableSpec {
+      name:             "quotes",
+      partition_format: "%Y-%m-%d",
+      ts_resolution:    None,
+      columns:          vec![
+        ("ts", Timestamp),
+        ("ts_participant", I64),
+        ("seq_id", U64),
+        ("sym", Symbol16),
+        ("bid_price", F64),
+        ("bid_size", U32),
+        ("bid_exchange", Exchange),
+        ("ask_price", F64),
+        ("ask_size", U32),
+        ("ask_exchange", Exchange),
+        ("cond", U32),
+        ("indicators", U32),
+        ("tape", U8),
+      ]
+    },
+    "tickers" => TableSpec {
+      name:             "tickers",
+      partition_format: "%Y",
+      ts_resolution:    Some(DAY),
+      columns:          vec![
+        ("ts", Timestamp),
+        ("sym", Symbol16),
+        ("name", Heap("name")),
+        ("primary_exchange", Symbol8),
+        ("type", Symbol8),
+        ("currency_name", Symbol8),
+        ("cik", Heap("cik")),
+        ("composite_figi", Heap("composite_figi")),
+        ("share_class_figi", Heap("share_class_figi")),
+        // Added after tickers tables existed, so they go last
+        ("market", Symbol8),
+        ("locale", Symbol8),
+        ("active", U8),
+        ("last_updated_utc", I64),
+        ("delisted_utc", I64),
+      ]
+    },
+    "ticker_events" => TableSpec {
+      name:             "ticker_events",
+      partition_format: "%Y",
+      ts_resolution:    Some(DAY),
+      columns:          vec![
+        ("ts", Timestamp),
+        ("sym", Symbol16),
+        ("event", Symbol8),
+        ("old", Heap("values")),
+        ("new", Heap("values")),
+      ]
+    },
+    "exchanges" => TableSpec {
+      name:             "exchanges",
+      partition_format: "%Y",
+      ts_resolution:    Some(DAY),
+      columns:          vec![
+        ("ts", Timestamp),
+        ("id", U8),
+        ("mic", Symbol8),
+        ("name", Symbol8),
+        ("type", Symbol8),
+        ("tape", Symbol8),
+      ]
+    },
+    _ => return None
+  };
+
+  Some(res)
+}
+
+pub enum Values {
+  Timestamp(Vec<i64>),
+  I64(Vec<i64>),
+  U64(Vec<u64>),
+  U32(Vec<u32>),
+  U8(Vec<u8>),
+  F64(Vec<f64>),
+  Str(Vec<String>)
+}
+
+impl Values {
+  pub fn len(&self) -> usize {
+    match self {
+      Values::Timestamp(v) | Values::I64(v) => v.len(),
+      Values::U64(v) => v.len(),
+      Values::U32(v) => v.len(),
+      Values::U8(v) => v.len(),
+      Values::F64(v) => v.len(),
+      Values::Str(v) => v.len()
+    }
+  }
+
+  pub fn retain(&mut self, mask: &[bool]) {
+    fn filter<T>(v: &mut Vec<T>, mask: &[bool]) {
+      let mut i = 0;
+      v.retain(|_| {
+        i += 1;
+        mask[i - 1]
+      });
+    }
+    match self {
+      Values::Timestamp(v) | Values::I64(v) => filter(v, mask),
+      Values::U64(v) => filter(v, mask),
+      Values::U32(v) => filter(v, mask),
+      Values::U8(v) => filter(v, mask),
+      Values::F64(v) => filter(v, mask),
+      Values::Str(v) => filter(v, mask)
+    }
+  }
+
+  // Leaves [0, at) in place and returns [at, len)
+  pub fn split_off(&mut self, at: usize) -> Values {
+    match self {
+      Values::Timestamp(v) => Values::Timestamp(v.split_off(at)),
+      Values::I64(v) => Values::I64(v.split_off(at)),
+      Values::U64(v) => Values::U64(v.split_off(at)),
+      Values::U32(v) => Values::U32(v.split_off(at)),
+      Values::U8(v) => Values::U8(v.split_off(at)),
+      Values::F64(v) => Values::F64(v.split_off(at)),
+      Values::Str(v) => Values::Str(v.split_off(at))
+    }
+  }
+
+  pub fn fmt_ts(ts: i64, ts_format: TsFormat) -> String {
+    match ts_format {
+      TsFormat::Iso => ts.to_naive_date_time().format("%Y-%m-%dT%H:%M:%S%.9fZ").to_string(),
+      TsFormat::Nanos => ts.to_string()
+    }
+  }
+
+  pub fn fmt_csv(&self, i: usize, ts_format: TsFormat) -> String {
+    match self {
+      Values::Timestamp(v) => Values::fmt_ts(v[i], ts_format),
+      Values::I64(v) => v[i].to_string(),
+      Values::U64(v) => v[i].to_string(),
+      Values::U32(v) => v[i].to_string(),
+      Values::U8(v) => v[i].to_string(),
+      Values::F64(v) => v[i].to_string(),
+      Values::Str(v) => {
+        let s = &v[i];
+        if s.contains(|c: char| c == ',' || c == '"' || c == '\n' || c == '\r') {
+          format!("\"{}\"", s.replace('"', "\"\""))
+        } else {
+          s.clone()
+        }
+      }
+    }
+  }
+
+  pub fn fmt_json(&self, i: usize, ts_format: TsFormat) -> String {
+    match self {
+      Values::Timestamp(v) => match ts_format {
+        TsFormat::Iso => serde_json::to_string(&Values::fmt_ts(v[i], ts_format)).unwrap(),
+        TsFormat::Nanos => v[i].to_string()
+      },
+      Values::F64(v) if !v[i].is_finite() => "null".to_string(),
+      Values::Str(v) => serde_json::to_string(&v[i]).unwrap(),
+      _ => self.fmt_csv(i, ts_format)
+    }
+  }
+
+  pub fn to_arrow(&self) -> (DataType, ArrayRef) {
+    match self {
+      Values::Timestamp(v) => (
+        DataType::Timestamp(TimeUnit::Nanosecond, Some("UTC".to_string())),
+        Arc::new(TimestampNanosecondArray::from_vec(v.clone(), Some("UTC".to_string())))
+      ),
+      Values::I64(v) => (DataType::Int64, Arc::new(Int64Array::from(v.clone()))),
+      Values::U64(v) => (DataType::UInt64, Arc::new(UInt64Array::from(v.clone()))),
+      Values::U32(v) => (DataType::UInt32, Arc::new(UInt32Array::from(v.clone()))),
+      Values::U8(v) => (DataType::UInt8, Arc::new(UInt8Array::from(v.clone()))),
+      Values::F64(v) => (DataType::Float64, Arc::new(Float64Array::from(v.clone()))),
+      Values::Str(v) => (
+        DataType::Utf8,
+        Arc::new(StringArray::from(v.iter().map(|s| s.as_str()).collect::<Vec<_>>()))
+      )
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TsFormat {
+  Iso,
+  Nanos
+}
+
+// Named columns of one partition, either read from zdb or on their way to a Sink
+pub struct Batch {
+  pub partition: String,
+  pub columns:   Vec<(String, Values)>
+}
+
+impl Batch {
+  pub fn num_rows(&self) -> usize {
+    self.columns.first().map(|(_name, values)| values.len()).unwrap_or(0)
+  }
+
+  pub fn timestamps(&self) -> &[i64] {
+    match self.columns.iter().find(|(name, _values)| name == "ts") {
+      Some((_name, Values::Timestamp(ts))) => ts,
+      _ => &[]
+    }
+  }
+
+  // Leaves rows [0, at) in place and returns rows [at, len) under the same partition name
+  pub fn split_off(&mut self, at: usize) -> Batch {
+    Batch {
+      partition: self.partition.clone(),
+      columns:   self
+        .columns
+        .iter_mut()
+        .map(|(name, values)| (name.clone(), values.split_off(at)))
+        .collect()
+    }
+  }
+
+  pub fn to_record_batch(&self) -> RecordBatch {
+    let mut fields = Vec::<Field>::with_capacity(self.columns.len());
+    let mut arrays = Vec::<ArrayRef>::with_capacity(self.columns.len());
+    for (name, values) in self.columns.iter() {
+      let (data_type, array) = values.to_arrow();
+      fields.push(Field::new(name, data_type, false));
+      arrays.push(array);
+    }
+
+    RecordBatch::try_new(Arc::new(ArrowSchema::new(fields)), arrays)
+      .expect("Columns must have the same length")
+  }
+}
+
+// Converts rows into a Batch for a Sink
+pub fn batch(label: &str, columns: Vec<(&str, Values)>) -> Batch {
+  Batch {
+    partition: label.to_string(),
+    columns:   columns.into_iter().map(|(name, values)| (name.to_string(), values)).collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const TABLES: [&str; 7] =
+    ["agg1d", "agg1m", "trades", "quotes", "tickers", "ticker_events", "exchanges"];
+
+  #[test]
+  fn specs() {
+    for table in TABLES.iter() {
+      let spec = table_spec(table).unwrap();
+      assert_eq!(spec.name, *table);
+      // Sinks name partitions and resume by ts
+      assert_eq!(spec.columns[0], ("ts", ColumnKind::Timestamp));
+      let mut names = spec.columns.iter().map(|(name, _kind)| *name).collect::<Vec<_>>();
+      names.sort_unstable();
+      names.dedup();
+      assert_eq!(names.len(), spec.columns.len(), "{} has duplicate columns", table);
+      assert!(["%Y", "%Y-%m", "%Y-%m-%d"].contains(&spec.partition_format));
+    }
+    assert!(table_spec("nope").is_none());
+  }
+
+  #[test]
+  fn split_off() {
+    let mut rows = batch("2021", vec![
+      ("ts", Values::Timestamp(vec![1, 2, 3])),
+      ("sym", Values::Str(vec!["A".to_string(), "B".to_string(), "C".to_string()])),
+    ]);
+    let rest = rows.split_off(1);
+    assert_eq!(rows.timestamps(), &[1]);
+    assert_eq!(rest.timestamps(), &[2, 3]);
+    assert_eq!(rest.partition, "2021");
+    assert_eq!(rest.num_rows(), 2);
+  }
+}
@@ -0,0 +1,231 @@
+use crate::{
+  journal,
+  lock::Lock,
+  placement::{data_dir, move_partition, placed_schema},
+  util::{data_root, table_dir}
+};
+use chrono::{Duration, Utc};
+use serde_json::json;
+use std::{
+  convert::TryInto,
+  fs::{self, File},
+  io,
+  path::{Path, PathBuf},
+  process
+};
+use tracing::{info, info_span, warn};
+use zdb::table::Table;
+
+// Compacted partitions keep their columns as <file>.zst next to this, which lists each file's
+// original length and whether it was delta encoded
+const MANIFEST: &str = "compacted.json";
+
+// Sorted or nearly so, so the deltas are small and compress far better than the values
+const DELTA_COLUMNS: [&str; 4] = ["ts", "ts_participant", "id", "seq_id"];
+
+const ZSTD_LEVEL: i32 = 19;
+
+pub struct CompactArgs<'a> {
+  pub tables:      Vec<&'a str>,
+  // Partitions whose last row is older than this many days
+  pub older_than:  i64,
+  // Moves compacted partitions here, off the --data-dir SSDs
+  pub archive_dir: Option<&'a str>,
+  pub data_dirs:   Vec<&'a str>
+}
+
+fn sync_dir(dir: &Path) -> io::Result<()> { File::open(dir)?.sync_all() }
+
+fn column(file_name: &str) -> &str { file_name.split('.').next().unwrap() }
+
+pub fn is_compacted(partition_dir: &Path) -> bool { partition_dir.join(MANIFEST).exists() }
+
+fn encode(raw: &[u8], delta: bool) -> io::Result<Vec<u8>> {
+  if !delta {
+    return zstd::encode_all(raw, ZSTD_LEVEL);
+  }
+  let mut deltas = Vec::<u8>::with_capacity(raw.len());
+  let mut prev = 0i64;
+  for chunk in raw.chunks_exact(8) {
+    let value = i64::from_le_bytes(chunk.try_into().unwrap());
+    deltas.extend_from_slice(&value.wrapping_sub(prev).to_le_bytes());
+    prev = value;
+  }
+  zstd::encode_all(&deltas[..], ZSTD_LEVEL)
+}
+
+fn decode(compressed: &[u8], delta: bool) -> io::Result<Vec<u8>> {
+  let mut res = zstd::decode_all(compressed)?;
+  if delta {
+    let mut prev = 0i64;
+    for chunk in res.chunks_exact_mut(8) {
+      prev = prev.wrapping_add(i64::from_le_bytes((&*chunk).try_into().unwrap()));
+      chunk.copy_from_slice(&prev.to_le_bytes());
+    }
+  }
+
+  Ok(res)
+}
+
+fn read_manifest(partition_dir: &Path) -> io::Result<serde_json::Value> {
+  let data = fs::read_to_string(partition_dir.join(MANIFEST))?;
+  serde_json::from_str(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// Writes every column as <file>.zst, checks it decodes back to the same bytes and only then
+// commits by writing the manifest and removes the originals. Returns (before, after) bytes.
+fn compact_partition(dir: &Path, from_ts: i64) -> io::Result<(u64, u64)> {
+  let mut files = serde_json::Map::new();
+  let (mut before, mut after) = (0u64, 0u64);
+  for entry in fs::read_dir(dir)? {
+    let path = entry?.path();
+    let name = path.file_name().unwrap().to_string_lossy().to_string();
+    if !path.is_file() || name.ends_with(".zst") || name.ends_with(".tmp") {
+      continue;
+    }
+    let raw = fs::read(&path)?;
+    let delta = DELTA_COLUMNS.contains(&column(&name)) && raw.len() % 8 == 0;
+    let compressed = encode(&raw, delta)?;
+    if decode(&compressed, delta)? != raw {
+      let message = format!("{} did not round trip", name);
+      return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+    }
+    let zst = dir.join(format!("{}.zst", name));
+    fs::write(&zst, &compressed)?;
+    File::open(&zst)?.sync_all()?;
+    before += raw.len() as u64;
+    after += compressed.len() as u64;
+    files.insert(name, json!({ "len": raw.len(), "delta": delta }));
+  }
+
+  let tmp = dir.join(format!("{}.tmp", MANIFEST));
+  let manifest = json!({ "from_ts": from_ts, "files": files });
+  fs::write(&tmp, serde_json::to_string(&manifest).unwrap())?;
+  File::open(&tmp)?.sync_all()?;
+  fs::rename(&tmp, dir.join(MANIFEST))?;
+  sync_dir(dir)?;
+  remove_originals(dir)?;
+
+  Ok((before, after))
+}
+
+// Also finishes a compaction that crashed after committing
+fn remove_originals(dir: &Path) -> io::Result<()> {
+  let manifest = read_manifest(dir)?;
+  for name in manifest["files"].as_object().into_iter().flat_map(|f| f.keys()) {
+    let path = dir.join(name);
+    if path.exists() {
+      fs::remove_file(&path)?;
+    }
+  }
+  sync_dir(dir)
+}
+
+// Compresses partitions older than `older_than` days and optionally moves them to the archive dir
+pub fn compact(args: CompactArgs) {
+  let cutoff = (Utc::now() - Duration::days(args.older_than)).timestamp_nanos();
+  for name in args.tables.iter() {
+    let _span = info_span!("compact", table = name).entered();
+    if !table_dir(name).exists() {
+      warn!("Table does not exist, skipping");
+      continue;
+    }
+    let _lock = Lock::table(name);
+    journal::recover(name).unwrap_or_else(|e| panic!("Could not recover {}: {}", name, e));
+    let mut table = Table::create_or_open(placed_schema(name, args.data_dirs.clone()))
+      .expect("Could not open table");
+
+    let mut partitions = table
+      .partition_meta
+      .iter()
+      .filter(|(_partition, meta)| meta.to_ts < cutoff)
+      .map(|(partition, meta)| (partition.clone(), meta.to_ts))
+      .collect::<Vec<_>>();
+    partitions.sort();
+    let (mut before, mut after) = (0u64, 0u64);
+    for (partition, to_ts) in partitions.iter() {
+      let dir = PathBuf::from(&table.partition_meta[partition].dir);
+      if is_compacted(&dir) {
+        remove_originals(&dir).unwrap_or_else(|e| panic!("Could not clean up {:?}: {}", dir, e));
+      } else {
+        let from_ts = table
+          .partition_iter(*to_ts, *to_ts, vec!["ts"])
+          .next()
+          .map(|p| p[0].get_i64()[0])
+          .unwrap_or(*to_ts);
+        let (b, a) = compact_partition(&dir, from_ts)
+          .unwrap_or_else(|e| panic!("Could not compact {:?}: {}", dir, e));
+        info!(partition = %partition, before = b, after = a, "Compacted");
+        before += b;
+        after += a;
+      }
+      if let Some(archive_dir) = args.archive_dir {
+        if data_dir(&table.partition_meta[partition].dir) != archive_dir {
+          move_partition(&mut table, name, partition, archive_dir);
+        }
+      }
+    }
+    info!(partitions = partitions.len(), before, after, "Compacted table");
+  }
+}
+
+// Decompressed copies of compacted partitions, removed when dropped
+pub struct Thawed {
+  dir: PathBuf
+}
+
+impl Drop for Thawed {
+  fn drop(&mut self) {
+    if self.dir.exists() {
+      fs::remove_dir_all(&self.dir).ok();
+    }
+  }
+}
+
+fn scratch_dir() -> PathBuf { data_root().join(".thawed").join(process::id().to_string()) }
+
+// The first ts and uncompressed bytes of a compacted partition, without decompressing it
+pub fn manifest_summary(partition_dir: &Path) -> Option<(i64, u64)> {
+  let manifest = read_manifest(partition_dir).ok()?;
+  let bytes = manifest["files"]
+    .as_object()?
+    .values()
+    .filter_map(|file_meta| file_meta["len"].as_u64())
+    .sum();
+  Some((manifest["from_ts"].as_i64()?, bytes))
+}
+
+fn thaw_files(dir: &Path, manifest: &serde_json::Value, thawed: &Path, columns: &[&str]) {
+  fs::create_dir_all(thawed).unwrap_or_else(|e| panic!("Could not create {:?}: {}", thawed, e));
+  for (file, file_meta) in manifest["files"].as_object().into_iter().flatten() {
+    if !columns.contains(&column(file)) {
+      continue;
+    }
+    let compressed = fs::read(dir.join(format!("{}.zst", file)))
+      .unwrap_or_else(|e| panic!("Could not read {:?}: {}", dir, e));
+    let raw = decode(&compressed, file_meta["delta"].as_bool().unwrap_or(false))
+      .unwrap_or_else(|e| panic!("Could not decompress {:?} {}: {}", dir, file, e));
+    let len = file_meta["len"].as_u64();
+    assert_eq!(Some(raw.len() as u64), len, "{:?} {} is corrupt", dir, file);
+    fs::write(thawed.join(file), raw)
+      .unwrap_or_else(|e| panic!("Could not write {:?}: {}", thawed, e));
+  }
+}
+
+// Decompresses `columns` of `partition` of `table` if it's compacted to a scratch dir and
+// points its partition_meta there. The change is only in memory, so only call this on tables
+// opened for reading. Scanning a table a partition at a time only ever has one decompressed.
+pub fn thaw_partition(table: &mut Table, name: &str, partition: &str, columns: &[&str]) -> Thawed {
+  let res = Thawed { dir: scratch_dir().join(name).join(partition) };
+  if let Some(meta) = table.partition_meta.get_mut(partition) {
+    let dir = PathBuf::from(&meta.dir);
+    if is_compacted(&dir) {
+      let manifest =
+        read_manifest(&dir).unwrap_or_else(|e| panic!("Could not read {:?}: {}", dir, e));
+      thaw_files(&dir, &manifest, &res.dir, columns);
+      meta.dir = res.dir.to_string_lossy().to_string();
+    }
+  }
+
+  res
+}
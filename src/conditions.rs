@@ -0,0 +1,138 @@
+// Polygon's trade condition IDs from /v1/meta/conditions/trades
+const TRADE_CONDITIONS: [&str; 55] = [
+  "Regular Sale",
+  "Acquisition",
+  "Average Price Trade",
+  "Automatic Execution",
+  "Bunched Trade",
+  "Bunched Sold Trade",
+  "CAP Election",
+  "Cash Sale",
+  "Closing Prints",
+  "Cross Trade",
+  "Derivatively Priced",
+  "Distribution",
+  "Form T",
+  "Extended Trading Hours (Sold Out of Sequence)",
+  "Intermarket Sweep",
+  "Market Center Official Close",
+  "Market Center Official Open",
+  "Market Center Opening Trade",
+  "Market Center Reopening Trade",
+  "Market Center Closing Trade",
+  "Next Day",
+  "Price Variation Trade",
+  "Prior Reference Price",
+  "Rule 155 Trade (AMEX)",
+  "Rule 127 NYSE",
+  "Opening Prints",
+  "Opened",
+  "Stopped Stock (Regular Trade)",
+  "Re-Opening Prints",
+  "Seller",
+  "Sold Last",
+  "Sold Last and Stopped Stock",
+  "Sold (Out Of Sequence)",
+  "Sold (Out of Sequence) and Stopped Stock",
+  "Split Trade",
+  "Stock Option",
+  "Yellow Flag Regular Trade",
+  "Odd Lot Trade",
+  "Corrected Consolidated Close",
+  "Unknown",
+  "Held",
+  "Trade Thru Exempt",
+  "NonEligible",
+  "NonEligible-Extended",
+  "Cancelled",
+  "Recovery",
+  "Correction",
+  "As of",
+  "As of Correction",
+  "As of Cancel",
+  "OOB",
+  "Summary",
+  "Contingent Trade",
+  "Qualified Contingent Trade",
+  "Errored"
+];
+
+pub fn condition_name(id: u8) -> Option<&'static str> { TRADE_CONDITIONS.get(id as usize).copied() }
+
+// Condition columns pack up to 4 condition IDs into a byte each as the ID + 1, sorted, so an
+// empty slot (0) can't be mistaken for Regular Sale (ID 0)
+pub fn decode_conditions(cond: u32) -> Vec<u8> {
+  cond.to_le_bytes().iter().filter(|b| **b != 0).map(|b| b - 1).collect()
+}
+
+pub fn condition_names(cond: u32) -> String {
+  decode_conditions(cond)
+    .iter()
+    .map(|id| match condition_name(*id) {
+      Some(name) => name.to_string(),
+      None => format!("Unknown ({})", id)
+    })
+    .collect::<Vec<_>>()
+    .join("|")
+}
+
+// Inverse of decode_conditions. Duplicates, ID 255, which doesn't fit, and conditions past the
+// 4th are dropped.
+pub fn encode_conditions(ids: &[u8]) -> u32 {
+  let mut ids = ids.iter().filter(|id| **id != u8::MAX).cloned().collect::<Vec<_>>();
+  ids.sort_unstable();
+  ids.dedup();
+  let mut bytes = [0u8; 4];
+  for (i, id) in ids.iter().take(4).enumerate() {
+    bytes[i] = id + 1;
+  }
+
+  u32::from_le_bytes(bytes)
+}
+
+// polygon_io packs a REST trade's conditions as its 4 slots padded with 0s, sorted, and then
+// a byte each from the lowest. [12, 37] is 0x250c0000. Padding and Regular Sale (ID 0) are
+// both 0 there, so a Regular Sale alongside other conditions is lost. Only all 0s is read as
+// Regular Sale, which is also what a trade without conditions looks like.
+pub fn from_polygon(cond: u32) -> u32 {
+  let ids = cond.to_le_bytes().iter().filter(|b| **b != 0).cloned().collect::<Vec<_>>();
+  if ids.is_empty() {
+    return encode_conditions(&[0]);
+  }
+
+  encode_conditions(&ids)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn roundtrip() {
+    for ids in [vec![], vec![0], vec![12, 37], vec![0, 12], vec![1, 2, 3, 54]].iter() {
+      assert_eq!(&decode_conditions(encode_conditions(ids)), ids);
+    }
+  }
+
+  #[test]
+  fn regular_sale_is_not_padding() {
+    assert_eq!(decode_conditions(encode_conditions(&[12, 0])), vec![0, 12]);
+    assert_eq!(decode_conditions(encode_conditions(&[])), Vec::<u8>::new());
+    assert_eq!(condition_names(encode_conditions(&[0, 14])), "Regular Sale|Intermarket Sweep");
+  }
+
+  #[test]
+  fn encode_drops() {
+    assert_eq!(decode_conditions(encode_conditions(&[37, 12, 37])), vec![12, 37]);
+    assert_eq!(decode_conditions(encode_conditions(&[5, 4, 3, 2, 1])), vec![1, 2, 3, 4]);
+    assert_eq!(decode_conditions(encode_conditions(&[255, 7])), vec![7]);
+  }
+
+  #[test]
+  fn polygon() {
+    // What polygon_io's to_conditions makes of "conditions": [37, 12]
+    assert_eq!(decode_conditions(from_polygon(0x250c_0000)), vec![12, 37]);
+    assert_eq!(decode_conditions(from_polygon(0x0e00_0000)), vec![14]);
+    assert_eq!(decode_conditions(from_polygon(0)), vec![0]);
+  }
+}
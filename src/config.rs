@@ -0,0 +1,226 @@
+use crate::{retry::RetryPolicy, util::new_york};
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+use serde::Deserialize;
+use std::{
+  cmp,
+  collections::{HashMap, HashSet},
+  fs,
+  path::Path,
+  process,
+  time::Duration
+};
+use tracing::{error, info};
+
+pub const DEFAULT_SETTLE_MINS: i64 = 30;
+
+pub const DATASETS: [&str; 6] = ["agg1d", "tickers", "ticker-events", "exchanges", "agg1m", "trades"];
+
+// polyzdb.toml. Everything is optional and CLI flags or their env vars win over it.
+//
+//   download = ["agg1d", "tickers", "trades"]
+//   api_key_file = "/etc/polyzdb/keys"
+//   data_root = "/var/lib/polyzdb"
+//   data_dirs = ["/mnt/ssd1", "/mnt/ssd2"]
+//   placement = "by-year"
+//   pin = { "2019" = "/mnt/ssd1" }
+//   threads = 100
+//
+//   [retry]
+//   max_elapsed_secs = 600
+//
+//   [datasets.trades]
+//   from = "2020-01-01"
+//   to = "2020-12-31"
+//   symbols = ["AAPL", "MSFT"]
+//   retry = { max_elapsed_secs = 3600 }
+//
+//   [datasets.agg1d]
+//   settle_mins = 60
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+  pub download:     Vec<String>,
+  pub api_key_file: Option<String>,
+  // Where table metadata and polyzdb's own state go
+  pub data_root:    Option<String>,
+  pub data_dirs:    Vec<String>,
+  // Which data dir new agg1m, trades and quotes partitions go in
+  pub placement:    Option<String>,
+  // Year to data dir for the by-year placement
+  pub pin:          HashMap<String, String>,
+  pub threads:      Option<usize>,
+  pub retry:        RetryConfig,
+  pub datasets:     HashMap<String, DatasetFileConfig>
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+  pub initial_secs:     Option<f64>,
+  pub max_delay_secs:   Option<f64>,
+  pub max_elapsed_secs: Option<f64>
+}
+
+impl RetryConfig {
+  fn apply(&self, policy: &mut RetryPolicy) {
+    if let Some(secs) = self.initial_secs {
+      policy.initial = Duration::from_secs_f64(secs);
+    }
+    if let Some(secs) = self.max_delay_secs {
+      policy.max_delay = Duration::from_secs_f64(secs);
+    }
+    if let Some(secs) = self.max_elapsed_secs {
+      policy.max_elapsed = Duration::from_secs_f64(secs);
+    }
+  }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatasetFileConfig {
+  // %Y-%m-%d
+  pub from:        Option<String>,
+  pub to:          Option<String>,
+  pub symbols:     Option<Vec<String>>,
+  pub retry:       RetryConfig,
+  pub settle_mins: Option<i64>
+}
+
+// What to download of one dataset
+#[derive(Clone, Debug)]
+pub struct DatasetConfig {
+  pub from:    NaiveDate,
+  // Last day to download. None is as far as there's data.
+  pub to:      Option<NaiveDate>,
+  // Only agg1m and trades fetch per symbol. The rest fetch whole days.
+  pub symbols: Option<HashSet<String>>,
+  pub retry:   RetryPolicy,
+  // How long after the close Polygon may still correct a day
+  pub settle:  chrono::Duration
+}
+
+impl DatasetConfig {
+  // The day after the last one to download. That's `default` unless a last day was set, which
+  // can be today when the caller knows it's over.
+  pub fn until(&self, default: NaiveDate) -> NaiveDate {
+    match self.to {
+      Some(last) => cmp::min(last.succ(), Utc::now().naive_utc().date().succ()),
+      None => default
+    }
+  }
+
+  // The first day that isn't final, since the market hasn't closed at `close` New York time
+  // or closed less than `settle` ago. Rows from then on are provisional.
+  pub fn provisional_from(&self, close: NaiveTime) -> NaiveDate {
+    self.provisional_at(close, Utc::now())
+  }
+
+  fn provisional_at(&self, close: NaiveTime, now: DateTime<Utc>) -> NaiveDate {
+    let mut res = now.naive_utc().date().succ().succ();
+    while new_york(res.pred(), close) + self.settle > now {
+      res = res.pred();
+    }
+
+    res
+  }
+}
+
+pub fn parse_date(date: &str, what: &str) -> NaiveDate {
+  NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap_or_else(|e| {
+    error!(date, error = %e, "Invalid {}", what);
+    process::exit(1);
+  })
+}
+
+impl FileConfig {
+  // An explicit path must exist. Without one, ./polyzdb.toml is used if it's there.
+  pub fn load(path: Option<&str>) -> FileConfig {
+    let (path, required) = match path {
+      Some(path) => (path, true),
+      None => ("polyzdb.toml", false)
+    };
+    if !required && !Path::new(path).exists() {
+      return FileConfig::default();
+    }
+    let data = fs::read_to_string(path).unwrap_or_else(|e| {
+      error!(path, error = %e, "Could not read config");
+      process::exit(1);
+    });
+    let res = toml::from_str::<FileConfig>(&data).unwrap_or_else(|e| {
+      error!(path, error = %e, "Invalid config");
+      process::exit(1);
+    });
+    for dataset in res.download.iter().chain(res.datasets.keys()) {
+      if !DATASETS.contains(&dataset.as_str()) {
+        error!(path, dataset = %dataset, "Unknown dataset in config");
+        process::exit(1);
+      }
+    }
+    info!(path, "Loaded config");
+
+    res
+  }
+
+  // Defaults, then [retry], then [datasets.<dataset>.retry]
+  pub fn dataset(&self, dataset: &str) -> DatasetConfig {
+    let mut retry = RetryPolicy::for_dataset(dataset);
+    self.retry.apply(&mut retry);
+    let file = match self.datasets.get(dataset) {
+      Some(file) => file,
+      None => {
+        return DatasetConfig {
+          from: NaiveDate::from_ymd(2004, 1, 1),
+          to: None,
+          symbols: None,
+          retry,
+          settle: chrono::Duration::minutes(DEFAULT_SETTLE_MINS)
+        }
+      }
+    };
+    file.retry.apply(&mut retry);
+
+    DatasetConfig {
+      from: file
+        .from
+        .as_ref()
+        .map(|d| parse_date(d, "from"))
+        .unwrap_or_else(|| NaiveDate::from_ymd(2004, 1, 1)),
+      to: file.to.as_ref().map(|d| parse_date(d, "to")),
+      symbols: file.symbols.as_ref().map(|s| s.iter().cloned().collect()),
+      retry,
+      settle: chrono::Duration::minutes(file.settle_mins.unwrap_or(DEFAULT_SETTLE_MINS))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn config(settle_mins: i64) -> DatasetConfig {
+    DatasetConfig {
+      from:    NaiveDate::from_ymd(2021, 1, 1),
+      to:      None,
+      symbols: None,
+      retry:   RetryPolicy::for_dataset("agg1d"),
+      settle:  chrono::Duration::minutes(settle_mins)
+    }
+  }
+
+  fn utc(s: &str) -> DateTime<Utc> { DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc) }
+
+  #[test]
+  fn provisional_days() {
+    let close = NaiveTime::from_hms(16, 0, 0);
+    let day = |d| NaiveDate::from_ymd(2021, 3, d);
+    // 16:00 EST is 21:00 UTC, so the 4th settles at 21:30 UTC
+    assert_eq!(config(30).provisional_at(close, utc("2021-03-04T15:00:00Z")), day(4));
+    assert_eq!(config(30).provisional_at(close, utc("2021-03-04T21:29:00Z")), day(4));
+    assert_eq!(config(30).provisional_at(close, utc("2021-03-04T21:30:00Z")), day(5));
+    assert_eq!(config(0).provisional_at(close, utc("2021-03-04T21:00:00Z")), day(5));
+    // 02:00 UTC is still the 4th in New York
+    assert_eq!(config(30).provisional_at(close, utc("2021-03-05T02:00:00Z")), day(5));
+    // Settling for longer than a day keeps the day before provisional too
+    assert_eq!(config(24 * 60).provisional_at(close, utc("2021-03-05T02:00:00Z")), day(4));
+  }
+}
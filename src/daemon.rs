@@ -0,0 +1,274 @@
+use crate::{
+  config::DatasetConfig,
+  download::Downloader,
+  lock::Lock,
+  report, shutdown,
+  util::{data_root, new_york}
+};
+use chrono::{DateTime, Duration, NaiveDate, NaiveTime, Utc};
+use chrono_tz::America::New_York;
+use serde_json::json;
+use std::{
+  collections::BTreeMap,
+  fs,
+  io::{self, Read, Write},
+  net::{TcpListener, TcpStream},
+  path::PathBuf,
+  process,
+  sync::{Arc, Mutex},
+  thread
+};
+use tracing::{error, info, info_span, warn};
+use zdb::calendar::us_equity::is_market_open;
+
+fn state_path() -> PathBuf { data_root().join("daemon.json") }
+
+fn lock_path() -> PathBuf { data_root().join("daemon.lock") }
+
+// A dataset whose requests gave up is tried again this long after
+const FAILURE_BACKOFF: i64 = 30;
+
+pub struct DaemonArgs<'a> {
+  // Serves the last successful update per dataset as JSON
+  pub status_addr: Option<&'a str>,
+  // After the 16:00 ET close, for agg1d and tickers
+  pub close_delay: Duration,
+  // After 20:00 ET, for when Polygon has the final agg1m and trades. Early closes end extended
+  // hours sooner, but the calendar doesn't say when, so those days wait just as long.
+  pub final_delay: Duration
+}
+
+// Datasets that update together at some time after a market day's `at` in New York
+struct Job {
+  datasets: &'static [&'static str],
+  at:       NaiveTime,
+  delay:    Duration
+}
+
+impl Job {
+  fn due(&self, day: NaiveDate) -> DateTime<Utc> { new_york(day, self.at) + self.delay }
+
+  // The latest market day this job is due for
+  fn latest_day(&self, now: DateTime<Utc>) -> Option<NaiveDate> {
+    let today = now.with_timezone(&New_York).date().naive_local();
+    (0..10)
+      .map(|i| today - Duration::days(i))
+      .find(|day| is_market_open(day) && self.due(*day) <= now)
+  }
+}
+
+#[derive(Clone, Default)]
+struct DatasetStatus {
+  // The last market day downloaded
+  through:      Option<NaiveDate>,
+  last_success: Option<DateTime<Utc>>,
+  // The last run whose requests gave up, which doesn't advance `through`
+  last_failure: Option<DateTime<Utc>>
+}
+
+#[derive(Default)]
+struct State {
+  datasets: BTreeMap<String, DatasetStatus>,
+  running:  Option<String>
+}
+
+impl DatasetStatus {
+  // Whether `dataset` should be downloaded through `day`, which it isn't while backing off
+  // from a failure
+  fn is_due(&self, day: NaiveDate, now: DateTime<Utc>) -> bool {
+    self.through < Some(day)
+      && self.last_failure.map_or(true, |t| now >= t + Duration::minutes(FAILURE_BACKOFF))
+  }
+
+  // Whatever gave up is fetched again on the next try, so only success advances `through`
+  fn record(&mut self, day: NaiveDate, failed: bool, now: DateTime<Utc>) {
+    if failed {
+      self.last_failure = Some(now);
+    } else {
+      self.through = Some(day);
+      self.last_success = Some(now);
+    }
+  }
+}
+
+impl State {
+  fn load() -> State {
+    let mut res = State::default();
+    let json = match fs::read_to_string(state_path()) {
+      Ok(data) => serde_json::from_str::<serde_json::Value>(&data).unwrap_or_default(),
+      Err(_) => return res
+    };
+    let datetime = |value: &serde_json::Value| {
+      let datetime = DateTime::parse_from_rfc3339(value.as_str()?).ok()?;
+      Some(datetime.with_timezone(&Utc))
+    };
+    if let Some(datasets) = json["datasets"].as_object() {
+      for (name, status) in datasets.iter() {
+        res.datasets.insert(name.clone(), DatasetStatus {
+          through:      status["through"]
+            .as_str()
+            .and_then(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d").ok()),
+          last_success: datetime(&status["last_success"]),
+          last_failure: datetime(&status["last_failure"])
+        });
+      }
+    }
+
+    res
+  }
+
+  fn to_json(&self) -> serde_json::Value {
+    let datasets = self
+      .datasets
+      .iter()
+      .map(|(name, status)| {
+        (name.clone(), json!({
+          "through": status.through.map(|d| d.to_string()),
+          "last_success": status.last_success.map(|d| d.to_rfc3339()),
+          "last_failure": status.last_failure.map(|d| d.to_rfc3339())
+        }))
+      })
+      .collect::<serde_json::Map<_, _>>();
+
+    json!({ "running": self.running, "datasets": datasets })
+  }
+
+  fn save(&self) {
+    let path = state_path();
+    let tmp = path.with_extension("json.tmp");
+    let res = fs::write(&tmp, serde_json::to_string_pretty(&self.to_json()).unwrap())
+      .and_then(|_| fs::rename(&tmp, &path));
+    if let Err(e) = res {
+      error!(?path, error = %e, "Could not save daemon state");
+    }
+  }
+}
+
+fn respond(mut stream: TcpStream, state: &Mutex<State>) -> io::Result<()> {
+  // Every path gets the status, so only read enough of the request to be polite
+  let mut buf = [0u8; 1024];
+  let _request_len = stream.read(&mut buf)?;
+  let body = serde_json::to_string_pretty(&state.lock().unwrap().to_json()).unwrap();
+  write!(
+    stream,
+    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+    body.len(),
+    body
+  )
+}
+
+fn serve_status(addr: &str, state: Arc<Mutex<State>>) {
+  let listener = match TcpListener::bind(addr) {
+    Ok(listener) => listener,
+    Err(e) => {
+      error!(addr, error = %e, "Could not bind status endpoint");
+      process::exit(1);
+    }
+  };
+  info!(addr, "Serving status");
+  thread::spawn(move || {
+    for stream in listener.incoming().filter_map(|s| s.ok()) {
+      if let Err(e) = respond(stream, &state) {
+        warn!(error = %e, "Could not serve status");
+      }
+    }
+  });
+}
+
+// Downloads each dataset once its market day is over, forever. `config` gives a dataset's
+// config, which the daemon caps at the day it's due for.
+pub fn daemon<F>(downloader: &Downloader, args: DaemonArgs, config: F)
+where
+  F: Fn(&str) -> DatasetConfig
+{
+  let _lock = Lock::acquire(&lock_path(), false);
+  let jobs = [
+    Job {
+      datasets: &["agg1d", "tickers", "ticker-events", "exchanges"],
+      at:       NaiveTime::from_hms(16, 0, 0),
+      delay:    args.close_delay
+    },
+    Job {
+      datasets: &["agg1m", "trades"],
+      at:       NaiveTime::from_hms(20, 0, 0),
+      delay:    args.final_delay
+    },
+  ];
+  let state = Arc::new(Mutex::new(State::load()));
+  if let Some(addr) = args.status_addr {
+    serve_status(addr, state.clone());
+  }
+
+  info!("Waiting for the next close");
+  loop {
+    let now = Utc::now();
+    for job in jobs.iter() {
+      let day = match job.latest_day(now) {
+        Some(day) => day,
+        None => continue
+      };
+      for (i, dataset) in job.datasets.iter().enumerate() {
+        let status = state.lock().unwrap().datasets.get(*dataset).cloned().unwrap_or_default();
+        if !status.is_due(day, now) {
+          continue;
+        }
+        let _span = info_span!("daemon", %day).entered();
+        state.lock().unwrap().running = Some(dataset.to_string());
+        report::init(data_root().join("reports").join(format!(
+          "{}-{}.json",
+          dataset,
+          Utc::now().format("%Y%m%dT%H%M%SZ")
+        )));
+        let mut config = config(dataset);
+        config.to = Some(day);
+        downloader.download(dataset, &config);
+        // A request that gave up stops the run, but the daemon backs off instead of exiting
+        let failed = shutdown::take_failure() || report::failures(dataset) > 0;
+        report::finish();
+        shutdown::checkpoint(&job.datasets[i..]);
+
+        if failed {
+          warn!(dataset, retry_minutes = FAILURE_BACKOFF, "Not advancing after failures");
+        }
+        let mut state = state.lock().unwrap();
+        state.running = None;
+        state.datasets.entry(dataset.to_string()).or_default().record(day, failed, Utc::now());
+        state.save();
+      }
+    }
+
+    // Wake up often enough to notice a shutdown
+    for _ in 0..60 {
+      if shutdown::requested() {
+        shutdown::checkpoint(&[]);
+      }
+      thread::sleep(std::time::Duration::from_secs(1));
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn backs_off_after_failures() {
+    let day = NaiveDate::from_ymd(2021, 3, 4);
+    let now = new_york(day.succ(), NaiveTime::from_hms(0, 30, 0));
+    let mut status = DatasetStatus::default();
+    assert!(status.is_due(day, now));
+
+    status.record(day, true, now);
+    assert_eq!(status.through, None);
+    assert_eq!(status.last_success, None);
+    assert!(!status.is_due(day, now + Duration::minutes(FAILURE_BACKOFF - 1)));
+    assert!(status.is_due(day, now + Duration::minutes(FAILURE_BACKOFF)));
+
+    let later = now + Duration::minutes(FAILURE_BACKOFF);
+    status.record(day, false, later);
+    assert_eq!(status.through, Some(day));
+    assert_eq!(status.last_success, Some(later));
+    assert!(!status.is_due(day, later + Duration::days(1)));
+    assert!(status.is_due(day.succ(), later + Duration::days(1)));
+  }
+}
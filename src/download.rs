@@ -0,0 +1,56 @@
+use crate::{
+  agg1d::{agg1d_schema, download_agg1d},
+  agg1m::{agg1m_schema, download_agg1m},
+  auth::Clients,
+  config::DatasetConfig,
+  exchanges::{download_exchanges, exchanges_schema},
+  journal::Savepoint,
+  sink::{open_sink, SinkKind},
+  ticker_events::build_ticker_events,
+  tickers::{download_tickers, tickers_schema},
+  trades::{download_trades, trades_schema}
+};
+use threadpool::ThreadPool;
+
+// What every dataset's download shares
+pub struct Downloader<'a> {
+  pub thread_pool: ThreadPool,
+  pub clients:     Clients,
+  pub sink:        SinkKind,
+  pub sink_out:    Option<&'a str>,
+  pub data_dirs:   Vec<&'a str>
+}
+
+impl Downloader<'_> {
+  pub fn download(&self, dataset: &str, config: &DatasetConfig) {
+    let (sink, sink_out) = (self.sink, self.sink_out);
+    match dataset {
+      "agg1d" => {
+        let replace = [Savepoint::Provisional];
+        let mut output = open_sink(sink, agg1d_schema(), "agg1d", sink_out, &replace);
+        download_agg1d(&self.thread_pool, &self.clients, &mut *output, config);
+      }
+      "tickers" => {
+        let mut output = open_sink(sink, tickers_schema(), "tickers", sink_out, &[]);
+        download_tickers(&self.thread_pool, &self.clients, &mut *output, config);
+      }
+      "ticker-events" => build_ticker_events(),
+      "exchanges" => {
+        let mut output = open_sink(sink, exchanges_schema(), "exchanges", sink_out, &[]);
+        download_exchanges(&self.clients, &mut *output, config);
+      }
+      "agg1m" => {
+        let schema = agg1m_schema(self.data_dirs.clone());
+        let replace = [Savepoint::Provisional, Savepoint::Live];
+        let mut output = open_sink(sink, schema, "agg1m", sink_out, &replace);
+        download_agg1m(&self.thread_pool, &self.clients, &mut *output, config);
+      }
+      "trades" => {
+        let schema = trades_schema(self.data_dirs.clone());
+        let mut output = open_sink(sink, schema, "trades", sink_out, &[Savepoint::Live]);
+        download_trades(&self.thread_pool, &self.clients, &mut *output, config);
+      }
+      _ => unreachable!()
+    }
+  }
+}
@@ -0,0 +1,162 @@
+use crate::{
+  auth::Clients,
+  batch::{batch, table_spec, Values},
+  config::DatasetConfig,
+  report,
+  retry::{give_up, retry},
+  shutdown,
+  sink::{Sink, SinkKind},
+  symbols::lookup
+};
+use chrono::{NaiveDate, Utc};
+use polygon_io::{
+  reference::exchanges::Exchange
+};
+use std::{
+  collections::HashMap,
+  convert::TryFrom,
+  time::Instant
+};
+use tracing::{debug, error, info, info_span};
+use zdb::{
+  calendar::ToNaiveDateTime,
+  schema::Schema,
+  table::Table
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExchangeInfo {
+  pub mic:    String,
+  pub name:   String,
+  pub r#type: String,
+  pub tape:   String
+}
+
+impl ExchangeInfo {
+  // Trade reporting facilities are where off-exchange (dark pool) prints land
+  pub fn is_trf(&self) -> bool { self.r#type.eq_ignore_ascii_case("TRF") }
+}
+
+pub fn exchanges_schema() -> Schema { table_spec("exchanges").unwrap().schema(Vec::new()) }
+
+fn latest_snapshot_date(exchanges: &Table) -> Option<NaiveDate> {
+  exchanges
+    .partition_meta
+    .values()
+    .map(|meta| meta.to_ts)
+    .max()
+    .map(|ts| ts.to_naive_date_time().date())
+}
+
+// Returns the exchange mapping as of the most recent snapshot
+pub fn load_exchanges(exchanges: &Table) -> HashMap<u8, ExchangeInfo> {
+  let mut res = HashMap::<u8, ExchangeInfo>::new();
+  let latest = match latest_snapshot_date(exchanges) {
+    Some(date) => date.and_hms(0, 0, 0).timestamp_nanos(),
+    None => return res
+  };
+  let partitions = exchanges.partition_iter(
+    latest,
+    latest,
+    vec!["id", "mic", "name", "type", "tape"]
+  );
+  for partition in partitions {
+    let ids = partition[0].get_u8();
+    let sym = |col: usize, i: usize| -> String {
+      let sym_i = partition[col].get_u8()[i];
+      lookup(&partition[col].symbols, sym_i as usize).cloned().unwrap_or(String::new())
+    };
+    for (i, id) in ids.iter().enumerate() {
+      res.insert(*id, ExchangeInfo {
+        mic:    sym(1, i),
+        name:   sym(2, i),
+        r#type: sym(3, i),
+        tape:   sym(4, i)
+      });
+    }
+  }
+
+  res
+}
+
+// Opens the exchanges table if it's been downloaded and returns its latest mapping
+pub fn open_exchanges() -> HashMap<u8, ExchangeInfo> {
+  match Table::open("exchanges") {
+    Ok(table) => load_exchanges(&table),
+    Err(_) => HashMap::new()
+  }
+}
+
+fn to_info(e: Exchange) -> ExchangeInfo {
+  ExchangeInfo {
+    mic:    e.mic.unwrap_or(String::new()),
+    name:   e.name,
+    r#type: e.r#type,
+    tape:   e.tape.unwrap_or(String::new())
+  }
+}
+
+pub fn download_exchanges(
+  clients: &Clients,
+  exchanges: &mut dyn Sink,
+  config: &DatasetConfig
+) {
+  let now = Instant::now();
+  let _span = info_span!("download", dataset = "exchanges").entered();
+  let today = Utc::now().naive_utc().date();
+  let ts = today.and_hms(0, 0, 0).timestamp_nanos();
+  if exchanges.partition_to_ts(&today.format("%Y").to_string()) == Some(ts) {
+    info!("Already downloaded {}", today);
+    return;
+  }
+
+  let mut client = clients.next();
+  let res = retry("exchanges", "get_exchanges", &config.retry, || client.get_exchanges());
+  let results = match res {
+    Ok(results) => results,
+    Err(e) => {
+      give_up("exchanges", "get_exchanges", &e);
+      return;
+    }
+  };
+
+  // Trades and quotes store exchange IDs as a U8, so a wider one can't be mapped
+  let mut snapshot = Vec::<(u8, ExchangeInfo)>::new();
+  for e in results.into_iter().filter(|e| e.market == "equities") {
+    match u8::try_from(e.id) {
+      Ok(id) => snapshot.push((id, to_info(e))),
+      Err(_) => {
+        error!(id = e.id, name = %e.name, "Exchange ID does not fit in a U8");
+        report::failure("exchanges");
+        shutdown::fail();
+        return;
+      }
+    }
+  }
+  snapshot.sort_unstable_by(|e1, e2| e1.0.cmp(&e2.0));
+
+  // Only version the table when the mapping actually changed. Streams always get a snapshot.
+  if exchanges.kind() == SinkKind::Zdb {
+    let previous = open_exchanges();
+    if previous.len() == snapshot.len()
+      && snapshot.iter().all(|(id, info)| previous.get(id) == Some(info))
+    {
+      info!("Unchanged since last snapshot");
+      return;
+    }
+  }
+
+  debug!("Writing {} exchanges", snapshot.len());
+  exchanges.append(batch(&today.to_string(), vec![
+    ("ts", Values::Timestamp(snapshot.iter().map(|_| ts).collect())),
+    ("id", Values::U8(snapshot.iter().map(|(id, _)| *id).collect())),
+    ("mic", Values::Str(snapshot.iter_mut().map(|(_, e)| std::mem::take(&mut e.mic)).collect())),
+    ("name", Values::Str(snapshot.iter_mut().map(|(_, e)| std::mem::take(&mut e.name)).collect())),
+    ("type", Values::Str(snapshot.iter_mut().map(|(_, e)| std::mem::take(&mut e.r#type)).collect())),
+    ("tape", Values::Str(snapshot.iter_mut().map(|(_, e)| std::mem::take(&mut e.tape)).collect())),
+  ]));
+  exchanges.flush();
+  exchanges.finish();
+
+  info!("Downloaded in {}s", now.elapsed().as_secs());
+}
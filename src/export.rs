@@ -0,0 +1,336 @@
+use crate::{
+  batch::{table_spec, Batch, ColumnKind, TableSpec, TsFormat, Values},
+  compact::{is_compacted, manifest_summary, thaw_partition},
+  conditions::condition_names,
+  exchanges::{open_exchanges, ExchangeInfo},
+  layout::check_layout,
+  strings::StringHeapReader,
+  symbols::lookup
+};
+use chrono::{Duration, NaiveDate};
+use parquet::{
+  arrow::ArrowWriter,
+  basic::Compression,
+  file::properties::WriterProperties
+};
+use std::{
+  collections::{HashMap, HashSet},
+  fs::{self, File},
+  io::{self, BufWriter, ErrorKind, Write},
+  path::{Path, PathBuf},
+  process,
+  time::Instant
+};
+use tracing::{error, info};
+use zdb::{calendar::ToNaiveDateTime, table::Table};
+
+pub struct ExportArgs<'a> {
+  pub table:     &'a str,
+  pub from:      NaiveDate,
+  pub to:        NaiveDate,
+  pub symbols:   Vec<&'a str>,
+  // Empty means every column
+  pub columns:   Vec<&'a str>,
+  pub format:    &'a str,
+  pub ts_format: TsFormat,
+  // None means stdout
+  pub out:       Option<PathBuf>
+}
+
+struct Decoder {
+  heaps:     HashMap<&'static str, StringHeapReader>,
+  exchanges: HashMap<u8, ExchangeInfo>
+}
+
+impl Decoder {
+  fn new(spec: &TableSpec) -> Decoder {
+    let mut heaps = HashMap::<&'static str, StringHeapReader>::new();
+    let mut exchanges = HashMap::<u8, ExchangeInfo>::new();
+    for (_name, kind) in spec.columns.iter() {
+      match kind {
+        ColumnKind::Heap(heap) => {
+          let reader = StringHeapReader::open(spec.name, heap).expect("Could not open string heap");
+          heaps.insert(*heap, reader);
+        }
+        ColumnKind::Exchange => exchanges = open_exchanges(),
+        _ => {}
+      }
+    }
+
+    Decoder { heaps, exchanges }
+  }
+}
+
+// Reads the requested columns of every partition in [from, to] and hands each one to `f`
+// with symbols, string heap offsets and exchange IDs decoded.
+pub fn for_each_batch<F>(spec: &TableSpec, args: &ExportArgs, mut f: F)
+where
+  F: FnMut(Batch)
+{
+  check_layout(spec.name);
+  let mut table = match Table::open(spec.name) {
+    Ok(table) => table,
+    Err(e) => {
+      error!(table = spec.name, error = %e, "Could not open table");
+      process::exit(1);
+    }
+  };
+  let projected = spec
+    .columns
+    .iter()
+    .filter(|(name, _kind)| args.columns.len() == 0 || args.columns.contains(name))
+    .cloned()
+    .collect::<Vec<_>>();
+  for c in args.columns.iter() {
+    if !spec.columns.iter().any(|(name, _kind)| name == c) {
+      error!(table = spec.name, column = c, "No such column");
+      process::exit(1);
+    }
+  }
+  let decoder = Decoder::new(spec);
+  let symbols = args.symbols.iter().cloned().collect::<HashSet<&str>>();
+
+  // Always read ts to name partitions and sym to filter by symbol
+  let mut read = vec!["ts"];
+  let has_sym = spec.columns.iter().any(|(name, _kind)| *name == "sym");
+  if has_sym {
+    read.push("sym");
+  }
+  for (name, _kind) in projected.iter() {
+    if !read.contains(name) {
+      read.push(name);
+    }
+  }
+
+  let from_ts = args.from.and_hms(0, 0, 0).timestamp_nanos();
+  // Include every row on the last day
+  let to_ts = (args.to + Duration::days(1)).and_hms(0, 0, 0).timestamp_nanos() - 1;
+  let mut partitions = table
+    .partition_meta
+    .iter()
+    .filter(|(_partition, meta)| meta.to_ts >= from_ts)
+    .filter(|(_partition, meta)| {
+      // Compacted partitions know where they start without decompressing them
+      let dir = Path::new(&meta.dir);
+      !is_compacted(dir) || manifest_summary(dir).map_or(true, |(first, _bytes)| first <= to_ts)
+    })
+    .map(|(partition, meta)| (meta.to_ts, partition.clone()))
+    .collect::<Vec<_>>();
+  partitions.sort();
+  for (partition_to_ts, partition_name) in partitions.iter() {
+    // One at a time so only one compacted partition is ever decompressed
+    let _thawed = thaw_partition(&mut table, spec.name, partition_name, &read);
+    let partition = table.partition_iter(*partition_to_ts, *partition_to_ts, read.clone()).next();
+    let partition = match partition {
+      Some(partition) => partition,
+      None => continue
+    };
+    let ts = partition[0].get_i64();
+    if ts.len() == 0 || ts[0] > to_ts {
+      continue;
+    }
+    let name = ts[0]
+      .to_naive_date_time()
+      .format(spec.partition_format)
+      .to_string();
+
+    let mut mask = ts.iter().map(|ts| *ts >= from_ts && *ts <= to_ts).collect::<Vec<_>>();
+    if has_sym && symbols.len() > 0 {
+      let col = &partition[1];
+      for (i, sym_i) in col.get_u16().iter().enumerate() {
+        let keep = match lookup(&col.symbols, *sym_i as usize) {
+          Some(sym) => symbols.contains(sym.as_str()),
+          None => false
+        };
+        mask[i] = mask[i] && keep;
+      }
+    }
+
+    let mut columns = Vec::<(String, Values)>::with_capacity(projected.len());
+    for (name, kind) in projected.iter() {
+      let col = &partition[read.iter().position(|c| c == name).unwrap()];
+      let sym = |sym_i: usize| lookup(&col.symbols, sym_i).cloned().unwrap_or(String::new());
+      let values = match kind {
+        ColumnKind::Timestamp => Values::Timestamp(col.get_i64().to_vec()),
+        ColumnKind::I64 => Values::I64(col.get_i64().to_vec()),
+        ColumnKind::U64 => Values::U64(col.get_u64().to_vec()),
+        ColumnKind::U32 => Values::U32(col.get_u32().to_vec()),
+        ColumnKind::U8 => Values::U8(col.get_u8().to_vec()),
+        ColumnKind::F64 => Values::F64(col.get_f64().to_vec()),
+        ColumnKind::Symbol8 => {
+          Values::Str(col.get_u8().iter().map(|i| sym(*i as usize)).collect())
+        }
+        ColumnKind::Symbol16 => {
+          Values::Str(col.get_u16().iter().map(|i| sym(*i as usize)).collect())
+        }
+        ColumnKind::Heap(heap) => {
+          let heap = &decoder.heaps[*heap];
+          let get = |offset: &u64| heap.get(*offset).unwrap_or("").to_string();
+          Values::Str(col.get_u64().iter().map(get).collect())
+        }
+        ColumnKind::Exchange => {
+          let ids = col.get_u8();
+          let names = ids
+            .iter()
+            .map(|id| match decoder.exchanges.get(id) {
+              Some(info) => info.name.clone(),
+              None => String::new()
+            })
+            .collect();
+          columns.push((name.to_string(), Values::U8(ids.to_vec())));
+          columns.push((format!("{}_name", name), Values::Str(names)));
+          continue;
+        }
+        ColumnKind::Conditions => {
+          let conds = col.get_u32();
+          let names = conds.iter().map(|cond| condition_names(*cond)).collect();
+          columns.push((name.to_string(), Values::U32(conds.to_vec())));
+          columns.push((format!("{}_names", name), Values::Str(names)));
+          continue;
+        }
+      };
+      columns.push((name.to_string(), values));
+    }
+    for (_name, values) in columns.iter_mut() {
+      values.retain(&mask);
+    }
+
+    f(Batch {
+      partition: name,
+      columns
+    });
+  }
+}
+
+pub fn write_parquet(path: &PathBuf, batch: &Batch) {
+  let record_batch = batch.to_record_batch();
+  let props = WriterProperties::builder()
+    .set_compression(Compression::SNAPPY)
+    .set_statistics_enabled(true)
+    .set_max_row_group_size(1 << 20)
+    .build();
+  let file = File::create(path).unwrap_or_else(|e| panic!("Could not create {:?}: {}", path, e));
+  let mut writer = ArrowWriter::try_new(file, record_batch.schema(), Some(props))
+    .expect("Could not create parquet writer");
+  writer.write(&record_batch).expect("Could not write parquet");
+  writer.close().expect("Could not close parquet writer");
+}
+
+fn write_csv<W: Write>(w: &mut W, batch: &Batch, ts_format: TsFormat, header: bool) -> io::Result<()> {
+  if header {
+    let names = batch.columns.iter().map(|(name, _values)| name.as_str()).collect::<Vec<_>>();
+    writeln!(w, "{}", names.join(","))?;
+  }
+  for i in 0..batch.num_rows() {
+    let row = batch
+      .columns
+      .iter()
+      .map(|(_name, values)| values.fmt_csv(i, ts_format))
+      .collect::<Vec<_>>();
+    writeln!(w, "{}", row.join(","))?;
+  }
+
+  Ok(())
+}
+
+pub fn write_ndjson<W: Write>(w: &mut W, batch: &Batch, ts_format: TsFormat) -> io::Result<()> {
+  let keys = batch
+    .columns
+    .iter()
+    .map(|(name, _values)| serde_json::to_string(name).unwrap())
+    .collect::<Vec<_>>();
+  for i in 0..batch.num_rows() {
+    let row = batch
+      .columns
+      .iter()
+      .zip(keys.iter())
+      .map(|((_name, values), key)| format!("{}:{}", key, values.fmt_json(i, ts_format)))
+      .collect::<Vec<_>>();
+    writeln!(w, "{{{}}}", row.join(","))?;
+  }
+
+  Ok(())
+}
+
+fn write_text(path: Option<&PathBuf>, batch: &Batch, args: &ExportArgs, header: bool) {
+  let res = match path {
+    Some(path) => {
+      let file = File::create(path).unwrap_or_else(|e| panic!("Could not create {:?}: {}", path, e));
+      let mut w = BufWriter::new(file);
+      let res = match args.format {
+        "csv" => write_csv(&mut w, batch, args.ts_format, true),
+        _ => write_ndjson(&mut w, batch, args.ts_format)
+      };
+      res.and_then(|_| w.flush())
+    }
+    None => {
+      let stdout = io::stdout();
+      let mut w = BufWriter::new(stdout.lock());
+      let res = match args.format {
+        "csv" => write_csv(&mut w, batch, args.ts_format, header),
+        _ => write_ndjson(&mut w, batch, args.ts_format)
+      };
+      res.and_then(|_| w.flush())
+    }
+  };
+  match res {
+    Ok(_) => {}
+    // Piped into something like `head` which has seen enough
+    Err(e) if e.kind() == ErrorKind::BrokenPipe => process::exit(0),
+    Err(e) => {
+      error!(format = args.format, error = %e, "Could not write");
+      process::exit(1);
+    }
+  }
+}
+
+pub fn export(args: ExportArgs) {
+  let now = Instant::now();
+  let spec = match table_spec(args.table) {
+    Some(spec) => spec,
+    None => {
+      error!(table = args.table, "Unknown table");
+      process::exit(1);
+    }
+  };
+  let extension = match args.format {
+    "parquet" => "parquet",
+    "csv" => "csv",
+    "ndjson" => "ndjson",
+    _ => {
+      error!(format = args.format, "Unknown format");
+      process::exit(1);
+    }
+  };
+  match &args.out {
+    Some(out) => fs::create_dir_all(out).unwrap_or_else(|e| panic!("Could not create {:?}: {}", out, e)),
+    None if args.format == "parquet" => {
+      error!("Exporting to parquet requires --out");
+      process::exit(1);
+    }
+    None => {}
+  }
+
+  let mut num_rows = 0;
+  for_each_batch(&spec, &args, |batch| {
+    if batch.num_rows() == 0 {
+      return;
+    }
+    let path = args
+      .out
+      .as_ref()
+      .map(|out| out.join(format!("{}_{}.{}", spec.name, batch.partition, extension)));
+    match &path {
+      Some(path) => info!(partition = %batch.partition, ?path, "Writing {} rows", batch.num_rows()),
+      None => info!(partition = %batch.partition, "Writing {} rows", batch.num_rows())
+    }
+    match (args.format, &path) {
+      ("parquet", Some(path)) => write_parquet(path, &batch),
+      // Only the first batch on stdout gets a CSV header
+      _ => write_text(path.as_ref(), &batch, &args, num_rows == 0)
+    }
+    num_rows += batch.num_rows();
+  });
+
+  info!(table = spec.name, "Exported {} rows in {}s", num_rows, now.elapsed().as_secs());
+}
@@ -0,0 +1,364 @@
+use crate::{
+  agg1d::{agg1d_schema, agg1d_symbols, write_agg1d},
+  agg1m::{agg1m_schema, write_agg1m},
+  conditions::encode_conditions,
+  journal::Savepoint,
+  quotes::{quotes_schema, write_quotes, QuoteRow},
+  sink::{open_sink, Sink, SinkKind},
+  trades::{trades_schema, write_trades, TradeRow},
+  util::Bar
+};
+use chrono::NaiveDate;
+use csv::StringRecord;
+use flate2::read::GzDecoder;
+use std::{
+  collections::HashSet,
+  fs::{self, File},
+  path::{Path, PathBuf},
+  process,
+  str::FromStr,
+  time::Instant
+};
+use tracing::{error, info, info_span, warn};
+use zdb::{calendar::ToNaiveDateTime, table::Table};
+
+pub struct ImportArgs<'a> {
+  // Root of the flat files, which has a directory per dataset like trades_v1
+  pub dir:       PathBuf,
+  pub datasets:  Vec<&'a str>,
+  pub from:      NaiveDate,
+  pub to:        NaiveDate,
+  pub data_dirs: Vec<&'a str>
+}
+
+// Recursively finds files named like 2021-03-04.csv.gz in [from, to]
+fn flat_files(dir: &Path, from: NaiveDate, to: NaiveDate) -> Vec<(NaiveDate, PathBuf)> {
+  let mut res = Vec::<(NaiveDate, PathBuf)>::new();
+  let entries = match fs::read_dir(dir) {
+    Ok(entries) => entries,
+    Err(e) => {
+      warn!(?dir, error = %e, "Could not read flat file directory");
+      return res;
+    }
+  };
+  for entry in entries.filter_map(|e| e.ok()) {
+    let path = entry.path();
+    if path.is_dir() {
+      res.append(&mut flat_files(&path, from, to));
+      continue;
+    }
+    let name = path.file_name().unwrap().to_string_lossy().to_string();
+    if !name.ends_with(".csv.gz") {
+      continue;
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(&name[..10.min(name.len())], "%Y-%m-%d") {
+      if date >= from && date <= to {
+        res.push((date, path));
+      }
+    }
+  }
+  res.sort_unstable_by(|f1, f2| f1.0.cmp(&f2.0));
+
+  res
+}
+
+// Whether `date` is already in the partition keyed by `key`, using the same resume logic as the
+// REST downloaders
+fn is_imported(output: &dyn Sink, key: &str, date: NaiveDate) -> bool {
+  match output.partition_to_ts(key) {
+    Some(to_ts) => to_ts.to_naive_date_time().date() >= date,
+    None => false
+  }
+}
+
+struct Row<'a> {
+  path:    &'a Path,
+  line:    u64,
+  headers: &'a StringRecord,
+  record:  &'a StringRecord
+}
+
+impl<'a> Row<'a> {
+  fn get(&self, name: &str) -> &'a str {
+    match self.headers.iter().position(|h| h == name) {
+      Some(i) => self.record.get(i).unwrap_or(""),
+      None => {
+        error!(path = ?self.path, column = name, "Missing column");
+        process::exit(1);
+      }
+    }
+  }
+
+  fn error(&self, name: &str, value: &str) -> String {
+    format!("{:?} line {} column {}: invalid value {:?}", self.path, self.line, name, value)
+  }
+
+  fn parse<T: FromStr>(&self, name: &str) -> Result<T, String> {
+    let value = self.get(name);
+    value.parse::<T>().map_err(|_| self.error(name, value))
+  }
+
+  // For columns that are empty when they don't apply, like a trade's correction
+  fn parse_or_default<T: FromStr + Default>(&self, name: &str) -> Result<T, String> {
+    match self.get(name) {
+      "" => Ok(T::default()),
+      _ => self.parse(name)
+    }
+  }
+
+  fn parse_conditions(&self, name: &str) -> Result<u32, String> {
+    let value = self.get(name);
+    let ids = value
+      .split(',')
+      .map(str::trim)
+      .filter(|id| !id.is_empty())
+      .map(|id| id.parse::<u8>().map_err(|_| self.error(name, value)))
+      .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(encode_conditions(&ids))
+  }
+}
+
+// Reads every row of a CSV.gz flat file. Exits on the first row `f` can't parse, before
+// anything of the file is written.
+fn read_flat_file<F>(path: &Path, mut f: F)
+where
+  F: FnMut(&Row) -> Result<(), String>
+{
+  let file = File::open(path).unwrap_or_else(|e| panic!("Could not open {:?}: {}", path, e));
+  let mut reader = csv::Reader::from_reader(GzDecoder::new(file));
+  let headers = match reader.headers() {
+    Ok(headers) => headers.clone(),
+    Err(e) => {
+      error!(?path, error = %e, "Could not read header");
+      process::exit(1);
+    }
+  };
+  let mut record = StringRecord::new();
+  loop {
+    match reader.read_record(&mut record) {
+      Ok(true) => {}
+      Ok(false) => break,
+      Err(e) => {
+        error!(?path, error = %e, "Could not read record");
+        process::exit(1);
+      }
+    }
+    let row = Row {
+      path,
+      line: record.position().map(|p| p.line()).unwrap_or(0),
+      headers: &headers,
+      record: &record
+    };
+    if let Err(e) = f(&row) {
+      error!(error = %e, "Could not parse row");
+      process::exit(1);
+    }
+  }
+}
+
+fn parse_bar(row: &Row) -> Result<Bar, String> {
+  Ok(Bar {
+    ts:     row.parse("window_start")?,
+    symbol: row.get("ticker").to_string(),
+    open:   row.parse("open")?,
+    high:   row.parse("high")?,
+    low:    row.parse("low")?,
+    close:  row.parse("close")?,
+    volume: row.parse::<f64>("volume")? as u64
+  })
+}
+
+// Flat files have every symbol Polygon knows of while the REST downloaders only fetch those in
+// agg1d for the day. Keeps imports to the same symbols. Returns None if agg1d has none.
+fn agg1d_filter(agg1d: &Table, date: NaiveDate, traded: bool) -> Option<HashSet<String>> {
+  let symbols = agg1d_symbols(agg1d, date, date, traded);
+  if symbols.is_empty() {
+    warn!(%date, "No agg1d, skipping");
+    return None;
+  }
+
+  Some(symbols)
+}
+
+fn open_agg1d() -> Table {
+  match Table::open("agg1d") {
+    Ok(table) => table,
+    Err(e) => {
+      error!(error = %e, "Table agg1d must exist to know which symbols to import");
+      process::exit(1);
+    }
+  }
+}
+
+fn import_agg1d(files: Vec<(NaiveDate, PathBuf)>) {
+  // Flat files are final, so they replace provisional rows
+  let replace = [Savepoint::Provisional];
+  let mut agg1d = open_sink(SinkKind::Zdb, agg1d_schema(), "agg1d", None, &replace);
+  for (date, path) in files {
+    if is_imported(&*agg1d, &date.format("%Y").to_string(), date) {
+      continue;
+    }
+    let mut candles = Vec::<Bar>::new();
+    read_flat_file(&path, |row| {
+      candles.push(parse_bar(row)?);
+      Ok(())
+    });
+    write_agg1d(&mut *agg1d, &mut candles, &date.to_string());
+  }
+}
+
+fn import_agg1m(files: Vec<(NaiveDate, PathBuf)>, data_dirs: Vec<&str>) {
+  let agg1d = open_agg1d();
+  let replace = [Savepoint::Provisional, Savepoint::Live];
+  let mut agg1m = open_sink(SinkKind::Zdb, agg1m_schema(data_dirs), "agg1m", None, &replace);
+  for (date, path) in files {
+    if is_imported(&*agg1m, &date.format("%Y-%m").to_string(), date) {
+      continue;
+    }
+    let symbols = match agg1d_filter(&agg1d, date, true) {
+      Some(symbols) => symbols,
+      None => continue
+    };
+    let mut candles = Vec::<Bar>::new();
+    read_flat_file(&path, |row| {
+      let bar = parse_bar(row)?;
+      if symbols.contains(&bar.symbol) {
+        candles.push(bar);
+      }
+      Ok(())
+    });
+    write_agg1m(&mut *agg1m, &mut candles, &date.to_string());
+  }
+}
+
+fn import_trades(files: Vec<(NaiveDate, PathBuf)>, data_dirs: Vec<&str>) {
+  let agg1d = open_agg1d();
+  let replace = [Savepoint::Live];
+  let mut trades = open_sink(SinkKind::Zdb, trades_schema(data_dirs), "trades", None, &replace);
+  for (date, path) in files {
+    if is_imported(&*trades, &date.format("%Y-%m-%d").to_string(), date) {
+      continue;
+    }
+    let symbols = match agg1d_filter(&agg1d, date, false) {
+      Some(symbols) => symbols,
+      None => continue
+    };
+    let mut rows = Vec::<TradeRow>::new();
+    read_flat_file(&path, |row| {
+      if !symbols.contains(row.get("ticker")) {
+        return Ok(());
+      }
+      rows.push(TradeRow {
+        ts:             row.parse("sip_timestamp")?,
+        ts_participant: row.parse_or_default("participant_timestamp")?,
+        id:             row.parse("id")?,
+        seq_id:         row.parse("sequence_number")?,
+        symbol:         row.get("ticker").to_string(),
+        size:           row.parse::<f64>("size")? as u32,
+        price:          row.parse("price")?,
+        conditions:     row.parse_conditions("conditions")?,
+        error:          row.parse_or_default("correction")?,
+        exchange:       row.parse("exchange")?,
+        tape:           row.parse("tape")?
+      });
+      Ok(())
+    });
+    write_trades(&mut *trades, &mut rows, date);
+  }
+}
+
+fn import_quotes(files: Vec<(NaiveDate, PathBuf)>, data_dirs: Vec<&str>) {
+  let replace = [Savepoint::Live];
+  let mut quotes = open_sink(SinkKind::Zdb, quotes_schema(data_dirs), "quotes", None, &replace);
+  for (date, path) in files {
+    if is_imported(&*quotes, &date.format("%Y-%m-%d").to_string(), date) {
+      continue;
+    }
+    let mut rows = Vec::<QuoteRow>::new();
+    read_flat_file(&path, |row| {
+      rows.push(QuoteRow {
+        ts:             row.parse("sip_timestamp")?,
+        ts_participant: row.parse_or_default("participant_timestamp")?,
+        seq_id:         row.parse("sequence_number")?,
+        symbol:         row.get("ticker").to_string(),
+        bid_price:      row.parse("bid_price")?,
+        bid_size:       row.parse::<f64>("bid_size")? as u32,
+        bid_exchange:   row.parse("bid_exchange")?,
+        ask_price:      row.parse("ask_price")?,
+        ask_size:       row.parse::<f64>("ask_size")? as u32,
+        ask_exchange:   row.parse("ask_exchange")?,
+        conditions:     row.parse_conditions("conditions")?,
+        indicators:     row.parse_conditions("indicators")?,
+        tape:           row.parse("tape")?
+      });
+      Ok(())
+    });
+    write_quotes(&mut *quotes, &mut rows, date);
+  }
+}
+
+pub fn import(args: ImportArgs) {
+  for dataset in args.datasets.iter() {
+    let now = Instant::now();
+    let _span = info_span!("import", dataset = *dataset).entered();
+    let dataset_dir = match *dataset {
+      "agg1d" => "day_aggs_v1",
+      "agg1m" => "minute_aggs_v1",
+      "trades" => "trades_v1",
+      "quotes" => "quotes_v1",
+      _ => {
+        error!("Unknown dataset");
+        process::exit(1);
+      }
+    };
+    let files = flat_files(&args.dir.join(dataset_dir), args.from, args.to);
+    info!("Importing {} flat files", files.len());
+    match *dataset {
+      "agg1d" => import_agg1d(files),
+      "agg1m" => import_agg1m(files, args.data_dirs.clone()),
+      "trades" => import_trades(files, args.data_dirs.clone()),
+      _ => import_quotes(files, args.data_dirs.clone())
+    }
+    info!("Imported in {}s", now.elapsed().as_secs());
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn new_row<'a>(headers: &'a StringRecord, record: &'a StringRecord) -> Row<'a> {
+    Row { path: Path::new("2021-03-04.csv.gz"), line: 7, headers, record }
+  }
+
+  #[test]
+  fn parse() {
+    let headers = StringRecord::from(vec!["ticker", "size", "correction", "conditions"]);
+    let record = StringRecord::from(vec!["AAPL", "100", "", "12, 37"]);
+    let row = new_row(&headers, &record);
+    assert_eq!(row.get("ticker"), "AAPL");
+    assert_eq!(row.parse::<u32>("size"), Ok(100));
+    assert_eq!(row.parse_or_default::<u8>("correction"), Ok(0));
+    assert_eq!(row.parse_conditions("conditions"), Ok(encode_conditions(&[12, 37])));
+  }
+
+  #[test]
+  fn parse_errors() {
+    let headers = StringRecord::from(vec!["size", "correction", "conditions"]);
+    let record = StringRecord::from(vec!["1e", "x", "12,abc"]);
+    let row = new_row(&headers, &record);
+    assert_eq!(
+      row.parse::<u32>("size"),
+      Err("\"2021-03-04.csv.gz\" line 7 column size: invalid value \"1e\"".to_string())
+    );
+    // Empty isn't a default for required columns
+    let empty = StringRecord::from(vec!["", "", ""]);
+    assert!(new_row(&headers, &empty).parse::<u32>("size").is_err());
+    assert!(row.parse_or_default::<u8>("correction").is_err());
+    assert_eq!(
+      row.parse_conditions("conditions"),
+      Err("\"2021-03-04.csv.gz\" line 7 column conditions: invalid value \"12,abc\"".to_string())
+    );
+  }
+}
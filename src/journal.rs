@@ -0,0 +1,517 @@
+use crate::util::table_dir;
+use serde_json::json;
+use std::{
+  collections::HashMap,
+  fs::{self, File, OpenOptions},
+  io,
+  path::{Path, PathBuf}
+};
+use tracing::{info, warn};
+
+// zdb writes columns in place and persists partition_meta on flush, so a crash in between
+// leaves them disagreeing. Before touching a partition we stage what it takes to undo the
+// change under data/<table>/.staging/<partition>/:
+//   meta/         copies of the table's metadata files
+//   journal.json  the partition's dir and the length of each column file in it
+// Column files are only appended to, so undoing an append is truncating them back. The
+// journal is written last and removed once everything is fsynced, which is the commit. One
+// left behind on open means the partition is torn and gets rolled back.
+
+fn staging_dir(table: &str) -> PathBuf { table_dir(table).join(".staging") }
+
+// Why rows appended to a partition after a savepoint get replaced
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Savepoint {
+  // Days that weren't final when they were fetched
+  Provisional,
+  // Rows streamed by a live session, which the historical data replaces
+  Live
+}
+
+impl Savepoint {
+  fn dir(&self, table: &str) -> PathBuf {
+    match self {
+      Savepoint::Provisional => table_dir(table).join(".provisional"),
+      Savepoint::Live => table_dir(table).join(".live")
+    }
+  }
+}
+
+const SAVEPOINTS: [Savepoint; 2] = [Savepoint::Provisional, Savepoint::Live];
+
+// A partition as it was before rows that get replaced were appended to it. Unlike a journal
+// it only covers its own partition, so rolling back to it leaves other partitions alone.
+#[derive(Debug)]
+pub struct Saved {
+  pub kind:      Savepoint,
+  pub partition: String,
+  // (to_ts, row_count), or None if the partition didn't exist yet
+  pub meta:      Option<(i64, usize)>,
+  // Length of each column file
+  pub lengths:   HashMap<String, u64>
+}
+
+fn sync_dir(dir: &Path) -> io::Result<()> { File::open(dir)?.sync_all() }
+
+// Heaps are append-only and only referenced once the table is flushed, and the lockfile isn't
+// zdb's, so neither needs undoing
+fn is_meta_file(path: &Path) -> bool {
+  path.is_file()
+    && path.extension().map(|e| e != "strings").unwrap_or(true)
+    && path.file_name().map(|n| n != "lock").unwrap_or(false)
+}
+
+fn meta_files(table: &str) -> io::Result<Vec<PathBuf>> {
+  let dir = table_dir(table);
+  if !dir.exists() {
+    return Ok(Vec::new());
+  }
+  let mut res = Vec::<PathBuf>::new();
+  for entry in fs::read_dir(&dir)? {
+    let path = entry?.path();
+    if is_meta_file(&path) {
+      res.push(path);
+    }
+  }
+
+  Ok(res)
+}
+
+fn file_lengths(dir: &Path) -> io::Result<HashMap<String, u64>> {
+  let mut res = HashMap::<String, u64>::new();
+  if !dir.exists() {
+    return Ok(res);
+  }
+  for entry in fs::read_dir(dir)? {
+    let entry = entry?;
+    let meta = entry.metadata()?;
+    if meta.is_file() {
+      res.insert(entry.file_name().to_string_lossy().to_string(), meta.len());
+    }
+  }
+
+  Ok(res)
+}
+
+fn sync_files(dir: &Path) -> io::Result<()> {
+  if !dir.exists() {
+    return Ok(());
+  }
+  for entry in fs::read_dir(dir)? {
+    let path = entry?.path();
+    if path.is_file() {
+      File::open(&path)?.sync_all()?;
+    }
+  }
+  sync_dir(dir)
+}
+
+pub struct Journal {
+  table: String,
+  dir:   PathBuf
+}
+
+// Stages undoing a change to `partition` in `dir`
+fn stage(dir: &Path, table: &str, partition: &str, dirs: &[PathBuf]) -> io::Result<()> {
+  if dir.exists() {
+    fs::remove_dir_all(dir)?;
+  }
+  let meta_dir = dir.join("meta");
+  fs::create_dir_all(&meta_dir)?;
+  for path in meta_files(table)? {
+    let to = meta_dir.join(path.file_name().unwrap());
+    fs::copy(&path, &to)?;
+    File::open(&to)?.sync_all()?;
+  }
+  sync_dir(&meta_dir)?;
+
+  let mut lengths = serde_json::Map::new();
+  for partition_dir in dirs.iter() {
+    lengths.insert(
+      partition_dir.to_string_lossy().to_string(),
+      json!(file_lengths(partition_dir)?)
+    );
+  }
+  let json = json!({ "partition": partition, "dirs": lengths });
+  let tmp = dir.join("journal.json.tmp");
+  fs::write(&tmp, serde_json::to_string(&json).unwrap())?;
+  File::open(&tmp)?.sync_all()?;
+  fs::rename(&tmp, dir.join("journal.json"))?;
+  sync_dir(dir)?;
+  sync_dir(dir.parent().unwrap())
+}
+
+impl Journal {
+  // `dirs` are where the partition's column files are or, for a new partition, every place zdb
+  // might put them
+  pub fn begin(table: &str, partition: &str, dirs: &[PathBuf]) -> io::Result<Journal> {
+    let dir = staging_dir(table).join(partition);
+    stage(&dir, table, partition, dirs)?;
+
+    Ok(Journal { table: table.to_string(), dir })
+  }
+
+  // Call after the table is flushed. `dirs` are the partition's dirs now.
+  pub fn commit(self, dirs: &[PathBuf]) -> io::Result<()> {
+    for dir in dirs.iter() {
+      sync_files(dir)?;
+    }
+    sync_files(&table_dir(&self.table))?;
+    fs::remove_file(self.dir.join("journal.json"))?;
+    sync_dir(&self.dir)?;
+    fs::remove_dir_all(&self.dir)
+  }
+}
+
+fn rollback(table: &str, dir: &Path) -> io::Result<()> {
+  let data = fs::read_to_string(dir.join("journal.json"))?;
+  let json = serde_json::from_str::<serde_json::Value>(&data)
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+  let empty = serde_json::Map::new();
+  for (partition_dir, lengths) in json["dirs"].as_object().unwrap_or(&empty) {
+    let partition_dir = PathBuf::from(partition_dir);
+    let lengths = lengths.as_object().unwrap_or(&empty);
+    if lengths.is_empty() {
+      // Didn't exist before
+      if partition_dir.exists() {
+        fs::remove_dir_all(&partition_dir)?;
+      }
+      continue;
+    }
+    // A drop renames the dir away before deleting it
+    let dropped = partition_dir.with_extension("dropped");
+    if !partition_dir.exists() && dropped.exists() {
+      fs::rename(&dropped, &partition_dir)?;
+    }
+    for entry in fs::read_dir(&partition_dir)? {
+      let path = entry?.path();
+      let name = path.file_name().unwrap().to_string_lossy().to_string();
+      match lengths.get(&name).and_then(|l| l.as_u64()) {
+        Some(len) => {
+          let file = OpenOptions::new().write(true).open(&path)?;
+          file.set_len(len)?;
+          file.sync_all()?;
+        }
+        None if path.is_file() => fs::remove_file(&path)?,
+        None => {}
+      }
+    }
+    sync_dir(&partition_dir)?;
+  }
+
+  let meta_dir = dir.join("meta");
+  let staged = fs::read_dir(&meta_dir)?
+    .map(|e| e.map(|e| e.file_name()))
+    .collect::<io::Result<Vec<_>>>()?;
+  for path in meta_files(table)? {
+    if !staged.iter().any(|name| Some(name.as_os_str()) == path.file_name()) {
+      fs::remove_file(&path)?;
+    }
+  }
+  for name in staged.iter() {
+    fs::copy(meta_dir.join(name), table_dir(table).join(name))?;
+  }
+  sync_files(&table_dir(table))?;
+
+  fs::remove_dir_all(dir)
+}
+
+// Rolls back every partition of `table` whose change didn't commit. Call before opening it.
+pub fn recover(table: &str) -> io::Result<()> {
+  let staging = staging_dir(table);
+  if !staging.exists() {
+    return Ok(());
+  }
+  for entry in fs::read_dir(&staging)? {
+    let dir = entry?.path();
+    if dir.join("journal.json").exists() {
+      warn!(table, ?dir, "Rolling back torn partition");
+      rollback(table, &dir)?;
+      info!(table, ?dir, "Rolled back");
+    } else {
+      // Crashed after committing but before cleaning up
+      fs::remove_dir_all(&dir)?;
+    }
+  }
+
+  Ok(())
+}
+
+fn savepoint_path(table: &str, kind: Savepoint, partition: &str) -> PathBuf {
+  kind.dir(table).join(format!("{}.json", partition))
+}
+
+// Records `partition` as it is now, with its column files in `dir` and its (to_ts, row_count)
+// `meta`. Call after flushing. Keeps the first savepoint if there's already one.
+pub fn savepoint(
+  table: &str,
+  kind: Savepoint,
+  partition: &str,
+  dir: Option<&Path>,
+  meta: Option<(i64, usize)>
+) -> io::Result<()> {
+  let path = savepoint_path(table, kind, partition);
+  if path.exists() {
+    return Ok(());
+  }
+  let lengths = match dir {
+    Some(dir) => file_lengths(dir)?,
+    None => HashMap::new()
+  };
+  let json = json!({
+    "partition": partition,
+    "meta": meta.map(|(to_ts, row_count)| json!({ "to_ts": to_ts, "row_count": row_count })),
+    "lengths": lengths
+  });
+  fs::create_dir_all(kind.dir(table))?;
+  let tmp = path.with_extension("json.tmp");
+  fs::write(&tmp, serde_json::to_string(&json).unwrap())?;
+  File::open(&tmp)?.sync_all()?;
+  fs::rename(&tmp, &path)?;
+  sync_dir(&kind.dir(table))
+}
+
+fn read_saved(kind: Savepoint, path: &Path) -> io::Result<Saved> {
+  let data = fs::read_to_string(path)?;
+  let json = serde_json::from_str::<serde_json::Value>(&data)
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+  let meta = match (json["meta"]["to_ts"].as_i64(), json["meta"]["row_count"].as_u64()) {
+    (Some(to_ts), Some(row_count)) => Some((to_ts, row_count as usize)),
+    _ => None
+  };
+  let lengths = json["lengths"]
+    .as_object()
+    .into_iter()
+    .flatten()
+    .filter_map(|(name, len)| len.as_u64().map(|len| (name.clone(), len)))
+    .collect();
+
+  Ok(Saved { kind, partition: json["partition"].as_str().unwrap_or("").to_string(), meta, lengths })
+}
+
+// Every savepoint of `kind` in `table`, by partition
+pub fn savepoints(table: &str, kind: Savepoint) -> io::Result<Vec<Saved>> {
+  let dir = kind.dir(table);
+  if !dir.exists() {
+    return Ok(Vec::new());
+  }
+  let mut res = Vec::<Saved>::new();
+  for entry in fs::read_dir(&dir)? {
+    let path = entry?.path();
+    if path.extension().map(|e| e == "json").unwrap_or(false) {
+      res.push(read_saved(kind, &path)?);
+    }
+  }
+  res.sort_by(|s1, s2| s1.partition.cmp(&s2.partition));
+
+  Ok(res)
+}
+
+pub fn remove_savepoint(table: &str, saved: &Saved) -> io::Result<()> {
+  let path = savepoint_path(table, saved.kind, &saved.partition);
+  if path.exists() {
+    fs::remove_file(&path)?;
+    sync_dir(&saved.kind.dir(table))?;
+  }
+
+  Ok(())
+}
+
+// Removes every savepoint of `partition`, like when it's dropped
+pub fn remove_savepoints(table: &str, partition: &str) -> io::Result<()> {
+  for kind in SAVEPOINTS.iter() {
+    let path = savepoint_path(table, *kind, partition);
+    if path.exists() {
+      fs::remove_file(&path)?;
+    }
+  }
+
+  Ok(())
+}
+
+// Removes the savepoints of `saved`'s partition taken after it, which rolling back to it
+// made out of date
+pub fn remove_later(table: &str, saved: &Saved) -> io::Result<()> {
+  for kind in SAVEPOINTS.iter().filter(|k| **k != saved.kind) {
+    let path = savepoint_path(table, *kind, &saved.partition);
+    if !path.exists() {
+      continue;
+    }
+    let other = read_saved(*kind, &path)?;
+    let is_later = other.meta.is_some()
+      && other.lengths.iter().any(|(name, len)| *len > *saved.lengths.get(name).unwrap_or(&0));
+    if is_later {
+      fs::remove_file(&path)?;
+    }
+  }
+
+  Ok(())
+}
+
+// Truncates the column files in `dir` back to `saved`. Returns false without changing anything
+// if it no longer applies, which is when a file is shorter than when it was taken since
+// something else already rewrote the partition.
+pub fn truncate(saved: &Saved, dir: &Path) -> io::Result<bool> {
+  let lengths = file_lengths(dir)?;
+  if lengths.is_empty() {
+    return Ok(false);
+  }
+  let is_obsolete = saved
+    .lengths
+    .iter()
+    .any(|(name, len)| lengths.get(name).map(|cur| cur < len).unwrap_or(true));
+  if is_obsolete {
+    return Ok(false);
+  }
+  for (name, len) in lengths.iter() {
+    let path = dir.join(name);
+    match saved.lengths.get(name) {
+      Some(saved_len) if saved_len == len => {}
+      Some(saved_len) => {
+        let file = OpenOptions::new().write(true).open(&path)?;
+        file.set_len(*saved_len)?;
+        file.sync_all()?;
+      }
+      None => fs::remove_file(&path)?
+    }
+  }
+  sync_dir(dir)?;
+
+  Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::util::test_data_root;
+  use std::io::Write;
+
+  // A table with a metadata file and one partition of two columns
+  fn table(name: &str) -> PathBuf {
+    test_data_root();
+    let dir = table_dir(name);
+    if dir.exists() {
+      fs::remove_dir_all(&dir).unwrap();
+    }
+    fs::create_dir_all(dir.join("2021")).unwrap();
+    fs::write(dir.join("meta"), "2021").unwrap();
+    fs::write(dir.join("2021").join("ts.i64"), [1u8; 16]).unwrap();
+    fs::write(dir.join("2021").join("close.f64"), [2u8; 16]).unwrap();
+    dir.join("2021")
+  }
+
+  fn append(path: &Path, bytes: &[u8]) {
+    OpenOptions::new().append(true).open(path).unwrap().write_all(bytes).unwrap();
+  }
+
+  fn len(path: &Path) -> u64 { fs::metadata(path).unwrap().len() }
+
+  #[test]
+  fn crash_rolls_back() {
+    let partition = table("journal_crash");
+    let journal = Journal::begin("journal_crash", "2021", &[partition.clone()]).unwrap();
+    append(&partition.join("ts.i64"), &[3; 8]);
+    fs::write(partition.join("volume.u64"), [4u8; 8]).unwrap();
+    fs::write(table_dir("journal_crash").join("meta"), "2021 2022").unwrap();
+    fs::write(table_dir("journal_crash").join("new_meta"), "").unwrap();
+    // Crashed before flushing
+    drop(journal);
+
+    recover("journal_crash").unwrap();
+    assert_eq!(len(&partition.join("ts.i64")), 16);
+    assert_eq!(len(&partition.join("close.f64")), 16);
+    assert!(!partition.join("volume.u64").exists());
+    assert_eq!(fs::read_to_string(table_dir("journal_crash").join("meta")).unwrap(), "2021");
+    assert!(!table_dir("journal_crash").join("new_meta").exists());
+    assert!(!staging_dir("journal_crash").join("2021").exists());
+  }
+
+  #[test]
+  fn commit_keeps_changes() {
+    let partition = table("journal_commit");
+    let journal = Journal::begin("journal_commit", "2021", &[partition.clone()]).unwrap();
+    append(&partition.join("ts.i64"), &[3; 8]);
+    fs::write(table_dir("journal_commit").join("meta"), "2021 2022").unwrap();
+    journal.commit(&[partition.clone()]).unwrap();
+
+    recover("journal_commit").unwrap();
+    assert_eq!(len(&partition.join("ts.i64")), 24);
+    assert_eq!(fs::read_to_string(table_dir("journal_commit").join("meta")).unwrap(), "2021 2022");
+    assert!(!staging_dir("journal_commit").join("2021").exists());
+  }
+
+  #[test]
+  fn new_partition_is_removed() {
+    table("journal_new");
+    let partition = table_dir("journal_new").join("2022");
+    let journal = Journal::begin("journal_new", "2022", &[partition.clone()]).unwrap();
+    fs::create_dir_all(&partition).unwrap();
+    fs::write(partition.join("ts.i64"), [1u8; 8]).unwrap();
+    drop(journal);
+
+    recover("journal_new").unwrap();
+    assert!(!partition.exists());
+    assert_eq!(len(&table_dir("journal_new").join("2021").join("ts.i64")), 16);
+  }
+
+  #[test]
+  fn dropped_partition_is_restored() {
+    let partition = table("journal_dropped");
+    let journal = Journal::begin("journal_dropped", "2021", &[partition.clone()]).unwrap();
+    // What drop_partition does before it flushes
+    fs::rename(&partition, partition.with_extension("dropped")).unwrap();
+    fs::write(table_dir("journal_dropped").join("meta"), "").unwrap();
+    drop(journal);
+
+    recover("journal_dropped").unwrap();
+    assert_eq!(len(&partition.join("ts.i64")), 16);
+    assert!(!partition.with_extension("dropped").exists());
+    assert_eq!(fs::read_to_string(table_dir("journal_dropped").join("meta")).unwrap(), "2021");
+  }
+
+  #[test]
+  fn committed_but_not_cleaned_up() {
+    let partition = table("journal_cleanup");
+    Journal::begin("journal_cleanup", "2021", &[partition.clone()]).unwrap();
+    append(&partition.join("ts.i64"), &[3; 8]);
+    // Crashed right after removing journal.json
+    fs::remove_file(staging_dir("journal_cleanup").join("2021").join("journal.json")).unwrap();
+
+    recover("journal_cleanup").unwrap();
+    assert_eq!(len(&partition.join("ts.i64")), 24);
+    assert!(!staging_dir("journal_cleanup").join("2021").exists());
+  }
+
+  #[test]
+  fn rolls_back_one_partition() {
+    let x = table("journal_savepoint");
+    let y = table_dir("journal_savepoint").join("2022");
+    fs::create_dir_all(&y).unwrap();
+    fs::write(y.join("ts.i64"), [1u8; 8]).unwrap();
+    let provisional = Savepoint::Provisional;
+    savepoint("journal_savepoint", provisional, "2021", Some(&x), Some((10, 2))).unwrap();
+    // The first savepoint is kept
+    savepoint("journal_savepoint", provisional, "2021", Some(&x), Some((20, 3))).unwrap();
+    append(&x.join("ts.i64"), &[3u8; 8]);
+    append(&x.join("close.f64"), &[3u8; 8]);
+    append(&y.join("ts.i64"), &[3u8; 8]);
+
+    let saved = savepoints("journal_savepoint", provisional).unwrap();
+    assert_eq!(saved.len(), 1);
+    assert_eq!(saved[0].partition, "2021");
+    assert_eq!(saved[0].meta, Some((10, 2)));
+    assert!(truncate(&saved[0], &x).unwrap());
+    assert_eq!(len(&x.join("ts.i64")), 16);
+    assert_eq!(len(&x.join("close.f64")), 16);
+    assert_eq!(len(&y.join("ts.i64")), 16);
+
+    // Rewritten shorter by something else since
+    OpenOptions::new().write(true).open(x.join("ts.i64")).unwrap().set_len(8).unwrap();
+    assert!(!truncate(&saved[0], &x).unwrap());
+    assert_eq!(len(&x.join("close.f64")), 16);
+
+    remove_savepoint("journal_savepoint", &saved[0]).unwrap();
+    assert!(savepoints("journal_savepoint", provisional).unwrap().is_empty());
+  }
+}
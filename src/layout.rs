@@ -0,0 +1,114 @@
+use crate::{
+  batch::{table_spec, ColumnKind},
+  util::table_dir
+};
+use std::{fs, io, process};
+use tracing::error;
+use zdb::table::Table;
+
+// Lists how a table was written
+const LAYOUT: &str = "layout";
+
+// Tables that record their layout. Others were written before layouts were and would refuse
+// their own data.
+const CHECKED: [&str; 3] = ["tickers", "trades", "quotes"];
+
+// Bumped when what a table's columns hold changes without their names doing so
+fn version(table: &str) -> u32 {
+  match table {
+    // Conditions are packed as the ID + 1 so ID 0 isn't lost
+    "trades" | "quotes" => 2,
+    _ => 1
+  }
+}
+
+fn kind_name(kind: &ColumnKind) -> String {
+  match kind {
+    ColumnKind::Timestamp => String::from("timestamp"),
+    ColumnKind::I64 => String::from("i64"),
+    ColumnKind::U64 => String::from("u64"),
+    ColumnKind::U32 => String::from("u32"),
+    ColumnKind::U8 => String::from("u8"),
+    ColumnKind::F64 => String::from("f64"),
+    ColumnKind::Symbol8 => String::from("symbol8"),
+    ColumnKind::Symbol16 => String::from("symbol16"),
+    ColumnKind::Heap(heap) => format!("heap-{}", heap),
+    ColumnKind::Exchange => String::from("exchange"),
+    ColumnKind::Conditions => String::from("conditions")
+  }
+}
+
+// "<name>:<kind>" per column, since a column can keep its name and change what it's stored as.
+// Layouts used to list names only, which is `with_kinds` false.
+fn layout(table: &str, with_kinds: bool) -> String {
+  let spec = table_spec(table).unwrap();
+  let columns = spec
+    .columns
+    .iter()
+    .map(|(name, kind)| {
+      if with_kinds {
+        format!("{}:{}", name, kind_name(kind))
+      } else {
+        name.to_string()
+      }
+    })
+    .collect::<Vec<_>>();
+  match version(table) {
+    1 => columns.join(" "),
+    version => format!("v{} {}", version, columns.join(" "))
+  }
+}
+
+// zdb reads columns by position, so a table written with another column order, type or
+// encoding would be read wrong. Tables record how they were written and refuse to open otherwise.
+pub fn check_layout(table: &str) {
+  if !CHECKED.contains(&table) {
+    return;
+  }
+  let path = table_dir(table).join(LAYOUT);
+  let layout = layout(table, true);
+  let written = match fs::read_to_string(&path) {
+    Ok(written) => written.trim().to_string(),
+    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+      let has_rows = Table::open(table).map(|t| t.partition_meta.len() > 0).unwrap_or(false);
+      if has_rows {
+        String::from("an older one")
+      } else {
+        fs::create_dir_all(table_dir(table))
+          .unwrap_or_else(|e| panic!("Could not create {:?}: {}", table_dir(table), e));
+        fs::write(&path, &layout).unwrap_or_else(|e| panic!("Could not write {:?}: {}", path, e));
+        return;
+      }
+    }
+    Err(e) => panic!("Could not read {:?}: {}", path, e)
+  };
+  // Kinds didn't change while layouts listed names only
+  if written == self::layout(table, false) {
+    fs::write(&path, &layout).unwrap_or_else(|e| panic!("Could not write {:?}: {}", path, e));
+    return;
+  }
+  if written != layout {
+    error!(
+      table,
+      "Table was written with layout {} instead of {}. Move {:?} away and download it again.",
+      written,
+      layout,
+      table_dir(table)
+    );
+    process::exit(1);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn layouts_list_kinds() {
+    let tickers = layout("tickers", true);
+    assert!(tickers.starts_with("ts:timestamp sym:symbol16 name:heap-name "));
+    assert_eq!(layout("tickers", false).split(' ').count(), tickers.split(' ').count());
+    assert!(layout("trades", true).starts_with("v2 ts:timestamp "));
+    assert!(layout("trades", true).contains(" cond:conditions exchange:exchange "));
+  }
+}
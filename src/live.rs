@@ -0,0 +1,486 @@
+use crate::{
+  agg1m::{agg1m_schema, write_agg1m},
+  batch::table_spec,
+  conditions::encode_conditions,
+  journal::Savepoint,
+  quotes::{quotes_schema, write_quotes, QuoteRow},
+  shutdown,
+  sink::{open_sink, Sink, SinkKind},
+  trades::{trades_schema, write_trades, TradeRow},
+  util::{new_york, Bar}
+};
+use chrono::{NaiveDate, NaiveTime, Utc};
+use chrono_tz::America::New_York;
+use serde_json::Value;
+use std::{
+  collections::HashSet,
+  io::{self, ErrorKind},
+  net::TcpStream,
+  process, thread,
+  time::{Duration, Instant}
+};
+use tracing::{error, info, info_span, warn};
+use tungstenite::{stream::MaybeTlsStream, Message, WebSocket};
+
+pub struct LiveArgs<'a> {
+  // Polygon's stocks feed or a local stand-in like ws://127.0.0.1:8765
+  pub url:        &'a str,
+  pub api_key:    String,
+  pub symbols:    Vec<&'a str>,
+  pub flush_secs: u64,
+  pub data_dirs:  Vec<&'a str>,
+  pub sink:       SinkKind,
+  pub sink_out:   Option<&'a str>
+}
+
+// How long to hold rows back so ones that arrive slightly out of order still get written in
+// ts order
+const WATERMARK_LAG_NS: i64 = 2_000_000_000;
+
+fn get_i64(v: &Value, key: &str) -> i64 { v[key].as_i64().unwrap_or(0) }
+
+fn get_f64(v: &Value, key: &str) -> f64 { v[key].as_f64().unwrap_or(0.0) }
+
+// Trades send an array of condition IDs and quotes a single one
+fn get_ids(v: &Value, key: &str) -> u32 {
+  let ids = match &v[key] {
+    Value::Array(ids) => ids.iter().filter_map(|id| id.as_u64()).map(|id| id as u8).collect(),
+    Value::Number(id) => id.as_u64().map(|id| vec![id as u8]).unwrap_or_default(),
+    _ => Vec::new()
+  };
+
+  encode_conditions(&ids)
+}
+
+// Feed timestamps are in milliseconds
+fn get_ts(v: &Value, key: &str) -> i64 { get_i64(v, key) * 1_000_000 }
+
+#[derive(Default)]
+struct Buffers {
+  trades: Vec<TradeRow>,
+  quotes: Vec<QuoteRow>,
+  bars:   Vec<Bar>
+}
+
+impl Buffers {
+  // Parses one feed message, which is an array of events
+  fn extend(&mut self, text: &str) {
+    let events: Vec<Value> = match serde_json::from_str(text) {
+      Ok(events) => events,
+      Err(e) => {
+        warn!(message = text, error = %e, "Could not parse message");
+        return;
+      }
+    };
+    for e in events.iter() {
+      let symbol = e["sym"].as_str().unwrap_or("").to_string();
+      match e["ev"].as_str() {
+        Some("T") => self.trades.push(TradeRow {
+          ts: get_ts(e, "t"),
+          ts_participant: 0,
+          id: e["i"].as_str().and_then(|id| id.parse().ok()).unwrap_or(0),
+          seq_id: get_i64(e, "q") as u64,
+          symbol,
+          size: get_i64(e, "s") as u32,
+          price: get_f64(e, "p"),
+          conditions: get_ids(e, "c"),
+          error: 0,
+          exchange: get_i64(e, "x") as u8,
+          tape: get_i64(e, "z") as u8
+        }),
+        Some("Q") => self.quotes.push(QuoteRow {
+          ts: get_ts(e, "t"),
+          ts_participant: 0,
+          seq_id: get_i64(e, "q") as u64,
+          symbol,
+          bid_price: get_f64(e, "bp"),
+          bid_size: get_i64(e, "bs") as u32,
+          bid_exchange: get_i64(e, "bx") as u8,
+          ask_price: get_f64(e, "ap"),
+          ask_size: get_i64(e, "as") as u32,
+          ask_exchange: get_i64(e, "ax") as u8,
+          conditions: get_ids(e, "c"),
+          indicators: get_ids(e, "i"),
+          tape: get_i64(e, "z") as u8
+        }),
+        Some("AM") => self.bars.push(Bar {
+          ts: get_ts(e, "s"),
+          symbol,
+          open: get_f64(e, "o"),
+          high: get_f64(e, "h"),
+          low: get_f64(e, "l"),
+          close: get_f64(e, "c"),
+          volume: get_i64(e, "v") as u64
+        }),
+        Some("status") => info!("{}", e["message"].as_str().unwrap_or("")),
+        _ => {}
+      }
+    }
+  }
+}
+
+// Removes and returns rows in [from_ts, to_ts). Rows before from_ts arrived after rows
+// later than them were written and can't be appended in ts order anymore.
+fn take_ready<T, F>(rows: &mut Vec<T>, from_ts: i64, to_ts: i64, label: &str, ts: F) -> Vec<T>
+where
+  F: Fn(&T) -> i64
+{
+  let num_late = rows.iter().filter(|r| ts(r) < from_ts).count();
+  if num_late > 0 {
+    warn!(dataset = label, "Dropping {} late rows", num_late);
+  }
+  let mut ready = Vec::<T>::new();
+  let mut pending = Vec::<T>::new();
+  for r in rows.drain(..) {
+    let ts = ts(&r);
+    if ts >= to_ts {
+      pending.push(r);
+    } else if ts >= from_ts {
+      ready.push(r);
+    }
+  }
+  *rows = pending;
+
+  ready
+}
+
+// Resumes after whatever an earlier session of the same day wrote
+fn resume_ts(sink: &dyn Sink, table: &str, day_start: i64, day_end: i64) -> i64 {
+  // Sessions are shorter than any partition
+  table_spec(table)
+    .unwrap()
+    .partitions(day_start, day_end - 1)
+    .iter()
+    .filter_map(|partition| sink.partition_to_ts(partition))
+    .filter(|ts| *ts >= day_start && *ts < day_end)
+    .max()
+    .map(|ts| ts + 1)
+    .unwrap_or(day_start)
+}
+
+// Lets the next historical run of `table` replace rows in [from_ts, to_ts] by taking a
+// savepoint of each partition they're in before the session first writes to it
+fn savepoint(
+  sink: &mut dyn Sink,
+  saved: &mut HashSet<String>,
+  table: &str,
+  from_ts: i64,
+  to_ts: i64
+) {
+  for partition in table_spec(table).unwrap().partitions(from_ts, to_ts) {
+    if saved.insert(format!("{}/{}", table, partition)) {
+      sink.savepoint(Savepoint::Live, &partition);
+    }
+  }
+}
+
+// A New York market day's trades, quotes and minute bars, which run past midnight UTC
+struct Session {
+  date:      NaiveDate,
+  day_end:   i64,
+  trades:    Box<dyn Sink>,
+  quotes:    Box<dyn Sink>,
+  agg1m:     Box<dyn Sink>,
+  buffers:   Buffers,
+  // Rows before these are already written
+  trades_ts: i64,
+  quotes_ts: i64,
+  agg1m_ts:  i64,
+  // "<table>/<partition>" with a savepoint this session
+  saved:     HashSet<String>
+}
+
+impl Session {
+  fn open(args: &LiveArgs, date: NaiveDate) -> Session {
+    let open = |schema, dataset| open_sink(args.sink, schema, dataset, args.sink_out, &[]);
+    let trades = open(trades_schema(args.data_dirs.clone()), "trades");
+    let quotes = open(quotes_schema(args.data_dirs.clone()), "quotes");
+    let agg1m = open(agg1m_schema(args.data_dirs.clone()), "agg1m");
+
+    let midnight = NaiveTime::from_hms(0, 0, 0);
+    let day_start = new_york(date, midnight).timestamp_nanos();
+    let day_end = new_york(date.succ(), midnight).timestamp_nanos();
+    let trades_ts = resume_ts(&*trades, "trades", day_start, day_end);
+    let quotes_ts = resume_ts(&*quotes, "quotes", day_start, day_end);
+    let agg1m_ts = resume_ts(&*agg1m, "agg1m", day_start, day_end);
+
+    Session {
+      date,
+      day_end,
+      trades,
+      quotes,
+      agg1m,
+      buffers: Buffers::default(),
+      trades_ts,
+      quotes_ts,
+      agg1m_ts,
+      saved: HashSet::new()
+    }
+  }
+
+  // Writes every buffered row older than `to_ts`
+  fn flush(&mut self, to_ts: i64) {
+    let date = self.date;
+    let month = date.format("%Y-%m").to_string();
+    let to_ts = to_ts.min(self.day_end);
+    let saved = &mut self.saved;
+
+    let mut trades = take_ready(&mut self.buffers.trades, self.trades_ts, to_ts, "trades", |t| t.ts);
+    let trades_range = (trades.iter().map(|t| t.ts).min(), trades.iter().map(|t| t.ts).max());
+    if let (Some(from_ts), Some(ts)) = trades_range {
+      savepoint(&mut *self.trades, saved, "trades", from_ts, ts);
+      write_trades(&mut *self.trades, &mut trades, date);
+      self.trades_ts = ts + 1;
+    }
+    let mut quotes = take_ready(&mut self.buffers.quotes, self.quotes_ts, to_ts, "quotes", |q| q.ts);
+    let quotes_range = (quotes.iter().map(|q| q.ts).min(), quotes.iter().map(|q| q.ts).max());
+    if let (Some(from_ts), Some(ts)) = quotes_range {
+      savepoint(&mut *self.quotes, saved, "quotes", from_ts, ts);
+      write_quotes(&mut *self.quotes, &mut quotes, date);
+      self.quotes_ts = ts + 1;
+    }
+    let mut bars = take_ready(&mut self.buffers.bars, self.agg1m_ts, to_ts, "agg1m", |b| b.ts);
+    let bars_range = (bars.iter().map(|b| b.ts).min(), bars.iter().map(|b| b.ts).max());
+    if let (Some(from_ts), Some(ts)) = bars_range {
+      savepoint(&mut *self.agg1m, saved, "agg1m", from_ts, ts);
+      write_agg1m(&mut *self.agg1m, &mut bars, &month);
+      self.agg1m_ts = ts + 1;
+    }
+  }
+
+  fn is_over(&self) -> bool {
+    Utc::now().timestamp_nanos() >= self.day_end || shutdown::requested()
+  }
+
+  fn finish(&mut self) {
+    self.flush(i64::MAX);
+    self.trades.finish();
+    self.quotes.finish();
+    self.agg1m.finish();
+  }
+}
+
+fn set_read_timeout(socket: &WebSocket<MaybeTlsStream<TcpStream>>, timeout: Duration) {
+  let stream = socket.get_ref();
+  let res = if let MaybeTlsStream::Plain(stream) = stream {
+    stream.set_read_timeout(Some(timeout))
+  } else if let MaybeTlsStream::NativeTls(stream) = stream {
+    stream.get_ref().set_read_timeout(Some(timeout))
+  } else {
+    Ok(())
+  };
+  res.expect("Could not set socket read timeout");
+}
+
+fn connect(args: &LiveArgs, api_key: &str) -> tungstenite::Result<WebSocket<MaybeTlsStream<TcpStream>>> {
+  let (mut socket, _resp) = tungstenite::connect(args.url)?;
+  let auth = serde_json::json!({ "action": "auth", "params": api_key });
+  socket.write_message(Message::Text(auth.to_string()))?;
+  let params = ["T", "Q", "AM"]
+    .iter()
+    .flat_map(|ev| args.symbols.iter().map(move |sym| format!("{}.{}", ev, sym)))
+    .collect::<Vec<_>>()
+    .join(",");
+  let subscribe = serde_json::json!({ "action": "subscribe", "params": params });
+  socket.write_message(Message::Text(subscribe.to_string()))?;
+  info!(url = args.url, "Subscribed to {}", params);
+
+  Ok(socket)
+}
+
+// Reads the feed into `session` until `until_ts`, a shutdown or the connection ending. Returns
+// whether the connection ended, which is worth reconnecting after.
+fn read_feed<S>(
+  socket: &mut WebSocket<S>,
+  session: &mut Session,
+  until_ts: i64,
+  flush_every: Duration
+) -> bool
+where
+  S: io::Read + io::Write
+{
+  let mut last_flush = Instant::now();
+  while Utc::now().timestamp_nanos() < until_ts && !shutdown::requested() {
+    match socket.read_message() {
+      Ok(Message::Text(text)) => session.buffers.extend(&text),
+      Ok(Message::Close(_)) => {
+        warn!("Closed by server");
+        return true;
+      }
+      Ok(_) => {}
+      Err(tungstenite::Error::Io(e))
+        if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+      Err(e) => {
+        warn!(error = %e, "Connection lost");
+        return true;
+      }
+    }
+    if last_flush.elapsed() >= flush_every {
+      session.flush(Utc::now().timestamp_nanos() - WATERMARK_LAG_NS);
+      last_flush = Instant::now();
+    }
+  }
+
+  false
+}
+
+// Streams trades, quotes and minute bars into the partitions of today in New York until it ends
+pub fn live(args: LiveArgs) {
+  if args.sink != SinkKind::Zdb && args.sink != SinkKind::Parquet && args.sink_out.is_none() {
+    error!("Streaming trades, quotes and agg1m live requires --sink-out");
+    process::exit(1);
+  }
+  let date = Utc::now().with_timezone(&New_York).date().naive_local();
+  let _span = info_span!("live", %date).entered();
+  let mut session = Session::open(&args, date);
+  let flush_every = Duration::from_secs(args.flush_secs);
+
+  let mut retries = 0;
+  while !session.is_over() {
+    let mut socket = match connect(&args, &args.api_key) {
+      Ok(socket) => socket,
+      Err(e) => {
+        retries += 1;
+        warn!(url = args.url, attempt = retries, error = %e, "Could not connect");
+        if retries == 10 {
+          error!(url = args.url, "Could not connect 10 times");
+          break;
+        }
+        thread::sleep(Duration::from_secs(retries));
+        continue;
+      }
+    };
+    retries = 0;
+    // Wake up to flush even when the feed is quiet
+    set_read_timeout(&socket, Duration::from_secs(1));
+    let day_end = session.day_end;
+    if !read_feed(&mut socket, &mut session, day_end, flush_every) {
+      break;
+    }
+  }
+
+  if shutdown::requested() {
+    info!(%date, "Interrupted, flushing");
+  } else {
+    info!(%date, "Live session over, flushing");
+  }
+  session.finish();
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::DateTime;
+  use serde_json::json;
+  use std::{fs, net::TcpListener};
+
+  fn ms(s: &str) -> i64 { DateTime::parse_from_rfc3339(s).unwrap().timestamp_millis() }
+
+  fn read_ts(path: &std::path::Path) -> Vec<i64> {
+    fs::read_to_string(path)
+      .unwrap()
+      .lines()
+      .map(|l| serde_json::from_str::<Value>(l).unwrap()["ts"].as_i64().unwrap())
+      .collect()
+  }
+
+  fn trade(t: &str, q: u64) -> Value {
+    json!({ "ev": "T", "sym": "AAPL", "i": "1", "x": 4, "p": 120.5, "s": 100, "c": [14, 41],
+            "t": ms(t), "q": q, "z": 3 })
+  }
+
+  // Serves `messages` to one client, after it authenticates and subscribes
+  fn serve(messages: Vec<Value>) -> (String, thread::JoinHandle<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    let server = thread::spawn(move || {
+      let (stream, _addr) = listener.accept().unwrap();
+      let mut socket = tungstenite::accept(stream).unwrap();
+      for _ in 0..2 {
+        socket.read_message().unwrap();
+      }
+      for message in messages.iter() {
+        socket.write_message(Message::Text(message.to_string())).unwrap();
+      }
+      socket.close(None).unwrap();
+      while socket.read_message().is_ok() {}
+    });
+
+    (url, server)
+  }
+
+  // Runs a session of `date` until the server closes the feed
+  fn run(args: &LiveArgs, date: NaiveDate, server: thread::JoinHandle<()>) {
+    let mut session = Session::open(args, date);
+    let mut socket = connect(args, &args.api_key).unwrap();
+    assert!(read_feed(&mut socket, &mut session, i64::MAX, Duration::from_secs(3600)));
+    session.finish();
+    drop(session);
+    server.join().unwrap();
+  }
+
+  #[test]
+  fn writes_the_new_york_day() {
+    let messages = vec![
+      json!([{ "ev": "status", "status": "auth_success", "message": "authenticated" }]),
+      json!([
+        // 22:00 on the 3rd in New York, so before the session
+        trade("2021-03-04T03:00:00Z", 1),
+        trade("2021-03-04T15:00:00Z", 2),
+        json!({ "ev": "Q", "sym": "AAPL", "bx": 4, "bp": 120.4, "bs": 2, "ax": 7, "ap": 120.6,
+                "as": 3, "c": 0, "t": ms("2021-03-04T15:00:00Z"), "q": 3, "z": 3 }),
+        json!({ "ev": "AM", "sym": "AAPL", "v": 1000, "o": 120.0, "c": 120.5, "h": 121.0,
+                "l": 119.5, "s": ms("2021-03-04T15:00:00Z"), "e": ms("2021-03-04T15:01:00Z") })
+      ]),
+      // 19:30 on the 4th in New York is the 5th in UTC, and 01:00 on the 5th is the next day
+      json!([trade("2021-03-05T00:30:00Z", 4), trade("2021-03-05T06:00:00Z", 5)]),
+    ];
+    let (url, server) = serve(messages);
+    let dir = std::env::temp_dir().join(format!("polyzdb-live-{}", process::id()));
+    fs::remove_dir_all(&dir).ok();
+    let args = LiveArgs {
+      url:        &url,
+      api_key:    "key".to_string(),
+      symbols:    vec!["AAPL"],
+      flush_secs: 3600,
+      data_dirs:  Vec::new(),
+      sink:       SinkKind::Ndjson,
+      sink_out:   dir.to_str()
+    };
+    run(&args, NaiveDate::from_ymd(2021, 3, 4), server);
+
+    let ns = |s: &str| ms(s) * 1_000_000;
+    let trades = read_ts(&dir.join("trades.ndjson"));
+    assert_eq!(trades, vec![ns("2021-03-04T15:00:00Z"), ns("2021-03-05T00:30:00Z")]);
+    assert_eq!(read_ts(&dir.join("quotes.ndjson")), vec![ns("2021-03-04T15:00:00Z")]);
+    assert_eq!(read_ts(&dir.join("agg1m.ndjson")), vec![ns("2021-03-04T15:00:00Z")]);
+    fs::remove_dir_all(&dir).ok();
+  }
+
+  #[test]
+  fn zdb_writes_past_midnight_utc() {
+    crate::util::test_data_root();
+    let messages = vec![
+      json!([{ "ev": "status", "status": "auth_success", "message": "authenticated" }]),
+      json!([trade("2021-03-04T15:00:00Z", 1)]),
+      // 19:30 and 20:00 on the 4th in New York, which go in the 5th's partition
+      json!([trade("2021-03-05T00:30:00Z", 2), trade("2021-03-05T01:00:00Z", 3)]),
+    ];
+    let (url, server) = serve(messages);
+    let args = LiveArgs {
+      url:        &url,
+      api_key:    "key".to_string(),
+      symbols:    vec!["AAPL"],
+      flush_secs: 3600,
+      data_dirs:  Vec::new(),
+      sink:       SinkKind::Zdb,
+      sink_out:   None
+    };
+    run(&args, NaiveDate::from_ymd(2021, 3, 4), server);
+
+    let trades = zdb::table::Table::open("trades").unwrap();
+    let row_count = |partition| trades.partition_meta.get(partition).map(|meta| meta.row_count);
+    assert_eq!(row_count("2021-03-04"), Some(1));
+    assert_eq!(row_count("2021-03-05"), Some(2));
+  }
+}
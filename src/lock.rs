@@ -0,0 +1,93 @@
+use crate::util::table_dir;
+use chrono::Utc;
+use fs2::FileExt;
+use std::{
+  fs::{self, File, OpenOptions},
+  io::{Read, Seek, SeekFrom, Write},
+  path::{Path, PathBuf},
+  process,
+  sync::atomic::{AtomicBool, Ordering}
+};
+use tracing::{error, info};
+
+static WAIT: AtomicBool = AtomicBool::new(false);
+
+// --wait-lock makes table locks wait for their holder instead of exiting
+pub fn wait_for_tables() { WAIT.store(true, Ordering::SeqCst); }
+
+// An advisory lock on a file that says who holds it. The OS releases it when the holder exits,
+// even by crashing, so there's never a stale lock to clean up.
+pub struct Lock {
+  file: File,
+  path: PathBuf
+}
+
+fn holder(file: &mut File) -> String {
+  let mut res = String::new();
+  file.seek(SeekFrom::Start(0)).and_then(|_| file.read_to_string(&mut res)).ok();
+  match res.trim() {
+    "" => "an unknown process".to_string(),
+    holder => holder.to_string()
+  }
+}
+
+impl Lock {
+  // Exits naming the holder if someone else has it, unless `wait`ing for them to finish
+  pub fn acquire(path: &Path, wait: bool) -> Lock {
+    if let Some(parent) = path.parent() {
+      fs::create_dir_all(parent).ok();
+    }
+    let mut file = OpenOptions::new()
+      .create(true)
+      .read(true)
+      .write(true)
+      .open(path)
+      .unwrap_or_else(|e| {
+        error!(?path, error = %e, "Could not open lockfile");
+        process::exit(1);
+      });
+    if let Err(e) = file.try_lock_exclusive() {
+      if e.raw_os_error() != fs2::lock_contended_error().raw_os_error() {
+        error!(?path, error = %e, "Could not lock");
+        process::exit(1);
+      }
+      let holder = holder(&mut file);
+      if !wait {
+        error!(?path, "Locked by {}. Pass --wait-lock to wait for it.", holder);
+        process::exit(1);
+      }
+      info!(?path, "Waiting for {}", holder);
+      file.lock_exclusive().unwrap_or_else(|e| {
+        error!(?path, error = %e, "Could not lock");
+        process::exit(1);
+      });
+    }
+
+    let host = gethostname::gethostname().to_string_lossy().to_string();
+    let res = file
+      .set_len(0)
+      .and_then(|_| file.seek(SeekFrom::Start(0)))
+      .and_then(|_| {
+        writeln!(file, "pid {} on {} since {}", process::id(), host, Utc::now().to_rfc3339())
+      });
+    if let Err(e) = res {
+      error!(?path, error = %e, "Could not write lockfile");
+    }
+
+    Lock { file, path: path.to_path_buf() }
+  }
+
+  // Held by whatever writes `table` so two processes never interleave writes into a partition
+  pub fn table(table: &str) -> Lock {
+    Lock::acquire(&table_dir(table).join("lock"), WAIT.load(Ordering::SeqCst))
+  }
+}
+
+impl Drop for Lock {
+  fn drop(&mut self) {
+    self.file.set_len(0).ok();
+    if let Err(e) = self.file.unlock() {
+      error!(path = ?self.path, error = %e, "Could not unlock");
+    }
+  }
+}
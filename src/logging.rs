@@ -0,0 +1,93 @@
+use std::{
+  io::{self, Write},
+  sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc
+  }
+};
+use tracing::info;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+  Text,
+  Json
+}
+
+// Whether the last line on stderr is a progress line that the next one can overwrite
+static PROGRESS_DRAWN: AtomicBool = AtomicBool::new(false);
+
+// Stderr, but remembers that a log line went below any progress line
+struct LogWriter;
+
+impl Write for LogWriter {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    PROGRESS_DRAWN.store(false, Ordering::Relaxed);
+    io::stderr().write(buf)
+  }
+
+  fn flush(&mut self) -> io::Result<()> { io::stderr().flush() }
+}
+
+// RUST_LOG overrides `level`, so `RUST_LOG=polyzdb::trades=debug` works
+pub fn init(format: LogFormat, level: &str) {
+  let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(level));
+  let builder = tracing_subscriber::fmt()
+    .with_env_filter(filter)
+    .with_writer(|| LogWriter);
+  match format {
+    LogFormat::Json => builder.json().with_current_span(true).with_span_list(false).init(),
+    LogFormat::Text => builder.with_ansi(atty::is(atty::Stream::Stderr)).init()
+  }
+}
+
+// Redraws "done / total unit [item]" in place when attached to a terminal. Otherwise, like
+// when tee'd to a file, logs every 10% instead.
+pub struct Progress {
+  unit:        &'static str,
+  total:       usize,
+  done:        AtomicUsize,
+  interactive: bool
+}
+
+impl Progress {
+  pub fn new(unit: &'static str, total: usize) -> Arc<Progress> {
+    let res = Progress {
+      unit,
+      total,
+      done: AtomicUsize::new(0),
+      interactive: atty::is(atty::Stream::Stdout) && atty::is(atty::Stream::Stderr)
+    };
+    res.draw("");
+
+    Arc::new(res)
+  }
+
+  fn draw(&self, item: &str) {
+    if !self.interactive {
+      return;
+    }
+    let done = self.done.load(Ordering::Relaxed);
+    let line = format!("{:5} / {:5} {} [{}]", done, self.total, self.unit, item);
+    let stderr = io::stderr();
+    let mut stderr = stderr.lock();
+    let res = if PROGRESS_DRAWN.swap(true, Ordering::Relaxed) {
+      writeln!(stderr, "\x1b[1A\x1b[K{}", line)
+    } else {
+      writeln!(stderr, "{}", line)
+    };
+    res.ok();
+  }
+
+  // Whether every item was counted, which it isn't when workers stopped early
+  pub fn is_done(&self) -> bool { self.done.load(Ordering::Relaxed) == self.total }
+
+  pub fn inc(&self, item: &str) {
+    let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+    if self.interactive {
+      self.draw(item);
+    } else if self.total >= 10 && done % (self.total / 10) == 0 {
+      info!(done, total = self.total, "{} {}% done", self.unit, done * 100 / self.total);
+    }
+  }
+}
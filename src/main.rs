@@ -1,26 +1,253 @@
 mod agg1d;
+mod auth;
+mod batch;
+mod compact;
+mod conditions;
+mod config;
+mod daemon;
+mod download;
+mod exchanges;
+mod export;
+mod import;
+mod journal;
+mod layout;
+mod live;
+mod lock;
+mod logging;
+mod metrics;
+mod placement;
+mod strings;
+mod symbols;
+mod ticker_events;
+mod quotes;
+mod report;
+mod retry;
+mod shutdown;
+mod sink;
+mod status;
 mod tickers;
 mod agg1m;
 mod trades;
 mod util;
-use polygon_io::client::Client;
-use std::{panic, process};
+use std::{panic, path::PathBuf, process, thread};
 use threadpool::ThreadPool;
-use agg1d::download_agg1d;
-use tickers::download_tickers;
-use agg1m::download_agg1m;
-use trades::download_trades;
-use clap::{app_from_crate, crate_authors, crate_description, crate_version, crate_name, Arg};
+use tracing::error;
+use auth::{api_keys, endpoints, Clients};
+use daemon::{daemon, DaemonArgs};
+use download::Downloader;
+use batch::TsFormat;
+use compact::{compact, CompactArgs};
+use export::{export, ExportArgs};
+use import::{import, ImportArgs};
+use live::{live, LiveArgs};
+use retry::override_secs;
+use config::{parse_date, DatasetConfig, FileConfig, DATASETS};
+use logging::LogFormat;
+use placement::{rebalance, Policy, PLACED_TABLES};
+use symbols::check_symbols;
+use ticker_events::print_universe;
+use sink::SinkKind;
+use status::{print_status, STATUS_TABLES};
+use chrono::{Duration, NaiveDate, Utc};
+use clap::{app_from_crate, crate_authors, crate_description, crate_version, crate_name, Arg, ArgMatches, SubCommand};
+
+fn sink_kind(matches: &ArgMatches) -> SinkKind {
+  match matches.value_of("sink").unwrap() {
+    "parquet" => SinkKind::Parquet,
+    "arrow-ipc" => SinkKind::ArrowIpc,
+    "ndjson" => SinkKind::Ndjson,
+    _ => SinkKind::Zdb
+  }
+}
+
+// polyzdb.toml, then flags or their env vars. --retry-* values are "secs" for every dataset or
+// "dataset=secs" for one.
+fn dataset_config(matches: &ArgMatches, file_config: &FileConfig, dataset: &str) -> DatasetConfig {
+  let mut res = file_config.dataset(dataset);
+  if let Some(from) = matches.value_of("from") {
+    res.from = parse_date(from, "--from");
+  }
+  if let Some(to) = matches.value_of("to") {
+    res.to = Some(parse_date(to, "--to"));
+  }
+  if let Some(symbols) = matches.values_of("symbols") {
+    res.symbols = Some(symbols.map(String::from).collect());
+  }
+  let secs = |name: &str| override_secs(matches.values_of(name).into_iter().flatten(), dataset);
+  if let Some(initial) = secs("retry-initial") {
+    res.retry.initial = initial;
+  }
+  if let Some(max_delay) = secs("retry-max-delay") {
+    res.retry.max_delay = max_delay;
+  }
+  if let Some(max_elapsed) = secs("retry-max-elapsed") {
+    res.retry.max_elapsed = max_elapsed;
+  }
+  if let Some(settle) = matches.value_of("settle-delay") {
+    res.settle = Duration::minutes(settle.parse().unwrap_or_else(|_| {
+      error!("Invalid --settle-delay {}", settle);
+      process::exit(1);
+    }));
+  }
+
+  res
+}
 
 fn main() {
   let matches = app_from_crate!()
+    .arg(
+      Arg::with_name("config")
+        .help("TOML file of datasets, date ranges, symbols, data dirs, threads and retries")
+        .long("config")
+        .env("POLYZDB_CONFIG")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("api-key-file")
+        .help("File of Polygon API keys, one per line, to rotate between [default: POLYGON_KEY]")
+        .long("api-key-file")
+        .env("POLYZDB_API_KEY_FILE")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("data-root")
+        .help("Where zdb keeps table metadata and polyzdb its heaps, locks, journals and state [default: data]")
+        .long("data-root")
+        .env("POLYZDB_DATA_ROOT")
+        .takes_value(true)
+    )
     .arg(
       Arg::with_name("data-dir")
-        .help("Adds a directory to save data to to schema of agg1m or trades")
+        .help("Adds a directory to save data to to schema of agg1m or trades [default: data]")
         .long("data-dir")
+        .env("POLYZDB_DATA_DIR")
+        .takes_value(true)
+        .multiple(true)
+        .use_delimiter(true)
+    )
+    .arg(
+      Arg::with_name("placement")
+        .help("Which data dir new agg1m, trades and quotes partitions go in [default: most-free]")
+        .long("placement")
+        .env("POLYZDB_PLACEMENT")
+        .takes_value(true)
+        .possible_values(&["round-robin", "most-free", "by-year"])
+    )
+    .arg(
+      Arg::with_name("pin")
+        .help("Pins a year's partitions to a data dir for --placement by-year, like 2019=/mnt/ssd1")
+        .long("pin")
         .takes_value(true)
         .multiple(true)
-        .default_value("data")
+        .number_of_values(1)
+    )
+    .arg(
+      Arg::with_name("threads")
+        .help("Requests in flight at once [default: 100]")
+        .long("threads")
+        .env("POLYZDB_THREADS")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("from")
+        .help("First day to download in %Y-%m-%d format [default: 2004-01-01]")
+        .long("from")
+        .env("POLYZDB_FROM")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("to")
+        .help("Last day to download in %Y-%m-%d format [default: today]")
+        .long("to")
+        .env("POLYZDB_TO")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("symbols")
+        .help("Only download these symbols of agg1m and trades")
+        .long("symbols")
+        .env("POLYZDB_SYMBOLS")
+        .takes_value(true)
+        .multiple(true)
+        .use_delimiter(true)
+    )
+    .arg(
+      Arg::with_name("sink")
+        .help("Where downloaded rows go. Streams are written in ts order without resuming")
+        .long("sink")
+        .takes_value(true)
+        .possible_values(&["zdb", "parquet", "arrow-ipc", "ndjson"])
+        .default_value("zdb")
+    )
+    .arg(
+      Arg::with_name("sink-out")
+        .help("Directory to write Parquet partitions or a stream per dataset to instead of stdout")
+        .long("sink-out")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("log-level")
+        .help("Minimum level to log. RUST_LOG overrides this per module")
+        .long("log-level")
+        .takes_value(true)
+        .possible_values(&["error", "warn", "info", "debug", "trace"])
+        .default_value("info")
+    )
+    .arg(
+      Arg::with_name("log-format")
+        .help("json writes one object per line with span fields like dataset and partition")
+        .long("log-format")
+        .takes_value(true)
+        .possible_values(&["text", "json"])
+        .default_value("text")
+    )
+    .arg(
+      Arg::with_name("metrics-addr")
+        .help("Serves Prometheus metrics on this address, like 0.0.0.0:9184")
+        .long("metrics-addr")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("report")
+        .help("Where to write the end-of-run summary as JSON [default: <data-root>/reports/<start>.json]")
+        .long("report")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("wait-lock")
+        .help("Waits for another process writing a table to finish instead of exiting")
+        .long("wait-lock")
+    )
+    .arg(
+      Arg::with_name("retry-initial")
+        .help("Seconds to wait after the first failed request. Doubles each retry [default: 1]")
+        .long("retry-initial")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+    )
+    .arg(
+      Arg::with_name("retry-max-delay")
+        .help("Most seconds to wait between retries [default: 60]")
+        .long("retry-max-delay")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+    )
+    .arg(
+      Arg::with_name("retry-max-elapsed")
+        .help("Seconds after which a request gives up [default: 1800 for agg1m and trades, else 300]")
+        .long("retry-max-elapsed")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+    )
+    .arg(
+      Arg::with_name("settle-delay")
+        .help("Minutes after the close before agg1d and agg1m rows are final instead of replaced on the next run [default: 30]")
+        .long("settle-delay")
+        .env("POLYZDB_SETTLE_DELAY")
+        .takes_value(true)
     )
     .arg(
       Arg::with_name("agg1d")
@@ -32,6 +259,16 @@ fn main() {
         .help("Download tickers data using reference tickers vX endpoint")
         .long("tickers")
     )
+    .arg(
+      Arg::with_name("ticker-events")
+        .help("Build ticker_events by diffing consecutive tickers snapshots")
+        .long("ticker-events")
+    )
+    .arg(
+      Arg::with_name("exchanges")
+        .help("Download exchanges data using reference exchanges endpoint")
+        .long("exchanges")
+    )
     .arg(
       Arg::with_name("agg1m")
         .help("Download agg1m data using agg1d data as an index")
@@ -42,41 +279,472 @@ fn main() {
         .help("Download trade data using agg1d data as an index")
         .long("trades")
     )
+    .subcommand(
+      SubCommand::with_name("universe")
+        .about("Prints every ticker listed on a date using the tickers table")
+        .arg(
+          Arg::with_name("date")
+            .help("Date in %Y-%m-%d format")
+            .required(true)
+            .index(1)
+        )
+        .arg(
+          Arg::with_name("type")
+            .help("Only print tickers of this type (e.g. CS)")
+            .long("type")
+            .takes_value(true)
+            .multiple(true)
+        )
+    )
+    .subcommand(
+      SubCommand::with_name("symbols")
+        .about("Maintains the us_equities symbol dictionary shared by every table")
+        .subcommand(
+          SubCommand::with_name("check")
+            .about("Validates every table's symbol indexes against the shared dictionary")
+            .arg(
+              Arg::with_name("rebuild")
+                .help("Writes a dictionary of only the referenced symbols to data/us_equities.rebuilt")
+                .long("rebuild")
+            )
+        )
+    )
+    .subcommand(
+      SubCommand::with_name("export")
+        .about("Exports a table to files outside of zdb")
+        .arg(
+          Arg::with_name("table")
+            .help("Table to export")
+            .long("table")
+            .takes_value(true)
+            .required(true)
+        )
+        .arg(
+          Arg::with_name("from")
+            .help("First day to export in %Y-%m-%d format")
+            .long("from")
+            .takes_value(true)
+            .default_value("2004-01-01")
+        )
+        .arg(
+          Arg::with_name("to")
+            .help("Last day to export in %Y-%m-%d format")
+            .long("to")
+            .takes_value(true)
+            .default_value("2100-01-01")
+        )
+        .arg(
+          Arg::with_name("symbols")
+            .help("Only export these symbols")
+            .long("symbols")
+            .takes_value(true)
+            .multiple(true)
+            .use_delimiter(true)
+        )
+        .arg(
+          Arg::with_name("columns")
+            .help("Only export these columns")
+            .long("columns")
+            .takes_value(true)
+            .multiple(true)
+            .use_delimiter(true)
+        )
+        .arg(
+          Arg::with_name("format")
+            .help("Output format")
+            .long("format")
+            .takes_value(true)
+            .possible_values(&["parquet", "csv", "ndjson"])
+            .default_value("parquet")
+        )
+        .arg(
+          Arg::with_name("ts-format")
+            .help("How csv and ndjson timestamps are written")
+            .long("ts-format")
+            .takes_value(true)
+            .possible_values(&["iso", "nanos"])
+            .default_value("iso")
+        )
+        .arg(
+          Arg::with_name("out")
+            .help("Directory to write one file per partition to. csv and ndjson default to stdout")
+            .long("out")
+            .takes_value(true)
+        )
+    )
+    .subcommand(
+      SubCommand::with_name("import")
+        .about("Imports Polygon flat files (CSV.gz) instead of using the REST API")
+        .arg(
+          Arg::with_name("dir")
+            .help("Directory with a subdirectory per dataset like trades_v1")
+            .long("dir")
+            .takes_value(true)
+            .required(true)
+        )
+        .arg(
+          Arg::with_name("dataset")
+            .help("Datasets to import")
+            .long("dataset")
+            .takes_value(true)
+            .multiple(true)
+            .use_delimiter(true)
+            .possible_values(&["agg1d", "agg1m", "trades", "quotes"])
+            .default_value("agg1d,agg1m,trades")
+        )
+        .arg(
+          Arg::with_name("from")
+            .help("First day to import in %Y-%m-%d format")
+            .long("from")
+            .takes_value(true)
+            .default_value("2004-01-01")
+        )
+        .arg(
+          Arg::with_name("to")
+            .help("Last day to import in %Y-%m-%d format")
+            .long("to")
+            .takes_value(true)
+            .default_value("2100-01-01")
+        )
+    )
+    .subcommand(
+      SubCommand::with_name("live")
+        .about(
+          "Streams trades, quotes and minute bars from the WebSocket feed until the New York day \
+           ends. The next historical download or import replaces what it wrote."
+        )
+        .arg(
+          Arg::with_name("url")
+            .help("WebSocket URL, which can point at a local stand-in for testing")
+            .long("url")
+            .takes_value(true)
+            .default_value("wss://socket.polygon.io/stocks")
+        )
+        .arg(
+          Arg::with_name("symbols")
+            .help("Symbols to subscribe to")
+            .long("symbols")
+            .takes_value(true)
+            .multiple(true)
+            .use_delimiter(true)
+            .default_value("*")
+        )
+        .arg(
+          Arg::with_name("flush-secs")
+            .help("How often to write buffered rows")
+            .long("flush-secs")
+            .takes_value(true)
+            .default_value("60")
+        )
+    )
+    .subcommand(
+      SubCommand::with_name("status")
+        .about("Prints each table's partitions, size per data dir and missing market days")
+        .arg(
+          Arg::with_name("table")
+            .help("Tables to show [default: all]")
+            .long("table")
+            .takes_value(true)
+            .multiple(true)
+            .use_delimiter(true)
+            .possible_values(&STATUS_TABLES)
+        )
+    )
+    .subcommand(
+      SubCommand::with_name("rebalance")
+        .about("Moves partitions between data dirs to match --placement")
+        .arg(
+          Arg::with_name("table")
+            .help("Tables to rebalance [default: all]")
+            .long("table")
+            .takes_value(true)
+            .multiple(true)
+            .use_delimiter(true)
+            .possible_values(&PLACED_TABLES)
+        )
+        .arg(
+          Arg::with_name("dry-run")
+            .help("Prints the moves without making them")
+            .long("dry-run")
+        )
+    )
+    .subcommand(
+      SubCommand::with_name("compact")
+        .about("Compresses old partitions, which stay readable by export and status")
+        .arg(
+          Arg::with_name("table")
+            .help("Tables to compact")
+            .long("table")
+            .takes_value(true)
+            .multiple(true)
+            .use_delimiter(true)
+            .possible_values(&PLACED_TABLES)
+            .default_value("trades")
+        )
+        .arg(
+          Arg::with_name("older-than")
+            .help("Compacts partitions whose last row is older than this many days")
+            .long("older-than")
+            .takes_value(true)
+            .default_value("30")
+        )
+        .arg(
+          Arg::with_name("archive-dir")
+            .help("Moves compacted partitions to this data dir, like one on slower disks")
+            .long("archive-dir")
+            .takes_value(true)
+        )
+    )
+    .subcommand(
+      SubCommand::with_name("daemon")
+        .about("Keeps every dataset up to date, downloading each once its market day is over")
+        .arg(
+          Arg::with_name("status-addr")
+            .help("Address to serve the last successful update per dataset on, like 127.0.0.1:9101")
+            .long("status-addr")
+            .takes_value(true)
+        )
+        .arg(
+          Arg::with_name("close-delay")
+            .help("Minutes after the 16:00 ET close to download agg1d, tickers and exchanges")
+            .long("close-delay")
+            .takes_value(true)
+            .default_value("30")
+        )
+        .arg(
+          Arg::with_name("final-delay")
+            .help(
+              "Minutes after 20:00 ET for Polygon to finalize agg1m and trades. Early closes wait \
+               the same, since extended hours' end isn't in the calendar."
+            )
+            .long("final-delay")
+            .takes_value(true)
+            .default_value("240")
+        )
+    )
     .get_matches();
-  
-  // Holds API key and ratelimit
-  let mut client = Client::new();
+
+  let log_format = match matches.value_of("log-format").unwrap() {
+    "json" => LogFormat::Json,
+    _ => LogFormat::Text
+  };
+  logging::init(log_format, matches.value_of("log-level").unwrap());
+
+  let file_config = FileConfig::load(matches.value_of("config"));
+  util::set_data_root(
+    matches.value_of("data-root").or(file_config.data_root.as_deref()).unwrap_or("data")
+  );
+
+  if matches.is_present("wait-lock") {
+    lock::wait_for_tables();
+  }
+  let data_dirs = match matches.values_of("data-dir") {
+    Some(dirs) => dirs.map(String::from).collect::<Vec<_>>(),
+    None if !file_config.data_dirs.is_empty() => file_config.data_dirs.clone(),
+    None => vec!["data".to_string()]
+  };
+  let data_dirs = data_dirs.iter().map(String::as_str).collect::<Vec<_>>();
+  let api_key_file = matches.value_of("api-key-file").or(file_config.api_key_file.as_deref());
+  let mut pins = file_config.pin.clone();
+  for pin in matches.values_of("pin").into_iter().flatten() {
+    match pin.split_once('=') {
+      Some((year, dir)) => pins.insert(year.to_string(), dir.to_string()),
+      None => {
+        error!("Invalid --pin {}. Use year=dir.", pin);
+        process::exit(1);
+      }
+    };
+  }
+  let policy = Policy::parse(
+    matches.value_of("placement").or(file_config.placement.as_deref()).unwrap_or("most-free"),
+    pins
+  );
+  placement::init(policy.clone(), &data_dirs);
+
+  if let ("universe", Some(sub)) = matches.subcommand() {
+    let date = sub.value_of("date").unwrap();
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d").expect("Invalid date");
+    let types = sub.values_of("type").map(|v| v.collect::<Vec<&str>>()).unwrap_or(Vec::new());
+    print_universe(date, types);
+    return;
+  }
+  if let ("export", Some(sub)) = matches.subcommand() {
+    let date = |name: &str| {
+      let date = sub.value_of(name).unwrap();
+      NaiveDate::parse_from_str(date, "%Y-%m-%d").expect("Invalid date")
+    };
+    let values = |name: &str| sub.values_of(name).map(|v| v.collect::<Vec<&str>>()).unwrap_or(Vec::new());
+    export(ExportArgs {
+      table:     sub.value_of("table").unwrap(),
+      from:      date("from"),
+      to:        date("to"),
+      symbols:   values("symbols"),
+      columns:   values("columns"),
+      format:    sub.value_of("format").unwrap(),
+      ts_format: match sub.value_of("ts-format").unwrap() {
+        "nanos" => TsFormat::Nanos,
+        _ => TsFormat::Iso
+      },
+      out:       sub.value_of("out").map(PathBuf::from)
+    });
+    return;
+  }
+  if let ("import", Some(sub)) = matches.subcommand() {
+    let date = |name: &str| {
+      let date = sub.value_of(name).unwrap();
+      NaiveDate::parse_from_str(date, "%Y-%m-%d").expect("Invalid date")
+    };
+    import(ImportArgs {
+      dir:       PathBuf::from(sub.value_of("dir").unwrap()),
+      datasets:  sub.values_of("dataset").unwrap().collect(),
+      from:      date("from"),
+      to:        date("to"),
+      data_dirs: data_dirs.clone()
+    });
+    return;
+  }
+  if let ("live", Some(sub)) = matches.subcommand() {
+    shutdown::install();
+    live(LiveArgs {
+      url:        sub.value_of("url").unwrap(),
+      // One connection needs one key
+      api_key:    api_keys(api_key_file).remove(0),
+      symbols:    sub.values_of("symbols").unwrap().collect(),
+      flush_secs: sub.value_of("flush-secs").unwrap().parse().expect("Invalid --flush-secs"),
+      data_dirs:  data_dirs.clone(),
+      sink:       sink_kind(&matches),
+      sink_out:   matches.value_of("sink-out")
+    });
+    return;
+  }
+  if let ("status", Some(sub)) = matches.subcommand() {
+    print_status(sub.values_of("table").map(|v| v.collect()).unwrap_or(STATUS_TABLES.to_vec()));
+    return;
+  }
+  if let ("rebalance", Some(sub)) = matches.subcommand() {
+    let tables = sub.values_of("table").map(|v| v.collect()).unwrap_or(PLACED_TABLES.to_vec());
+    rebalance(tables, policy, data_dirs.clone(), sub.is_present("dry-run"));
+    return;
+  }
+  if let ("compact", Some(sub)) = matches.subcommand() {
+    compact(CompactArgs {
+      tables:      sub.values_of("table").unwrap().collect(),
+      older_than:  sub.value_of("older-than").unwrap().parse().expect("Invalid --older-than"),
+      archive_dir: sub.value_of("archive-dir"),
+      data_dirs:   data_dirs.clone()
+    });
+    return;
+  }
+  if let ("symbols", Some(sub)) = matches.subcommand() {
+    if let ("check", Some(check)) = sub.subcommand() {
+      if !check_symbols(check.is_present("rebuild")) {
+        process::exit(1);
+      }
+    }
+    return;
+  }
+
+  let report_path = match matches.value_of("report") {
+    Some(path) => PathBuf::from(path),
+    None => util::data_root()
+      .join("reports")
+      .join(format!("{}.json", Utc::now().format("%Y%m%dT%H%M%SZ")))
+  };
+  report::init(report_path);
 
   // Enough threads to end up blocking on io
-  let thread_pool = ThreadPool::new(100);
+  let threads = match matches.value_of("threads") {
+    Some(threads) => threads.parse().expect("Invalid --threads"),
+    None => file_config.threads.unwrap_or(100)
+  };
+  let thread_pool = ThreadPool::new(threads);
 
-  // Panic if thread panics
+  if let Some(addr) = matches.value_of("metrics-addr") {
+    metrics::serve(addr, &thread_pool, data_dirs.iter().map(|d| d.to_string()).collect());
+  }
+
+  // A worker panicking leaves its partition incomplete, so stop like on a signal. The main
+  // thread is the one writing, so it can't.
   let orig_hook = panic::take_hook();
   panic::set_hook(Box::new(move |panic_info| {
     orig_hook(panic_info);
-    process::exit(1);
+    if thread::current().name() == Some("main") {
+      process::exit(1);
+    }
+    shutdown::fail();
   }));
+  shutdown::install();
 
-  let agg1d = matches.is_present("agg1d");
-  let tickers = matches.is_present("tickers");
-  let agg1m = matches.is_present("agg1m");
-  let trades = matches.is_present("trades");
-  let download_all = !agg1d && !tickers && !agg1m && !trades;
+  if let ("daemon", Some(sub)) = matches.subcommand() {
+    if sink_kind(&matches) != SinkKind::Zdb {
+      error!("The daemon only writes to zdb");
+      process::exit(1);
+    }
+    let minutes = |name: &str| {
+      Duration::minutes(sub.value_of(name).unwrap().parse().unwrap_or_else(|_| {
+        error!("Invalid --{}", name);
+        process::exit(1);
+      }))
+    };
+    let needed = DATASETS.iter().flat_map(|d| endpoints(d).iter().copied()).collect::<Vec<_>>();
+    let clients = Clients::validate(&api_keys(api_key_file), &needed);
+    let downloader = Downloader { thread_pool, clients, sink: SinkKind::Zdb, sink_out: None, data_dirs };
+    let args = DaemonArgs {
+      status_addr: sub.value_of("status-addr"),
+      close_delay: minutes("close-delay"),
+      final_delay: minutes("final-delay")
+    };
+    daemon(&downloader, args, |dataset| dataset_config(&matches, &file_config, dataset));
+    return;
+  }
 
-  if download_all || agg1d {
-    download_agg1d(&thread_pool, &mut client);
+  // Flags, then an interrupted run, then the config, then everything
+  let mut datasets = DATASETS
+    .iter()
+    .filter(|d| matches.is_present(**d))
+    .map(|d| d.to_string())
+    .collect::<Vec<_>>();
+  if datasets.is_empty() {
+    datasets = shutdown::read_resume().unwrap_or_else(|| {
+      if !file_config.download.is_empty() {
+        return file_config.download.clone();
+      }
+      ["agg1d", "tickers", "exchanges", "agg1m", "trades"].iter().map(|d| d.to_string()).collect()
+    });
+  }
+  let sink = sink_kind(&matches);
+  // Derived from the tickers table, so rebuilt whenever it changes
+  if let Some(i) = datasets.iter().position(|d| d == "tickers") {
+    if !datasets.iter().any(|d| d == "ticker-events") {
+      datasets.insert(i + 1, "ticker-events".to_string());
+    }
   }
-  if download_all || tickers {
-    download_tickers(&thread_pool, &mut client);
+  if sink != SinkKind::Zdb {
+    datasets.retain(|d| d != "ticker-events");
   }
 
-  let data_dirs = matches.values_of("data-dir").unwrap().into_iter().collect::<Vec<&str>>();
-  if download_all || agg1m {
-    eprintln!("Downloading agg1m");
-    download_agg1m(&thread_pool, &mut client, data_dirs.clone());
+  let sink_out = matches.value_of("sink-out");
+  if sink == SinkKind::Parquet && sink_out.is_none() {
+    error!("The parquet sink requires --sink-out");
+    process::exit(1);
   }
-  if download_all || trades {
-    eprintln!("Downloading trade data");
-    download_trades(&thread_pool, &mut client, data_dirs.clone());
+  if sink != SinkKind::Zdb && sink_out.is_none() && datasets.len() > 1 {
+    error!("Streaming to stdout requires exactly one dataset. Use --sink-out for more.");
+    process::exit(1);
   }
+
+  let names = datasets.iter().map(String::as_str).collect::<Vec<_>>();
+  // Hold API keys and ratelimits. Checked up front so a bad key is one error instead of one
+  // per request.
+  let needed = names.iter().flat_map(|d| endpoints(d).iter().copied()).collect::<Vec<_>>();
+  let clients = Clients::validate(&api_keys(api_key_file), &needed);
+  let downloader = Downloader { thread_pool, clients, sink, sink_out, data_dirs };
+  for (i, dataset) in names.iter().enumerate() {
+    downloader.download(dataset, &dataset_config(&matches, &file_config, dataset));
+    // Whatever was running may be incomplete, so it's redone on resume
+    shutdown::checkpoint(&names[i..]);
+  }
+  shutdown::finish(&names);
+
+  report::finish();
 }
@@ -0,0 +1,149 @@
+use crate::report;
+use lazy_static::lazy_static;
+use prometheus::{
+  register_int_counter_vec, register_int_gauge, register_int_gauge_vec, Encoder, IntCounterVec,
+  IntGauge, IntGaugeVec, TextEncoder
+};
+use std::{
+  collections::HashMap,
+  fs,
+  io::{self, Read, Write},
+  net::{TcpListener, TcpStream},
+  path::Path,
+  sync::Mutex,
+  thread,
+  time::Duration
+};
+use threadpool::ThreadPool;
+use tracing::{error, info, warn};
+
+lazy_static! {
+  static ref REQUESTS: IntCounterVec = register_int_counter_vec!(
+    "polyzdb_requests_total",
+    "Polygon API requests issued",
+    &["dataset"]
+  )
+  .unwrap();
+  static ref RETRIES: IntCounterVec = register_int_counter_vec!(
+    "polyzdb_retries_total",
+    "Failed Polygon API requests that were retried",
+    &["dataset", "kind"]
+  )
+  .unwrap();
+  static ref ROWS_WRITTEN: IntCounterVec = register_int_counter_vec!(
+    "polyzdb_rows_written_total",
+    "Rows appended to a sink",
+    &["dataset"]
+  )
+  .unwrap();
+  static ref DATA_DIR_BYTES: IntGaugeVec = register_int_gauge_vec!(
+    "polyzdb_data_dir_bytes",
+    "Bytes on disk under each --data-dir",
+    &["dir"]
+  )
+  .unwrap();
+  static ref CURRENT_PARTITION: IntGaugeVec = register_int_gauge_vec!(
+    "polyzdb_current_partition",
+    "1 for the partition each dataset is downloading",
+    &["dataset", "partition"]
+  )
+  .unwrap();
+  static ref QUEUED: IntGauge =
+    register_int_gauge!("polyzdb_thread_pool_queued", "Jobs waiting in the thread pool").unwrap();
+  static ref ACTIVE: IntGauge =
+    register_int_gauge!("polyzdb_thread_pool_active", "Jobs running in the thread pool").unwrap();
+  static ref PARTITIONS: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
+
+// These also feed the end-of-run report
+
+pub fn request(dataset: &str) {
+  REQUESTS.with_label_values(&[dataset]).inc();
+  report::api_call(dataset);
+}
+
+pub fn retry(dataset: &str, kind: &str) {
+  RETRIES.with_label_values(&[dataset, kind]).inc();
+  report::retry(dataset);
+}
+
+pub fn rows_written(dataset: &str, num_rows: usize) {
+  ROWS_WRITTEN.with_label_values(&[dataset]).inc_by(num_rows as u64);
+  report::rows_written(dataset, num_rows);
+}
+
+pub fn set_partition(dataset: &str, partition: &str) {
+  report::partition(dataset, partition);
+  let mut partitions = PARTITIONS.lock().unwrap();
+  if let Some(prev) = partitions.insert(dataset.to_string(), partition.to_string()) {
+    CURRENT_PARTITION.remove_label_values(&[dataset, prev.as_str()]).ok();
+  }
+  CURRENT_PARTITION.with_label_values(&[dataset, partition]).set(1);
+}
+
+pub fn dir_size(path: &Path) -> u64 {
+  let entries = match fs::read_dir(path) {
+    Ok(entries) => entries,
+    Err(_) => return 0
+  };
+  entries
+    .filter_map(|e| e.ok())
+    .map(|e| match e.metadata() {
+      Ok(meta) if meta.is_dir() => dir_size(&e.path()),
+      Ok(meta) => meta.len(),
+      Err(_) => 0
+    })
+    .sum()
+}
+
+fn respond(mut stream: TcpStream) -> io::Result<()> {
+  // Every path gets the metrics, so only read enough of the request to be polite
+  let mut buf = [0u8; 1024];
+  let _request_len = stream.read(&mut buf)?;
+  let mut body = Vec::<u8>::new();
+  let encoder = TextEncoder::new();
+  encoder
+    .encode(&prometheus::gather(), &mut body)
+    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+  write!(
+    stream,
+    "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+    encoder.format_type(),
+    body.len()
+  )?;
+  stream.write_all(&body)
+}
+
+// Serves /metrics on `addr` and samples gauges in the background for the rest of the run
+pub fn serve(addr: &str, thread_pool: &ThreadPool, data_dirs: Vec<String>) {
+  let listener = match TcpListener::bind(addr) {
+    Ok(listener) => listener,
+    Err(e) => {
+      error!(addr, error = %e, "Could not bind metrics endpoint");
+      std::process::exit(1);
+    }
+  };
+  info!(addr, "Serving metrics");
+  thread::spawn(move || {
+    for stream in listener.incoming().filter_map(|s| s.ok()) {
+      if let Err(e) = respond(stream) {
+        warn!(error = %e, "Could not serve metrics");
+      }
+    }
+  });
+
+  let thread_pool = thread_pool.clone();
+  thread::spawn(move || loop {
+    QUEUED.set(thread_pool.queued_count() as i64);
+    ACTIVE.set(thread_pool.active_count() as i64);
+    thread::sleep(Duration::from_secs(1));
+  });
+
+  // Walking a data dir full of trades takes a while, so do it less often
+  thread::spawn(move || loop {
+    for dir in data_dirs.iter() {
+      DATA_DIR_BYTES.with_label_values(&[dir.as_str()]).set(dir_size(Path::new(dir)) as i64);
+    }
+    thread::sleep(Duration::from_secs(60));
+  });
+}
@@ -0,0 +1,263 @@
+use crate::{
+  agg1m::agg1m_schema,
+  journal::{self, Journal},
+  lock::Lock,
+  metrics::dir_size,
+  quotes::quotes_schema,
+  trades::trades_schema,
+  util::table_dir
+};
+use fs2::available_space;
+use lazy_static::lazy_static;
+use std::{
+  collections::HashMap,
+  fs::{self, File},
+  io,
+  path::{Path, PathBuf},
+  process,
+  sync::Mutex
+};
+use tracing::{error, info, warn};
+use zdb::{schema::Schema, table::Table};
+
+// Tables whose partitions are spread across --data-dir
+pub const PLACED_TABLES: [&str; 3] = ["agg1m", "trades", "quotes"];
+
+// Free space to leave on top of a partition's estimated size
+const HEADROOM: f64 = 1.5;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Policy {
+  RoundRobin,
+  MostFree,
+  // Years not pinned to a dir fall back to most-free
+  ByYear(HashMap<String, String>)
+}
+
+impl Policy {
+  pub fn parse(name: &str, pins: HashMap<String, String>) -> Policy {
+    match name {
+      "round-robin" => Policy::RoundRobin,
+      "most-free" => Policy::MostFree,
+      "by-year" => Policy::ByYear(pins),
+      _ => {
+        error!(policy = name, "Unknown placement policy");
+        process::exit(1);
+      }
+    }
+  }
+}
+
+struct Placement {
+  policy:    Policy,
+  data_dirs: Vec<String>,
+  next:      usize
+}
+
+lazy_static! {
+  static ref PLACEMENT: Mutex<Placement> = Mutex::new(Placement {
+    policy:    Policy::MostFree,
+    data_dirs: vec!["data".to_string()],
+    next:      0
+  });
+}
+
+pub fn init(policy: Policy, data_dirs: &[&str]) {
+  if let Policy::ByYear(pins) = &policy {
+    for (year, dir) in pins.iter() {
+      if !data_dirs.contains(&dir.as_str()) {
+        error!(year = %year, dir = %dir, ?data_dirs, "Pinned to a dir that isn't a --data-dir");
+        process::exit(1);
+      }
+    }
+  }
+  let mut placement = PLACEMENT.lock().unwrap();
+  placement.policy = policy;
+  placement.data_dirs = data_dirs.iter().map(|d| d.to_string()).collect();
+}
+
+fn free_bytes(dir: &str) -> u64 {
+  fs::create_dir_all(dir).ok();
+  available_space(dir).unwrap_or_else(|e| {
+    error!(dir, error = %e, "Could not get free space");
+    process::exit(1);
+  })
+}
+
+// The data dir a partition's columns are under, which is <data dir>/<table>/<partition>
+pub fn data_dir(partition_dir: &str) -> String {
+  Path::new(partition_dir)
+    .parent()
+    .and_then(|table_dir| table_dir.parent())
+    .map(|data_dir| data_dir.to_string_lossy().to_string())
+    .unwrap_or_default()
+}
+
+// The average size of the latest few partitions, since tables grow over time
+pub fn estimate_bytes(table: &Table) -> u64 {
+  let mut names = table.partition_meta.keys().collect::<Vec<_>>();
+  names.sort();
+  let latest = names.iter().rev().take(5).collect::<Vec<_>>();
+  if latest.is_empty() {
+    return 0;
+  }
+  let total = latest
+    .iter()
+    .map(|name| dir_size(Path::new(&table.partition_meta[name.as_str()].dir)))
+    .sum::<u64>();
+
+  total / latest.len() as u64
+}
+
+fn has_room(dir: &str, needed: u64) -> bool { free_bytes(dir) as f64 >= needed as f64 * HEADROOM }
+
+// Exits before writing `needed` bytes to `dir` would fill it partway through a partition
+pub fn preflight(table: &str, partition: &str, dir: &str, needed: u64) {
+  let free = free_bytes(dir);
+  if (free as f64) < needed as f64 * HEADROOM {
+    error!(table, partition, dir, free, needed, "Not enough free space for partition");
+    process::exit(1);
+  }
+}
+
+// Picks the data dir for a new partition of `table` and checks it has room for `needed` bytes
+pub fn place(table: &str, partition: &str, needed: u64) -> String {
+  let mut placement = PLACEMENT.lock().unwrap();
+  let dirs = placement.data_dirs.clone();
+  let res = match placement.policy.clone() {
+    Policy::ByYear(pins) if pins.contains_key(&partition[..4]) => pins[&partition[..4]].clone(),
+    Policy::RoundRobin => {
+      // Skips dirs that are full rather than failing while another has room
+      let start = placement.next;
+      let i = (0..dirs.len())
+        .map(|i| (start + i) % dirs.len())
+        .find(|i| has_room(&dirs[*i], needed))
+        .unwrap_or(start % dirs.len());
+      placement.next = i + 1;
+      dirs[i].clone()
+    }
+    _ => dirs.iter().max_by_key(|d| free_bytes(d)).unwrap().clone()
+  };
+  preflight(table, partition, &res, needed);
+
+  res
+}
+
+fn sync_dir(dir: &Path) -> io::Result<()> { File::open(dir)?.sync_all() }
+
+// Copies then renames so a crash never leaves a half-copied partition where the metadata
+// could point
+fn copy_partition(from: &Path, to: &Path) -> io::Result<()> {
+  let tmp = to.with_extension("moving");
+  if tmp.exists() {
+    fs::remove_dir_all(&tmp)?;
+  }
+  fs::create_dir_all(&tmp)?;
+  for entry in fs::read_dir(from)? {
+    let path = entry?.path();
+    let dest = tmp.join(path.file_name().unwrap());
+    fs::copy(&path, &dest)?;
+    File::open(&dest)?.sync_all()?;
+  }
+  sync_dir(&tmp)?;
+  fs::rename(&tmp, to)?;
+  sync_dir(to.parent().unwrap())
+}
+
+// Where each partition should be: pinned years go to their dir and the rest even out the free
+// space, newest first since those are the ones read most
+fn plan(table: &Table, policy: &Policy, data_dirs: &[&str]) -> Vec<(String, String)> {
+  let mut free =
+    data_dirs.iter().map(|d| (d.to_string(), free_bytes(d))).collect::<HashMap<_, _>>();
+  let mut names = table.partition_meta.keys().cloned().collect::<Vec<_>>();
+  names.sort();
+  names.reverse();
+  let mut res = Vec::<(String, String)>::new();
+  for name in names.iter() {
+    let meta = &table.partition_meta[name];
+    let from = data_dir(&meta.dir);
+    // Not one of ours, like an archive dir
+    if !free.contains_key(&from) {
+      continue;
+    }
+    let size = dir_size(Path::new(&meta.dir));
+    let to = match policy {
+      Policy::ByYear(pins) if pins.contains_key(&name[..4]) => pins[&name[..4]].clone(),
+      _ => {
+        let (most_free, most) = free.iter().max_by_key(|(_dir, free)| **free).unwrap();
+        // Only worth moving if it narrows the gap
+        if *most > free[&from] + 2 * size {
+          most_free.clone()
+        } else {
+          from.clone()
+        }
+      }
+    };
+    if to == from {
+      continue;
+    }
+    *free.get_mut(&from).unwrap() += size;
+    let to_free = free[&to].saturating_sub(size);
+    free.insert(to.clone(), to_free);
+    res.push((name.clone(), to));
+  }
+
+  res
+}
+
+// The schema to open a placed table with to change its partitions' dirs
+pub fn placed_schema(table: &str, data_dirs: Vec<&str>) -> Schema {
+  match table {
+    "agg1m" => agg1m_schema(data_dirs),
+    "trades" => trades_schema(data_dirs),
+    _ => quotes_schema(data_dirs)
+  }
+}
+
+// Moves a partition's columns under `to` and persists its new dir. Until the journal commits
+// the old copy is the one in use, and a crash before then removes the new one.
+pub fn move_partition(table: &mut Table, name: &str, partition: &str, to: &str) {
+  let from = PathBuf::from(&table.partition_meta[partition].dir);
+  let dest = Path::new(to).join(name).join(partition);
+  let size = dir_size(&from);
+  preflight(name, partition, to, size);
+  if dest.exists() {
+    // Left over from a move that crashed before committing
+    fs::remove_dir_all(&dest).unwrap_or_else(|e| panic!("Could not remove {:?}: {}", dest, e));
+  }
+  let journal = Journal::begin(name, partition, &[from.clone(), dest.clone()])
+    .unwrap_or_else(|e| panic!("Could not stage {}: {}", partition, e));
+  copy_partition(&from, &dest)
+    .unwrap_or_else(|e| panic!("Could not copy {:?} to {:?}: {}", from, dest, e));
+  table.partition_meta.get_mut(partition).unwrap().dir = dest.to_string_lossy().to_string();
+  table.flush();
+  journal
+    .commit(&[dest.clone()])
+    .unwrap_or_else(|e| panic!("Could not commit {}: {}", partition, e));
+  fs::remove_dir_all(&from).unwrap_or_else(|e| panic!("Could not remove {:?}: {}", from, e));
+  info!(table = name, partition, ?from, to = ?dest, size, "Moved");
+}
+
+// Moves partitions between data dirs to match `policy`, updating the table's metadata after each
+pub fn rebalance(tables: Vec<&str>, policy: Policy, data_dirs: Vec<&str>, dry_run: bool) {
+  for name in tables.iter() {
+    if !table_dir(name).exists() {
+      warn!(table = name, "Table does not exist, skipping");
+      continue;
+    }
+    let _lock = Lock::table(name);
+    journal::recover(name).unwrap_or_else(|e| panic!("Could not recover {}: {}", name, e));
+    let mut table =
+      Table::create_or_open(placed_schema(name, data_dirs.clone())).expect("Could not open table");
+    let moves = plan(&table, &policy, &data_dirs);
+    info!(table = name, partitions = moves.len(), "Rebalancing");
+    for (partition, to) in moves.iter() {
+      if dry_run {
+        let from = &table.partition_meta[partition].dir;
+        println!("{}: {} -> {}/{}/{}", name, from, to, name, partition);
+        continue;
+      }
+      move_partition(&mut table, name, partition, to);
+    }
+  }
+}
@@ -0,0 +1,58 @@
+use crate::{
+  batch::{batch, table_spec, Values},
+  report::{self, Phase},
+  sink::Sink
+};
+use chrono::NaiveDate;
+use tracing::debug;
+use zdb::schema::Schema;
+
+pub struct QuoteRow {
+  pub ts:             i64,
+  pub ts_participant: i64,
+  pub seq_id:         u64,
+  pub symbol:         String,
+  pub bid_price:      f64,
+  pub bid_size:       u32,
+  pub bid_exchange:   u8,
+  pub ask_price:      f64,
+  pub ask_size:       u32,
+  pub ask_exchange:   u8,
+  pub conditions:     u32,
+  pub indicators:     u32,
+  pub tape:           u8
+}
+
+pub fn quotes_schema(partition_dirs: Vec<&str>) -> Schema {
+  table_spec("quotes").unwrap().schema(partition_dirs)
+}
+
+// Sorts and writes a day of quotes then flushes
+pub fn write_quotes(quotes_table: &mut dyn Sink, quotes: &mut Vec<QuoteRow>, date: NaiveDate) {
+  let num_quotes = quotes.len();
+  // Sequence numbers are per symbol, so sort by ts first
+  debug!(%date, "Sorting {} quotes", num_quotes);
+  report::timed("quotes", Phase::Sort, || {
+    quotes.sort_unstable_by(|q1, q2| q1.ts.cmp(&q2.ts).then(q1.seq_id.cmp(&q2.seq_id)))
+  });
+  debug!(%date, "Writing {} quotes", num_quotes);
+  let rows = batch(&date.to_string(), vec![
+    ("ts", Values::Timestamp(quotes.iter().map(|q| q.ts).collect())),
+    ("ts_participant", Values::I64(quotes.iter().map(|q| q.ts_participant).collect())),
+    ("seq_id", Values::U64(quotes.iter().map(|q| q.seq_id).collect())),
+    ("sym", Values::Str(quotes.iter_mut().map(|q| std::mem::take(&mut q.symbol)).collect())),
+    ("bid_price", Values::F64(quotes.iter().map(|q| q.bid_price).collect())),
+    ("bid_size", Values::U32(quotes.iter().map(|q| q.bid_size).collect())),
+    ("bid_exchange", Values::U8(quotes.iter().map(|q| q.bid_exchange).collect())),
+    ("ask_price", Values::F64(quotes.iter().map(|q| q.ask_price).collect())),
+    ("ask_size", Values::U32(quotes.iter().map(|q| q.ask_size).collect())),
+    ("ask_exchange", Values::U8(quotes.iter().map(|q| q.ask_exchange).collect())),
+    ("cond", Values::U32(quotes.iter().map(|q| q.conditions).collect())),
+    ("indicators", Values::U32(quotes.iter().map(|q| q.indicators).collect())),
+    ("tape", Values::U8(quotes.iter().map(|q| q.tape).collect())),
+  ]);
+  quotes.clear();
+  report::timed("quotes", Phase::Write, || quotes_table.append(rows));
+  debug!(%date, "Flushing {} quotes", num_quotes);
+  report::timed("quotes", Phase::Flush, || quotes_table.flush());
+}
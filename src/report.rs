@@ -0,0 +1,172 @@
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use serde_json::json;
+use std::{
+  collections::BTreeMap,
+  fs,
+  path::PathBuf,
+  sync::Mutex,
+  time::{Duration, Instant}
+};
+use tracing::{error, info};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+  Fetch,
+  Sort,
+  Write,
+  Flush
+}
+
+impl Phase {
+  fn name(&self) -> &'static str {
+    match self {
+      Phase::Fetch => "fetch",
+      Phase::Sort => "sort",
+      Phase::Write => "write",
+      Phase::Flush => "flush"
+    }
+  }
+}
+
+#[derive(Default)]
+struct DatasetReport {
+  partitions:   Vec<String>,
+  rows_written: u64,
+  // (partition, symbol)
+  no_data:      Vec<(String, String)>,
+  api_calls:    u64,
+  retries:      u64,
+  failures:     u64,
+  phases:       BTreeMap<&'static str, Duration>
+}
+
+struct Report {
+  started:  DateTime<Utc>,
+  path:     Option<PathBuf>,
+  datasets: BTreeMap<String, DatasetReport>
+}
+
+lazy_static! {
+  static ref REPORT: Mutex<Report> = Mutex::new(Report {
+    started:  Utc::now(),
+    path:     None,
+    datasets: BTreeMap::new()
+  });
+}
+
+fn with_dataset<F>(dataset: &str, f: F)
+where
+  F: FnOnce(&mut DatasetReport)
+{
+  let mut report = REPORT.lock().unwrap();
+  f(report.datasets.entry(dataset.to_string()).or_default());
+}
+
+// Starts a report that finish() writes to `path`. Runs without one don't write anything.
+pub fn init(path: PathBuf) {
+  let mut report = REPORT.lock().unwrap();
+  report.started = Utc::now();
+  report.path = Some(path);
+  report.datasets.clear();
+}
+
+pub fn partition(dataset: &str, partition: &str) {
+  with_dataset(dataset, |d| {
+    if !d.partitions.iter().any(|p| p == partition) {
+      d.partitions.push(partition.to_string());
+    }
+  });
+}
+
+pub fn rows_written(dataset: &str, num_rows: usize) {
+  with_dataset(dataset, |d| d.rows_written += num_rows as u64);
+}
+
+pub fn no_data(dataset: &str, partition: &str, symbol: &str) {
+  with_dataset(dataset, |d| d.no_data.push((partition.to_string(), symbol.to_string())));
+}
+
+pub fn api_call(dataset: &str) { with_dataset(dataset, |d| d.api_calls += 1); }
+
+pub fn retry(dataset: &str) { with_dataset(dataset, |d| d.retries += 1); }
+
+pub fn failure(dataset: &str) { with_dataset(dataset, |d| d.failures += 1); }
+
+// Requests of `dataset` that gave up since init()
+pub fn failures(dataset: &str) -> u64 {
+  REPORT.lock().unwrap().datasets.get(dataset).map(|d| d.failures).unwrap_or(0)
+}
+
+pub fn phase(dataset: &str, phase: Phase, elapsed: Duration) {
+  with_dataset(dataset, |d| *d.phases.entry(phase.name()).or_default() += elapsed);
+}
+
+pub fn timed<T, F>(dataset: &str, phase: Phase, f: F) -> T
+where
+  F: FnOnce() -> T
+{
+  let now = Instant::now();
+  let res = f();
+  self::phase(dataset, phase, now.elapsed());
+
+  res
+}
+
+// Logs a line per dataset and writes the whole report as JSON. Called at the end of a run and
+// before exiting on a failure so the report covers what was done.
+pub fn finish() {
+  let report = REPORT.lock().unwrap();
+  let finished = Utc::now();
+  let mut datasets = serde_json::Map::new();
+  for (name, d) in report.datasets.iter() {
+    info!(
+      dataset = %name,
+      partitions = d.partitions.len(),
+      rows_written = d.rows_written,
+      no_data = d.no_data.len(),
+      api_calls = d.api_calls,
+      retries = d.retries,
+      failures = d.failures,
+      "Summary"
+    );
+    let phases = d
+      .phases
+      .iter()
+      .map(|(phase, elapsed)| (phase.to_string(), json!(elapsed.as_secs_f64())))
+      .collect::<serde_json::Map<_, _>>();
+    datasets.insert(name.clone(), json!({
+      "partitions": d.partitions,
+      "rows_written": d.rows_written,
+      "no_data": d
+        .no_data
+        .iter()
+        .map(|(partition, symbol)| json!({ "partition": partition, "symbol": symbol }))
+        .collect::<Vec<_>>(),
+      "api_calls": d.api_calls,
+      "retries": d.retries,
+      "failures": d.failures,
+      "phase_secs": phases
+    }));
+  }
+
+  let path = match &report.path {
+    Some(path) => path,
+    None => return
+  };
+  let json = json!({
+    "started": report.started.to_rfc3339(),
+    "finished": finished.to_rfc3339(),
+    "wall_secs": (finished - report.started).num_milliseconds() as f64 / 1000.0,
+    "datasets": datasets
+  });
+  let res = path
+    .parent()
+    .map(fs::create_dir_all)
+    .unwrap_or(Ok(()))
+    .and_then(|_| fs::write(path, serde_json::to_string_pretty(&json).unwrap()));
+  match res {
+    Ok(_) => info!(?path, "Wrote report"),
+    Err(e) => error!(?path, error = %e, "Could not write report")
+  }
+}
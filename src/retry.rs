@@ -0,0 +1,261 @@
+use crate::{metrics, report, shutdown};
+use rand::Rng;
+use std::{
+  cmp, fmt,
+  io::{self, ErrorKind},
+  thread,
+  time::{Duration, Instant}
+};
+use tracing::{error, warn};
+
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorClass {
+  RateLimited,
+  Transient,
+  Server,
+  Client,
+  Parse,
+  NoData,
+  // Stopped retrying because the run is shutting down
+  Cancelled
+}
+
+impl ErrorClass {
+  // polygon_io flattens everything into io::Error. No data is UnexpectedEof, bad JSON is
+  // InvalidData and HTTP errors carry their status code in the message.
+  pub fn classify(e: &io::Error) -> ErrorClass {
+    match e.kind() {
+      ErrorKind::UnexpectedEof => return ErrorClass::NoData,
+      ErrorKind::InvalidData => return ErrorClass::Parse,
+      ErrorKind::PermissionDenied => return ErrorClass::Client,
+      _ => {}
+    };
+    match status(e) {
+      Some(429) => ErrorClass::RateLimited,
+      Some(status) if status >= 500 => ErrorClass::Server,
+      Some(status) if status >= 400 => ErrorClass::Client,
+      // Resets, timeouts, odd successes like 204 and anything else we can't place are worth
+      // another try
+      _ => ErrorClass::Transient
+    }
+  }
+
+  // Retrying these gets the same answer
+  pub fn is_permanent(&self) -> bool {
+    matches!(
+      self,
+      ErrorClass::Client | ErrorClass::Parse | ErrorClass::NoData | ErrorClass::Cancelled
+    )
+  }
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      ErrorClass::RateLimited => "rate_limited",
+      ErrorClass::Transient => "transient",
+      ErrorClass::Server => "server",
+      ErrorClass::Client => "client",
+      ErrorClass::Parse => "parse",
+      ErrorClass::NoData => "no_data",
+      ErrorClass::Cancelled => "cancelled"
+    }
+  }
+}
+
+// The HTTP status of a failed polygon_io request. polygon_io only keeps the message, which is
+// ureq's error as "Status(429, Response[...])" or "Server returned 204" for a status ureq
+// didn't treat as an error. Anything else, like a transport error whose URL or message happens
+// to contain 443 or 404, has no status.
+pub fn status(e: &io::Error) -> Option<u16> {
+  let msg = e.to_string();
+  let code = match msg.strip_prefix("Status(") {
+    Some(rest) => rest.split(',').next()?,
+    None => msg.strip_prefix("Server returned ")?
+  };
+  code.trim().parse().ok()
+}
+
+#[derive(Debug)]
+pub struct RetryError {
+  pub class:    ErrorClass,
+  pub error:    io::Error,
+  pub attempts: u32
+}
+
+impl fmt::Display for RetryError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{} after {} attempts ({})", self.error, self.attempts, self.class.name())
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+  // First delay. Doubles each attempt up to max_delay.
+  pub initial:     Duration,
+  pub max_delay:   Duration,
+  // Stop retrying once this much time has passed since the first attempt
+  pub max_elapsed: Duration
+}
+
+impl RetryPolicy {
+  // agg1m and trades make thousands of requests per partition and can wait out an outage
+  pub fn for_dataset(dataset: &str) -> RetryPolicy {
+    let max_elapsed = match dataset {
+      "agg1m" | "trades" => 30 * 60,
+      _ => 5 * 60
+    };
+    RetryPolicy {
+      initial:     Duration::from_secs(1),
+      max_delay:   Duration::from_secs(60),
+      max_elapsed: Duration::from_secs(max_elapsed)
+    }
+  }
+
+  // Half the exponential delay plus up to half again so threads that failed together don't
+  // retry together
+  fn delay(&self, attempt: u32) -> Duration {
+    let exp = self.initial.checked_mul(1 << cmp::min(attempt, 16)).unwrap_or(self.max_delay);
+    let half = cmp::min(exp, self.max_delay) / 2;
+    half + half.mul_f64(rand::thread_rng().gen::<f64>())
+  }
+
+  // polygon_io drops the response, so a 429's Retry-After can't be read. Polygon's limits are
+  // per minute, so wait out at least one rather than spend the retry budget getting 429s.
+  fn delay_for(&self, class: ErrorClass, attempt: u32) -> Duration {
+    match class {
+      ErrorClass::RateLimited => cmp::max(self.delay(attempt), RATE_LIMIT_WINDOW),
+      _ => self.delay(attempt)
+    }
+  }
+}
+
+// Values are "secs" for every dataset or "dataset=secs" for one. The last match wins.
+pub fn override_secs<'a, I>(values: I, dataset: &str) -> Option<Duration>
+where
+  I: Iterator<Item = &'a str>
+{
+  values
+    .filter_map(|v| match v.split_once('=') {
+      Some((d, secs)) if d == dataset => Some(secs),
+      Some(_) => None,
+      None => Some(v)
+    })
+    .last()
+    .map(|secs| Duration::from_secs_f64(secs.parse().expect("Invalid retry seconds")))
+}
+
+// Calls `f` until it succeeds, fails permanently or runs out of time. Each call counts as a
+// request for `dataset`.
+pub fn retry<T, F>(dataset: &str, what: &str, policy: &RetryPolicy, mut f: F) -> Result<T, RetryError>
+where
+  F: FnMut() -> io::Result<T>
+{
+  let start = Instant::now();
+  let mut attempts = 0;
+  loop {
+    metrics::request(dataset);
+    attempts += 1;
+    let error = match f() {
+      Ok(res) => return Ok(res),
+      Err(e) => e
+    };
+    let class = ErrorClass::classify(&error);
+    let delay = policy.delay_for(class, attempts - 1);
+    if class.is_permanent() || start.elapsed() + delay > policy.max_elapsed {
+      return Err(RetryError { class, error, attempts });
+    }
+    warn!(
+      attempt = attempts,
+      class = class.name(),
+      error = %error,
+      delay_ms = delay.as_millis() as u64,
+      "{} failed",
+      what
+    );
+    metrics::retry(dataset, class.name());
+    // Wake up to notice a shutdown rather than sleeping out a long backoff
+    let wake = Instant::now() + delay;
+    while !shutdown::requested() && Instant::now() < wake {
+      thread::sleep(cmp::min(wake.saturating_duration_since(Instant::now()), Duration::from_secs(1)));
+    }
+    if shutdown::requested() {
+      return Err(RetryError { class: ErrorClass::Cancelled, error, attempts });
+    }
+  }
+}
+
+// Stops the run over a request that won't succeed, unless it's already stopping
+pub fn give_up(dataset: &str, what: &str, e: &RetryError) {
+  if e.class == ErrorClass::Cancelled {
+    return;
+  }
+  error!(error = %e, "{} failed", what);
+  report::failure(dataset);
+  shutdown::fail();
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn error(kind: ErrorKind, msg: &str) -> io::Error { io::Error::new(kind, msg.to_string()) }
+
+  fn classify(msg: &str) -> ErrorClass { ErrorClass::classify(&error(ErrorKind::TimedOut, msg)) }
+
+  #[test]
+  fn classifies() {
+    let eof = error(ErrorKind::UnexpectedEof, "Results is empty");
+    assert_eq!(ErrorClass::classify(&eof), ErrorClass::NoData);
+    let json = error(ErrorKind::InvalidData, "expected value at line 1 column 1");
+    assert_eq!(ErrorClass::classify(&json), ErrorClass::Parse);
+    let url = "url: https://api.polygon.io/v2/aggs/ticker/AAPL/range/1/minute/2021-05-01/\
+               2021-05-31";
+    let response = |status: u16, text: &str| {
+      format!("Status({}, Response[status: {}, status_text: {}, {}])", status, status, text, url)
+    };
+    assert_eq!(classify(&response(429, "Too Many Requests")), ErrorClass::RateLimited);
+    assert_eq!(classify(&response(502, "Bad Gateway")), ErrorClass::Server);
+    assert_eq!(classify(&response(403, "Forbidden")), ErrorClass::Client);
+    let not_ok = error(ErrorKind::NotConnected, "Server returned 404");
+    assert_eq!(ErrorClass::classify(&not_ok), ErrorClass::Client);
+    let no_content = error(ErrorKind::NotConnected, "Server returned 204");
+    assert_eq!(ErrorClass::classify(&no_content), ErrorClass::Transient);
+  }
+
+  #[test]
+  fn numbers_in_messages_are_not_statuses() {
+    let transport = "Transport(Transport { kind: Io, url: Some(\"https://api.polygon.io:443/v2/\
+                     aggs/ticker/AAPL/range/1/day/2021-04-04/2021-05-05\"), source: Some(Custom { \
+                     kind: TimedOut, error: \"timed out after 404ms\" }) })";
+    assert_eq!(status(&error(ErrorKind::TimedOut, transport)), None);
+    assert_eq!(classify(transport), ErrorClass::Transient);
+    let reset = "connection reset by peer (os error 104) at 500 bytes";
+    assert_eq!(classify(reset), ErrorClass::Transient);
+  }
+
+  #[test]
+  fn delays() {
+    let policy = RetryPolicy {
+      initial:     Duration::from_secs(1),
+      max_delay:   Duration::from_secs(60),
+      max_elapsed: Duration::from_secs(600)
+    };
+    for attempt in 0..40 {
+      let full = cmp::min(Duration::from_secs(1 << cmp::min(attempt, 16)), policy.max_delay);
+      let delay = policy.delay(attempt);
+      assert!(delay >= full / 2 && delay <= full, "attempt {}: {:?}", attempt, delay);
+      assert!(policy.delay_for(ErrorClass::RateLimited, attempt) >= RATE_LIMIT_WINDOW);
+      assert!(policy.delay_for(ErrorClass::Server, attempt) <= full);
+    }
+  }
+
+  #[test]
+  fn overrides() {
+    let values = ["10", "trades=30", "agg1d=5"];
+    assert_eq!(override_secs(values.iter().cloned(), "trades"), Some(Duration::from_secs(30)));
+    assert_eq!(override_secs(values.iter().cloned(), "agg1d"), Some(Duration::from_secs(5)));
+    assert_eq!(override_secs(values.iter().cloned(), "agg1m"), Some(Duration::from_secs(10)));
+    assert_eq!(override_secs(["trades=1"].iter().cloned(), "agg1m"), None);
+  }
+}
@@ -0,0 +1,169 @@
+use chrono::Utc;
+use lazy_static::lazy_static;
+use serde_json::json;
+use std::{
+  fs,
+  path::PathBuf,
+  process,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Mutex
+  }
+};
+use tracing::{error, info, warn};
+
+// Lists what an interrupted run didn't finish so the next one can pick it up
+fn resume_path() -> PathBuf { crate::util::data_root().join("resume.json") }
+
+static STOP: AtomicBool = AtomicBool::new(false);
+static FAILED: AtomicBool = AtomicBool::new(false);
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+  // (dataset, partition) dropped because it was only partly fetched
+  static ref DISCARDED: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+}
+
+// The first SIGINT or SIGTERM asks workers to stop. A second one exits right away.
+pub fn install() {
+  let res = ctrlc::set_handler(|| {
+    if INTERRUPTED.swap(true, Ordering::SeqCst) {
+      warn!("Interrupted again, exiting without writing");
+      process::exit(130);
+    }
+    STOP.store(true, Ordering::SeqCst);
+    warn!("Interrupted, finishing in-flight requests. Interrupt again to exit now.");
+  });
+  if let Err(e) = res {
+    error!(error = %e, "Could not install signal handler");
+  }
+}
+
+pub fn requested() -> bool { STOP.load(Ordering::SeqCst) }
+
+// Stops the run like a signal would, but exits with 1 once everything is consistent
+pub fn fail() {
+  FAILED.store(true, Ordering::SeqCst);
+  STOP.store(true, Ordering::SeqCst);
+}
+
+// For the daemon, which backs off and tries a failed dataset again instead of exiting. Returns
+// whether the run failed and, unless it was also interrupted, lets it go on.
+pub fn take_failure() -> bool {
+  if INTERRUPTED.load(Ordering::SeqCst) {
+    return FAILED.load(Ordering::SeqCst);
+  }
+  let failed = FAILED.swap(false, Ordering::SeqCst);
+  if failed {
+    STOP.store(false, Ordering::SeqCst);
+  }
+
+  failed
+}
+
+pub fn discard(dataset: &str, partition: &str) {
+  warn!(dataset, partition, "Discarding incomplete partition");
+  DISCARDED.lock().unwrap().push((dataset.to_string(), partition.to_string()));
+}
+
+// Datasets an interrupted run didn't finish, in order
+pub fn read_resume() -> Option<Vec<String>> {
+  let path = resume_path();
+  let contents = fs::read_to_string(&path).ok()?;
+  let json = serde_json::from_str::<serde_json::Value>(&contents).ok()?;
+  let datasets = json["remaining"]
+    .as_array()?
+    .iter()
+    .filter_map(|d| d.as_str().map(String::from))
+    .collect::<Vec<_>>();
+  info!(?path, ?datasets, "Resuming interrupted run");
+
+  Some(datasets)
+}
+
+fn write_resume(remaining: &[&str]) {
+  let json = json!({
+    "interrupted": Utc::now().to_rfc3339(),
+    "remaining": remaining,
+    "discarded": DISCARDED
+      .lock()
+      .unwrap()
+      .iter()
+      .map(|(dataset, partition)| json!({ "dataset": dataset, "partition": partition }))
+      .collect::<Vec<_>>()
+  });
+  let path = resume_path();
+  let res = path
+    .parent()
+    .map(fs::create_dir_all)
+    .unwrap_or(Ok(()))
+    .and_then(|_| fs::write(&path, serde_json::to_string_pretty(&json).unwrap()));
+  match res {
+    Ok(_) => info!(?path, "Wrote resume file"),
+    Err(e) => error!(?path, error = %e, "Could not write resume file")
+  }
+}
+
+// Call between datasets. When stopping, records `remaining` (starting with the one that was
+// running) and exits.
+pub fn checkpoint(remaining: &[&str]) {
+  if !requested() {
+    return;
+  }
+  write_resume(remaining);
+  crate::report::finish();
+  process::exit(if FAILED.load(Ordering::SeqCst) { 1 } else { 130 });
+}
+
+// Call after the last dataset's checkpoint. Only `completed` is crossed off, so a run of
+// some other datasets doesn't lose what an interrupted run left to do.
+pub fn finish(completed: &[&str]) {
+  let path = resume_path();
+  let mut json = match fs::read_to_string(&path) {
+    Ok(contents) => match serde_json::from_str::<serde_json::Value>(&contents) {
+      Ok(json) => json,
+      Err(e) => {
+        warn!(?path, error = %e, "Could not parse resume file");
+        return;
+      }
+    },
+    Err(_) => return
+  };
+  let done = |v: &serde_json::Value| v.as_str().map_or(false, |d| completed.contains(&d));
+  if let Some(remaining) = json["remaining"].as_array_mut() {
+    remaining.retain(|d| !done(d));
+  }
+  if let Some(discarded) = json["discarded"].as_array_mut() {
+    discarded.retain(|d| !done(&d["dataset"]));
+  }
+  let res = if json["remaining"].as_array().map_or(true, |r| r.is_empty()) {
+    fs::remove_file(&path)
+  } else {
+    info!(?path, remaining = %json["remaining"], "Kept unfinished datasets in resume file");
+    fs::write(&path, serde_json::to_string_pretty(&json).unwrap())
+  };
+  if let Err(e) = res {
+    warn!(?path, error = %e, "Could not update resume file");
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn finish_keeps_other_datasets() {
+    crate::util::test_data_root();
+    discard("trades", "2021/03");
+    write_resume(&["agg1m", "trades"]);
+    finish(&["agg1m"]);
+    assert_eq!(read_resume(), Some(vec!["trades".to_string()]));
+    let json = serde_json::from_str::<serde_json::Value>(
+      &fs::read_to_string(resume_path()).unwrap()
+    )
+    .unwrap();
+    assert_eq!(json["discarded"][0]["partition"], "2021/03");
+    finish(&["trades"]);
+    assert!(!resume_path().exists());
+  }
+}
@@ -0,0 +1,651 @@
+use crate::{
+  batch::{table_spec, Batch, ColumnKind, TableSpec, TsFormat, Values},
+  compact::is_compacted,
+  export::{write_ndjson, write_parquet},
+  journal::{self, Journal, Saved, Savepoint},
+  layout::check_layout,
+  lock::Lock,
+  metrics,
+  placement::{self, estimate_bytes, PLACED_TABLES},
+  strings::StringHeap,
+  symbols::{check_capacity, load_dictionary, SYMBOL16_CAPACITY, SYMBOL8_CAPACITY},
+  util::table_dir
+};
+use arrow::ipc::writer::StreamWriter;
+use std::{
+  collections::{HashMap, HashSet},
+  fs::{self, File},
+  io::{self, BufWriter, ErrorKind, Write},
+  path::{Path, PathBuf},
+  process
+};
+use tracing::{error, info, warn};
+use zdb::{calendar::ToNaiveDateTime, schema::Schema, table::Table};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SinkKind {
+  Zdb,
+  Parquet,
+  ArrowIpc,
+  Ndjson
+}
+
+// Where a downloader's rows go. Downloaders only fetch, sort and build Batches whose columns
+// are in schema order, so they don't care how or where rows are stored.
+pub trait Sink {
+  fn kind(&self) -> SinkKind;
+
+  // Streams can't resume and must be written in ts order
+  fn is_stream(&self) -> bool { false }
+
+  // The last timestamp written to a partition. Downloads resume from the day after.
+  fn partition_to_ts(&self, partition: &str) -> Option<i64>;
+
+  fn partition_row_count(&self, partition: &str) -> Option<usize>;
+
+  fn append(&mut self, batch: Batch);
+
+  // Rows appended to a partition after this get replaced the next time it's opened with
+  // `kind`. Only zdb can take rows back out, so other sinks keep them.
+  fn savepoint(&mut self, _kind: Savepoint, _partition: &str) {}
+
+  // Partitions rolled back to a savepoint on open, which need downloading again
+  fn replaced(&self) -> &[String] { &[] }
+
+  // Removes every row of a partition so it can be downloaded again
+  fn drop_partition(&mut self, partition: &str);
+
+  // Makes every appended row durable
+  fn flush(&mut self);
+
+  // Called once a download is done
+  fn finish(&mut self) {}
+}
+
+fn spec_for(dataset: &str) -> TableSpec {
+  match table_spec(dataset) {
+    Some(spec) => spec,
+    None => {
+      error!(dataset, "No columns known");
+      process::exit(1);
+    }
+  }
+}
+
+fn partition_name(spec: &TableSpec, ts: i64) -> String {
+  ts.to_naive_date_time().format(spec.partition_format).to_string()
+}
+
+// Writes rows to a zdb table. String columns go in the table's symbol dictionaries or its
+// StringHeaps depending on the column. Each partition touched since the last flush has a
+// Journal so a crash rolls it back instead of leaving it torn.
+pub struct ZdbSink {
+  table:        Table,
+  spec:         TableSpec,
+  heaps:        HashMap<&'static str, StringHeap>,
+  journals:     HashMap<String, Journal>,
+  // Each symbol column's dictionary, loaded on its first append and kept up to date after
+  dictionaries: HashMap<&'static str, HashSet<String>>,
+  // The data dir chosen for each new partition
+  placed:       HashMap<String, String>,
+  replaced:     Vec<String>,
+  // Released once the sink is dropped, which is after its last flush
+  _lock:        Lock
+}
+
+// Rolls a partition back to `saved` under a journal. Returns whether it was.
+fn rollback_to(table: &mut Table, name: &str, saved: &Saved) -> bool {
+  let partition = saved.partition.as_str();
+  let dir = match table.partition_meta.get(partition) {
+    Some(meta) => PathBuf::from(&meta.dir),
+    // Dropped since
+    None => return false
+  };
+  if is_compacted(&dir) {
+    warn!(table = name, partition, "Partition was compacted since its savepoint, keeping it");
+    return false;
+  }
+  let journal = Journal::begin(name, partition, &[dir.clone()])
+    .unwrap_or_else(|e| panic!("Could not stage {}: {}", partition, e));
+  let res = match saved.meta {
+    // Everything in it came after
+    None => {
+      let dropped = dir.with_extension("dropped");
+      fs::rename(&dir, &dropped).unwrap_or_else(|e| panic!("Could not remove {:?}: {}", dir, e));
+      table.partition_meta.remove(partition);
+      true
+    }
+    Some((to_ts, row_count)) => {
+      let truncated = journal::truncate(saved, &dir)
+        .unwrap_or_else(|e| panic!("Could not truncate {:?}: {}", dir, e));
+      if truncated {
+        let meta = table.partition_meta.get_mut(partition).unwrap();
+        meta.to_ts = to_ts;
+        meta.row_count = row_count;
+      } else {
+        warn!(table = name, partition, "Partition was rewritten since its savepoint, keeping it");
+      }
+      truncated
+    }
+  };
+  table.flush();
+  journal
+    .commit(&[dir.clone()])
+    .unwrap_or_else(|e| panic!("Could not commit {}: {}", partition, e));
+  let dropped = dir.with_extension("dropped");
+  if dropped.exists() {
+    fs::remove_dir_all(&dropped)
+      .unwrap_or_else(|e| panic!("Could not remove {:?}: {}", dropped, e));
+  }
+
+  res
+}
+
+// Rolls every partition of `name` with a savepoint of one of `kinds` back to it. Done on a
+// table of its own before the sink opens so the sink's sees the files as they end up.
+fn replace(name: &str, kinds: &[Savepoint]) -> Vec<String> {
+  let mut res = Vec::<String>::new();
+  for kind in kinds.iter() {
+    let saved = journal::savepoints(name, *kind)
+      .unwrap_or_else(|e| panic!("Could not read savepoints of {}: {}", name, e));
+    if saved.is_empty() {
+      continue;
+    }
+    let mut table = Table::open(name).expect("Could not open table");
+    for saved in saved.iter() {
+      let rolled_back = rollback_to(&mut table, name, saved);
+      if rolled_back {
+        info!(table = name, partition = %saved.partition, kind = ?saved.kind, "Replacing rows");
+        res.push(saved.partition.clone());
+      }
+      // Savepoints taken after this one are of rows that are gone now
+      match (rolled_back, saved.meta) {
+        (true, None) => journal::remove_savepoints(name, &saved.partition),
+        (true, Some(_)) => journal::remove_later(name, saved),
+        (false, _) => Ok(())
+      }
+      .and_then(|_| journal::remove_savepoint(name, saved))
+      .unwrap_or_else(|e| panic!("Could not remove savepoint of {}: {}", saved.partition, e));
+    }
+  }
+  res.sort();
+  res.dedup();
+
+  res
+}
+
+impl ZdbSink {
+  // Rolls back partitions with a savepoint of one of `replace` first, for downloaders that
+  // fetch them again
+  pub fn open(schema: Schema, dataset: &str, replace: &[Savepoint]) -> ZdbSink {
+    let lock = Lock::table(dataset);
+    check_layout(dataset);
+    journal::recover(dataset).unwrap_or_else(|e| panic!("Could not recover {}: {}", dataset, e));
+    let replaced = self::replace(dataset, replace);
+    let table = Table::create_or_open(schema).expect("Could not open table");
+    let spec = spec_for(dataset);
+    let mut heaps = HashMap::<&'static str, StringHeap>::new();
+    for (_name, kind) in spec.columns.iter() {
+      if let ColumnKind::Heap(heap) = kind {
+        if !heaps.contains_key(heap) {
+          let string_heap = StringHeap::open(spec.name, heap).expect("Could not open string heap");
+          heaps.insert(*heap, string_heap);
+        }
+      }
+    }
+
+    ZdbSink {
+      table,
+      spec,
+      heaps,
+      journals: HashMap::new(),
+      dictionaries: HashMap::new(),
+      placed: HashMap::new(),
+      replaced,
+      _lock: lock
+    }
+  }
+
+  // Where a partition's column files are. New partitions go next to existing ones or in a data
+  // dir we placed one in.
+  fn partition_dirs(&self, partition: &str) -> Vec<PathBuf> {
+    if let Some(meta) = self.table.partition_meta.get(partition) {
+      return vec![PathBuf::from(&meta.dir)];
+    }
+    let mut res = self
+      .table
+      .partition_meta
+      .values()
+      .filter_map(|meta| Path::new(&meta.dir).parent().map(|p| p.join(partition)))
+      .collect::<Vec<_>>();
+    res.extend(self.placed.values().map(|d| Path::new(d).join(self.spec.name).join(partition)));
+    res.push(table_dir(self.spec.name).join(partition));
+    res.sort();
+    res.dedup();
+
+    res
+  }
+
+  // zdb puts new partitions in one of its partition dirs, so give it only the one we chose.
+  // Chosen once per partition so its journal covers where it ends up.
+  fn place(&mut self, partition: &str) {
+    let name = self.spec.name;
+    if !PLACED_TABLES.contains(&name) || self.table.partition_meta.contains_key(partition) {
+      return;
+    }
+    let data_dir = match self.placed.get(partition) {
+      Some(data_dir) => data_dir.clone(),
+      None => placement::place(name, partition, estimate_bytes(&self.table))
+    };
+    self.table.schema.partition_dirs = vec![data_dir.clone()];
+    self.placed.insert(partition.to_string(), data_dir);
+  }
+
+  fn begin(&mut self, partition: &str) {
+    if self.journals.contains_key(partition) {
+      return;
+    }
+    self.place(partition);
+    let dirs = self.partition_dirs(partition);
+    let journal = Journal::begin(self.spec.name, partition, &dirs)
+      .unwrap_or_else(|e| panic!("Could not stage {}: {}", partition, e));
+    self.journals.insert(partition.to_string(), journal);
+  }
+
+  fn commit(&mut self) {
+    for (partition, journal) in self.journals.drain().collect::<Vec<_>>() {
+      journal
+        .commit(&self.partition_dirs(&partition))
+        .unwrap_or_else(|e| panic!("Could not commit {}: {}", partition, e));
+    }
+  }
+}
+
+impl Sink for ZdbSink {
+  fn kind(&self) -> SinkKind { SinkKind::Zdb }
+
+  fn partition_to_ts(&self, partition: &str) -> Option<i64> {
+    self.table.partition_meta.get(partition).map(|meta| meta.to_ts)
+  }
+
+  fn partition_row_count(&self, partition: &str) -> Option<usize> {
+    self.table.partition_meta.get(partition).map(|meta| meta.row_count)
+  }
+
+  fn append(&mut self, mut batch: Batch) {
+    let names = batch.columns.iter().map(|(name, _values)| name.as_str()).collect::<Vec<_>>();
+    let expected = self.spec.columns.iter().map(|(name, _kind)| *name).collect::<Vec<_>>();
+    assert_eq!(names, expected, "{}: columns must be in schema order", self.spec.name);
+
+    // Counts exactly the symbols this batch puts, after any filtering by its downloader
+    for ((_name, values), (name, kind)) in batch.columns.iter().zip(self.spec.columns.iter()) {
+      let capacity = match kind {
+        ColumnKind::Symbol8 => SYMBOL8_CAPACITY,
+        ColumnKind::Symbol16 => SYMBOL16_CAPACITY,
+        _ => continue
+      };
+      if let Values::Str(v) = values {
+        let table = self.spec.name;
+        let dictionary = self
+          .dictionaries
+          .entry(*name)
+          .or_insert_with(|| load_dictionary(table, name).into_iter().collect());
+        check_capacity(dictionary, name, capacity, v.iter().map(|s| s.as_str()));
+      }
+    }
+
+    // Batches are labelled by whoever built them, like a day of a yearly partition. Rows are
+    // sorted, so only format every ts when the first and last disagree.
+    let ts = batch.timestamps();
+    let mut partitions = match (ts.first(), ts.last()) {
+      (Some(first), Some(last)) => {
+        vec![partition_name(&self.spec, *first), partition_name(&self.spec, *last)]
+      }
+      _ => Vec::new()
+    };
+    if partitions.len() == 2 && partitions[0] != partitions[1] {
+      partitions = ts.iter().map(|ts| partition_name(&self.spec, *ts)).collect();
+    }
+    partitions.dedup();
+    for partition in partitions.iter() {
+      if let Some(meta) = self.table.partition_meta.get(partition) {
+        if is_compacted(Path::new(&meta.dir)) {
+          let table = self.spec.name;
+          error!(table, partition = %partition, "Can't append to a compacted partition. Drop it first.");
+          process::exit(1);
+        }
+      }
+      self.begin(partition);
+    }
+    metrics::rows_written(self.spec.name, batch.num_rows());
+    let table = &mut self.table;
+    let heaps = &mut self.heaps;
+    let kinds = self.spec.columns.iter().map(|(_name, kind)| *kind).collect::<Vec<_>>();
+    for i in 0..batch.num_rows() {
+      for ((_name, values), kind) in batch.columns.iter_mut().zip(kinds.iter()) {
+        match values {
+          Values::Timestamp(v) => table.put_timestamp(v[i]),
+          Values::I64(v) => table.put_i64(v[i]),
+          Values::U64(v) => table.put_u64(v[i]),
+          Values::U32(v) => table.put_u32(v[i]),
+          Values::U8(v) => table.put_u8(v[i]),
+          Values::F64(v) => table.put_f64(v[i]),
+          Values::Str(v) => match kind {
+            ColumnKind::Heap(heap) => table.put_u64(heaps.get_mut(heap).unwrap().put(&v[i])),
+            _ => table.put_symbol(std::mem::take(&mut v[i]))
+          }
+        }
+      }
+      table.write();
+    }
+  }
+
+  fn savepoint(&mut self, kind: Savepoint, partition: &str) {
+    // Only what's on disk is before it
+    self.flush();
+    let meta = self.table.partition_meta.get(partition);
+    let dir = meta.map(|meta| PathBuf::from(&meta.dir));
+    let saved = meta.map(|meta| (meta.to_ts, meta.row_count));
+    journal::savepoint(self.spec.name, kind, partition, dir.as_deref(), saved)
+      .unwrap_or_else(|e| panic!("Could not save {}: {}", partition, e));
+  }
+
+  fn replaced(&self) -> &[String] { &self.replaced }
+
+  fn drop_partition(&mut self, partition: &str) {
+    let dir = match self.table.partition_meta.get(partition) {
+      Some(meta) => PathBuf::from(&meta.dir),
+      None => return
+    };
+    self.begin(partition);
+    // Renamed rather than deleted until the drop commits so a rollback can put it back
+    let dropped = dir.with_extension("dropped");
+    fs::rename(&dir, &dropped).unwrap_or_else(|e| panic!("Could not remove {:?}: {}", dir, e));
+    self.table.partition_meta.remove(partition);
+    // Persists the partition_meta without it
+    self.table.flush();
+    self.commit();
+    fs::remove_dir_all(&dropped).unwrap_or_else(|e| panic!("Could not remove {:?}: {}", dropped, e));
+    journal::remove_savepoints(self.spec.name, partition)
+      .unwrap_or_else(|e| panic!("Could not remove savepoints of {}: {}", partition, e));
+  }
+
+  fn flush(&mut self) {
+    // Heaps first so the table never references a string that isn't on disk
+    for heap in self.heaps.values_mut() {
+      heap.flush();
+    }
+    self.table.flush();
+    self.commit();
+  }
+}
+
+// Writes each partition as a directory of Parquet files named by their first timestamp:
+// <dir>/<dataset>/<partition>/<first ts>.parquet
+// Resume points live in <dir>/<dataset>/_partitions.json.
+pub struct ParquetSink {
+  dir:        PathBuf,
+  spec:       TableSpec,
+  partitions: HashMap<String, (i64, usize)>
+}
+
+impl ParquetSink {
+  pub fn open(dataset: &str, dir: &str) -> ParquetSink {
+    let dir = PathBuf::from(dir).join(dataset);
+    fs::create_dir_all(&dir).unwrap_or_else(|e| panic!("Could not create {:?}: {}", dir, e));
+    let mut partitions = HashMap::<String, (i64, usize)>::new();
+    if let Ok(data) = fs::read_to_string(dir.join("_partitions.json")) {
+      let meta: serde_json::Value =
+        serde_json::from_str(&data).unwrap_or_else(|e| panic!("Could not parse {:?}: {}", dir, e));
+      if let Some(meta) = meta.as_object() {
+        for (partition, meta) in meta.iter() {
+          let to_ts = meta["to_ts"].as_i64().unwrap_or(0);
+          let row_count = meta["row_count"].as_u64().unwrap_or(0) as usize;
+          partitions.insert(partition.clone(), (to_ts, row_count));
+        }
+      }
+    }
+
+    ParquetSink {
+      dir,
+      spec: spec_for(dataset),
+      partitions
+    }
+  }
+
+  fn write_partition(&mut self, batch: Batch) {
+    let ts = batch.timestamps();
+    let (from_ts, to_ts) = (ts[0], ts[ts.len() - 1]);
+    let partition = partition_name(&self.spec, from_ts);
+    let dir = self.dir.join(&partition);
+    fs::create_dir_all(&dir).unwrap_or_else(|e| panic!("Could not create {:?}: {}", dir, e));
+    // Rewriting the same rows after a crash replaces the file instead of duplicating them
+    write_parquet(&dir.join(format!("{}.parquet", from_ts)), &batch);
+
+    let meta = self.partitions.entry(partition).or_insert((to_ts, 0));
+    meta.0 = meta.0.max(to_ts);
+    meta.1 += batch.num_rows();
+  }
+}
+
+impl Sink for ParquetSink {
+  fn kind(&self) -> SinkKind { SinkKind::Parquet }
+
+  fn partition_to_ts(&self, partition: &str) -> Option<i64> {
+    self.partitions.get(partition).map(|meta| meta.0)
+  }
+
+  fn partition_row_count(&self, partition: &str) -> Option<usize> {
+    self.partitions.get(partition).map(|meta| meta.1)
+  }
+
+  fn append(&mut self, mut batch: Batch) {
+    metrics::rows_written(self.spec.name, batch.num_rows());
+    // Batches are labelled by whoever built them, so split any that span partitions
+    let mut parts = Vec::<Batch>::new();
+    while batch.num_rows() > 0 {
+      let ts = batch.timestamps();
+      let last = partition_name(&self.spec, ts[ts.len() - 1]);
+      let at = ts.iter().position(|ts| partition_name(&self.spec, *ts) == last).unwrap();
+      parts.push(batch.split_off(at));
+    }
+    for part in parts.into_iter().rev() {
+      self.write_partition(part);
+    }
+  }
+
+  fn drop_partition(&mut self, partition: &str) {
+    if self.partitions.remove(partition).is_some() {
+      let dir = self.dir.join(partition);
+      fs::remove_dir_all(&dir).unwrap_or_else(|e| panic!("Could not remove {:?}: {}", dir, e));
+      self.flush();
+    }
+  }
+
+  fn flush(&mut self) {
+    let meta = self
+      .partitions
+      .iter()
+      .map(|(partition, (to_ts, row_count))| {
+        (partition.clone(), serde_json::json!({ "to_ts": to_ts, "row_count": row_count }))
+      })
+      .collect::<serde_json::Map<_, _>>();
+    let path = self.dir.join("_partitions.json");
+    let tmp = self.dir.join("_partitions.json.tmp");
+    let data = serde_json::to_string_pretty(&meta).unwrap();
+    fs::write(&tmp, data)
+      .and_then(|_| fs::rename(&tmp, &path))
+      .unwrap_or_else(|e| panic!("Could not write {:?}: {}", path, e));
+  }
+}
+
+// Streams record batches to stdout or a file instead of writing to zdb
+pub struct StreamSink {
+  kind:  SinkKind,
+  out:   Option<Box<dyn Write>>,
+  arrow: Option<StreamWriter<Box<dyn Write>>>,
+  label: String
+}
+
+impl StreamSink {
+  // `dir` of None means stdout
+  pub fn open(kind: SinkKind, dataset: &str, dir: Option<&str>) -> StreamSink {
+    let out: Box<dyn Write> = match dir {
+      Some(dir) => {
+        fs::create_dir_all(dir).unwrap_or_else(|e| panic!("Could not create {}: {}", dir, e));
+        let extension = match kind {
+          SinkKind::ArrowIpc => "arrows",
+          _ => "ndjson"
+        };
+        let path = PathBuf::from(dir).join(format!("{}.{}", dataset, extension));
+        let file = File::create(&path).unwrap_or_else(|e| panic!("Could not create {:?}: {}", path, e));
+        Box::new(BufWriter::new(file))
+      }
+      None => Box::new(BufWriter::new(io::stdout()))
+    };
+
+    StreamSink {
+      kind,
+      out: Some(out),
+      arrow: None,
+      label: dataset.to_string()
+    }
+  }
+
+  fn check(&self, res: io::Result<()>) {
+    match res {
+      Ok(_) => {}
+      // Whatever we're piped into has seen enough
+      Err(e) if e.kind() == ErrorKind::BrokenPipe => process::exit(0),
+      Err(e) => {
+        error!(dataset = %self.label, error = %e, "Could not write stream");
+        process::exit(1);
+      }
+    }
+  }
+
+  pub fn write(&mut self, batch: &Batch) {
+    if batch.num_rows() == 0 {
+      return;
+    }
+    let res = match self.kind {
+      SinkKind::ArrowIpc => {
+        let record_batch = batch.to_record_batch();
+        if self.arrow.is_none() {
+          let out = self.out.take().unwrap();
+          let writer = StreamWriter::try_new(out, &record_batch.schema())
+            .expect("Could not create arrow stream writer");
+          self.arrow = Some(writer);
+        }
+        self
+          .arrow
+          .as_mut()
+          .unwrap()
+          .write(&record_batch)
+          .map_err(|e| io::Error::new(ErrorKind::Other, e.to_string()))
+      }
+      _ => {
+        let out = self.out.as_mut().unwrap();
+        write_ndjson(out, batch, TsFormat::Nanos).and_then(|_| out.flush())
+      }
+    };
+    self.check(res);
+  }
+}
+
+impl Sink for StreamSink {
+  fn kind(&self) -> SinkKind { self.kind }
+
+  fn is_stream(&self) -> bool { true }
+
+  // Streams don't remember anything between runs
+  fn partition_to_ts(&self, _partition: &str) -> Option<i64> { None }
+
+  fn partition_row_count(&self, _partition: &str) -> Option<usize> { None }
+
+  fn append(&mut self, batch: Batch) {
+    metrics::rows_written(&self.label, batch.num_rows());
+    self.write(&batch);
+  }
+
+  fn drop_partition(&mut self, _partition: &str) {}
+
+  // Every batch is flushed as it's written
+  fn flush(&mut self) {}
+
+  fn finish(&mut self) {
+    let res = match self.arrow.as_mut() {
+      Some(writer) => writer.finish().map_err(|e| io::Error::new(ErrorKind::Other, e.to_string())),
+      None => match self.out.as_mut() {
+        Some(out) => out.flush(),
+        None => Ok(())
+      }
+    };
+    self.check(res);
+  }
+}
+
+// `dir` is where Parquet and stream sinks write, with None meaning stdout for streams
+// `replace` is which savepoints to roll back, for downloaders that re-enter those partitions
+pub fn open_sink(
+  kind: SinkKind,
+  schema: Schema,
+  dataset: &str,
+  dir: Option<&str>,
+  replace: &[Savepoint]
+) -> Box<dyn Sink> {
+  match kind {
+    SinkKind::Zdb => Box::new(ZdbSink::open(schema, dataset, replace)),
+    SinkKind::Parquet => match dir {
+      Some(dir) => Box::new(ParquetSink::open(dataset, dir)),
+      None => {
+        error!("The parquet sink requires --sink-out");
+        process::exit(1);
+      }
+    },
+    _ => Box::new(StreamSink::open(kind, dataset, dir))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::batch::batch;
+  use chrono::NaiveDate;
+
+  fn day(y: i32, m: u32, d: u32) -> i64 {
+    NaiveDate::from_ymd(y, m, d).and_hms(0, 0, 0).timestamp_nanos()
+  }
+
+  #[test]
+  fn parquet_splits_partitions() {
+    let dir = std::env::temp_dir().join(format!("polyzdb-parquet-{}", process::id()));
+    fs::remove_dir_all(&dir).ok();
+    let dir_str = dir.to_str().unwrap();
+    let ts = vec![day(2020, 12, 30), day(2020, 12, 31), day(2021, 1, 4)];
+    let mut sink = ParquetSink::open("agg1d", dir_str);
+    sink.append(batch("2020-12-30", vec![
+      ("ts", Values::Timestamp(ts.clone())),
+      ("sym", Values::Str(vec!["A".to_string(), "B".to_string(), "C".to_string()])),
+      ("open", Values::F64(vec![1.0, 2.0, 3.0])),
+      ("high", Values::F64(vec![1.0, 2.0, 3.0])),
+      ("low", Values::F64(vec![1.0, 2.0, 3.0])),
+      ("close", Values::F64(vec![1.0, 2.0, 3.0])),
+      ("volume", Values::U64(vec![10, 20, 30])),
+    ]));
+    sink.flush();
+
+    let table = dir.join("agg1d");
+    assert!(table.join("2020").join(format!("{}.parquet", ts[0])).exists());
+    assert!(table.join("2021").join(format!("{}.parquet", ts[2])).exists());
+    assert_eq!(sink.partition_row_count("2020"), Some(2));
+    assert_eq!(sink.partition_row_count("2021"), Some(1));
+
+    // Resumes from _partitions.json
+    let sink = ParquetSink::open("agg1d", dir_str);
+    assert_eq!(sink.partition_to_ts("2020"), Some(ts[1]));
+    assert_eq!(sink.partition_to_ts("2021"), Some(ts[2]));
+    assert_eq!(sink.partition_to_ts("2019"), None);
+    fs::remove_dir_all(&dir).ok();
+  }
+}
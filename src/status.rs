@@ -0,0 +1,157 @@
+use crate::{
+  batch::{table_spec, ColumnKind},
+  compact::{manifest_summary, thaw_partition},
+  metrics::dir_size,
+  placement::data_dir,
+  util::MarketDays
+};
+use chrono::{Datelike, NaiveDate, Utc};
+use std::{
+  collections::{BTreeMap, BTreeSet, HashSet},
+  path::Path
+};
+use zdb::{calendar::ToNaiveDateTime, table::Table};
+
+pub const STATUS_TABLES: [&str; 7] =
+  ["agg1d", "agg1m", "trades", "quotes", "tickers", "ticker_events", "exchanges"];
+
+// Tables that should have rows on every market day
+const DAILY_TABLES: [&str; 5] = ["agg1d", "agg1m", "trades", "quotes", "tickers"];
+
+fn fmt_bytes(bytes: u64) -> String {
+  let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+  let mut size = bytes as f64;
+  let mut unit = 0;
+  while size >= 1024.0 && unit < units.len() - 1 {
+    size /= 1024.0;
+    unit += 1;
+  }
+  format!("{:.1} {}", size, units[unit])
+}
+
+// A row per month and a column per day of it: # has data, . is a market day without any and
+// blank is a day the market was closed
+fn print_heat_map(days: &BTreeSet<NaiveDate>, to: NaiveDate) {
+  let from = match days.iter().next() {
+    Some(from) => *from,
+    None => return
+  };
+  let mut months = BTreeMap::<(i32, u32), Vec<char>>::new();
+  let mut missing = 0;
+  for day in (MarketDays { from, to }) {
+    let row = months.entry((day.year(), day.month())).or_insert_with(|| vec![' '; 31]);
+    row[day.day0() as usize] = if days.contains(&day) {
+      '#'
+    } else {
+      missing += 1;
+      '.'
+    };
+  }
+  println!("  missing market days: {}", missing);
+  println!("          {}", (1..=31).map(|d| (b'0' + (d % 10) as u8) as char).collect::<String>());
+  for ((year, month), row) in months.iter() {
+    let num_missing = row.iter().filter(|c| **c == '.').count();
+    let row = row.iter().collect::<String>();
+    match num_missing {
+      0 => println!("  {}-{:02} {}", year, month, row),
+      n => println!("  {}-{:02} {} {} missing", year, month, row, n)
+    }
+  }
+}
+
+fn print_table(name: &str) {
+  let spec = table_spec(name).unwrap();
+  let mut table = match Table::open(name) {
+    Ok(table) => table,
+    Err(_) => {
+      println!("{}: does not exist", name);
+      return;
+    }
+  };
+  let sym = spec.columns.iter().find(|(name, _kind)| *name == "sym").map(|(_name, kind)| kind);
+
+  let row_count = table.partition_meta.values().map(|meta| meta.row_count).sum::<usize>();
+  println!("{}: {} partitions, {} rows", name, table.partition_meta.len(), row_count);
+  let mut dir_bytes = BTreeMap::<String, u64>::new();
+  for meta in table.partition_meta.values() {
+    *dir_bytes.entry(data_dir(&meta.dir)).or_insert(0) += dir_size(Path::new(&meta.dir));
+  }
+  for (data_dir, bytes) in dir_bytes.iter() {
+    println!("  {}: {}", data_dir, fmt_bytes(*bytes));
+  }
+
+  let mut days = BTreeSet::<NaiveDate>::new();
+  println!(
+    "  {:<10} {:<19} {:<19} {:>12} {:>8} {:>10} {:>10}",
+    "partition", "from", "to", "rows", "symbols", "size", "raw"
+  );
+  let mut columns = vec!["ts"];
+  if sym.is_some() {
+    columns.push("sym");
+  }
+  let mut partitions = table
+    .partition_meta
+    .iter()
+    .map(|(partition, meta)| (meta.to_ts, partition.clone()))
+    .collect::<Vec<_>>();
+  partitions.sort();
+  for (to_ts, partition_name) in partitions.iter() {
+    // Counts and sizes are in the metadata and manifest, so only the columns read below need
+    // decompressing, and only one partition's at a time
+    let row_count = table.partition_meta[partition_name].row_count;
+    let dir = table.partition_meta[partition_name].dir.clone();
+    let size = dir_size(Path::new(&dir));
+    let summary = manifest_summary(Path::new(&dir));
+    let _thawed = thaw_partition(&mut table, name, partition_name, &columns);
+    let partition = match table.partition_iter(*to_ts, *to_ts, columns.clone()).next() {
+      Some(partition) => partition,
+      None => continue
+    };
+    let ts = partition[0].get_i64();
+    let from_ts = match (summary, ts.first()) {
+      (Some((from_ts, _bytes)), _) => from_ts,
+      (None, Some(from_ts)) => *from_ts,
+      (None, None) => continue
+    };
+    let num_symbols = match sym {
+      Some(ColumnKind::Symbol8) => partition[1].get_u8().iter().collect::<HashSet<_>>().len(),
+      Some(_) => partition[1].get_u16().iter().collect::<HashSet<_>>().len(),
+      None => 0
+    };
+    if DAILY_TABLES.contains(&name) {
+      let mut last_day = None;
+      for ts in ts.iter() {
+        let day = ts.to_naive_date_time().date();
+        if last_day != Some(day) {
+          days.insert(day);
+          last_day = Some(day);
+        }
+      }
+    }
+    println!(
+      "  {:<10} {:<19} {:<19} {:>12} {:>8} {:>10} {:>10}",
+      partition_name,
+      from_ts.to_naive_date_time().format("%Y-%m-%d %H:%M:%S").to_string(),
+      to_ts.to_naive_date_time().format("%Y-%m-%d %H:%M:%S").to_string(),
+      row_count,
+      num_symbols,
+      fmt_bytes(size),
+      summary.map(|(_from_ts, bytes)| fmt_bytes(bytes)).unwrap_or_default()
+    );
+  }
+
+  if DAILY_TABLES.contains(&name) {
+    // Up to but excluding today, which isn't over
+    print_heat_map(&days, Utc::now().naive_utc().date());
+  }
+}
+
+// Prints each table's partitions and which market days it's missing
+pub fn print_status(tables: Vec<&str>) {
+  for (i, name) in tables.iter().enumerate() {
+    if i > 0 {
+      println!();
+    }
+    print_table(name);
+  }
+}
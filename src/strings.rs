@@ -0,0 +1,184 @@
+use crate::util::table_dir;
+use std::{
+  collections::HashMap,
+  convert::TryInto,
+  fs::{self, File, OpenOptions},
+  io::{self, Seek, SeekFrom, Write},
+  path::PathBuf
+};
+
+// zdb symbol columns are capped at 255 or 65535 distinct values per table, which free text
+// like company names outgrows over years of daily snapshots. A heap stores each distinct
+// string once as a little-endian u32 length followed by its UTF-8 bytes, and the table
+// stores the U64 offset of the string in the heap instead.
+pub struct StringHeap {
+  path:    PathBuf,
+  file:    File,
+  offsets: HashMap<String, u64>,
+  len:     u64
+}
+
+fn heap_path(table: &str, column: &str) -> PathBuf {
+  table_dir(table).join(format!("{}.strings", column))
+}
+
+fn parse_heap(data: &[u8]) -> Vec<(u64, String)> {
+  let mut res = Vec::<(u64, String)>::new();
+  let mut offset = 0;
+  while offset + 4 <= data.len() {
+    let len = u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap()) as usize;
+    let start = offset + 4;
+    // A torn write at the end of the heap is never referenced by the table
+    if start + len > data.len() {
+      break;
+    }
+    let string = String::from_utf8_lossy(&data[start..start + len]).to_string();
+    res.push((offset as u64, string));
+    offset = start + len;
+  }
+
+  res
+}
+
+impl StringHeap {
+  pub fn open(table: &str, column: &str) -> io::Result<StringHeap> {
+    let path = heap_path(table, column);
+    fs::create_dir_all(path.parent().unwrap())?;
+    let data = match fs::read(&path) {
+      Ok(data) => data,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+      Err(e) => return Err(e)
+    };
+    let strings = parse_heap(&data);
+    let len = match strings.last() {
+      Some((offset, string)) => offset + 4 + string.len() as u64,
+      None => 0
+    };
+    let file = OpenOptions::new().create(true).write(true).open(&path)?;
+    // Drop any torn write so new strings start at a known offset
+    file.set_len(len)?;
+
+    Ok(StringHeap {
+      path,
+      file,
+      offsets: strings.into_iter().map(|(offset, s)| (s, offset)).collect(),
+      len
+    })
+  }
+
+  // Returns the offset of `s`, appending it if it's new
+  pub fn put(&mut self, s: &str) -> u64 {
+    if let Some(offset) = self.offsets.get(s) {
+      return *offset;
+    }
+    let offset = self.len;
+    let bytes = s.as_bytes();
+    let mut buf = Vec::with_capacity(4 + bytes.len());
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+    self.file.seek(SeekFrom::Start(offset)).expect("Could not seek string heap");
+    self
+      .file
+      .write_all(&buf)
+      .unwrap_or_else(|e| panic!("Could not write to {:?}: {}", self.path, e));
+    self.len += buf.len() as u64;
+    self.offsets.insert(s.to_string(), offset);
+
+    offset
+  }
+
+  // Must be called before flushing the table that references these offsets
+  pub fn flush(&mut self) {
+    self
+      .file
+      .sync_all()
+      .unwrap_or_else(|e| panic!("Could not sync {:?}: {}", self.path, e));
+  }
+
+  pub fn len(&self) -> usize { self.offsets.len() }
+}
+
+pub struct StringHeapReader {
+  strings: HashMap<u64, String>
+}
+
+impl StringHeapReader {
+  pub fn open(table: &str, column: &str) -> io::Result<StringHeapReader> {
+    let data = match fs::read(heap_path(table, column)) {
+      Ok(data) => data,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+      Err(e) => return Err(e)
+    };
+
+    Ok(StringHeapReader {
+      strings: parse_heap(&data).into_iter().collect()
+    })
+  }
+
+  // Returns None instead of panicking on an offset that doesn't start a string, like one past
+  // a heap that lost its last writes
+  pub fn get(&self, offset: u64) -> Option<&str> { self.strings.get(&offset).map(String::as_str) }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::util::test_data_root;
+
+  fn entry(s: &str) -> Vec<u8> {
+    let mut res = (s.len() as u32).to_le_bytes().to_vec();
+    res.extend_from_slice(s.as_bytes());
+    res
+  }
+
+  #[test]
+  fn parse() {
+    let mut data = entry("Apple Inc.");
+    data.extend(entry(""));
+    data.extend(entry("Microsoft"));
+    assert_eq!(parse_heap(&data), vec![
+      (0, "Apple Inc.".to_string()),
+      (14, String::new()),
+      (18, "Microsoft".to_string()),
+    ]);
+  }
+
+  #[test]
+  fn parse_torn() {
+    let mut data = entry("Apple Inc.");
+    let torn = entry("Microsoft");
+    data.extend_from_slice(&torn[..torn.len() - 1]);
+    assert_eq!(parse_heap(&data), vec![(0, "Apple Inc.".to_string())]);
+    // Not even a whole length
+    assert_eq!(parse_heap(&data[..16]), vec![(0, "Apple Inc.".to_string())]);
+  }
+
+  #[test]
+  fn put_and_get() {
+    test_data_root();
+    fs::remove_dir_all(table_dir("strings_test")).ok();
+    let mut heap = StringHeap::open("strings_test", "name").unwrap();
+    let apple = heap.put("Apple Inc.");
+    let msft = heap.put("Microsoft");
+    assert_eq!(heap.put("Apple Inc."), apple);
+    heap.flush();
+    drop(heap);
+
+    // Reopening truncates a torn write and appends after the last whole string
+    let path = heap_path("strings_test", "name");
+    let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(&entry("Torn")[..6]).unwrap();
+    drop(file);
+    let mut heap = StringHeap::open("strings_test", "name").unwrap();
+    assert_eq!(heap.len(), 2);
+    let tesla = heap.put("Tesla");
+    heap.flush();
+
+    let reader = StringHeapReader::open("strings_test", "name").unwrap();
+    assert_eq!(reader.get(apple), Some("Apple Inc."));
+    assert_eq!(reader.get(msft), Some("Microsoft"));
+    assert_eq!(reader.get(tesla), Some("Tesla"));
+    assert_eq!(reader.get(apple + 1), None);
+    assert_eq!(reader.get(u64::MAX), None);
+  }
+}
@@ -0,0 +1,239 @@
+use crate::{compact::thaw_partition, util::table_dir};
+use std::{
+  collections::HashSet,
+  fs,
+  path::Path,
+  process
+};
+use tracing::{error, info, warn};
+use zdb::{
+  calendar::ToNaiveDateTime,
+  table::Table
+};
+
+// Symbol indexes are 1-based so 0 is never a valid symbol
+pub const SYMBOL8_CAPACITY: usize = u8::MAX as usize;
+pub const SYMBOL16_CAPACITY: usize = u16::MAX as usize;
+
+// Tables whose "sym" column uses the shared us_equities dictionary
+pub const US_EQUITIES_TABLES: [&str; 6] =
+  ["agg1d", "agg1m", "trades", "quotes", "tickers", "ticker_events"];
+
+// Returns None instead of panicking on index 0 or an index past the end of the dictionary
+pub fn lookup(symbols: &[String], index: usize) -> Option<&String> {
+  if index == 0 {
+    return None;
+  }
+  symbols.get(index - 1)
+}
+
+// The dictionary of a symbol column as of its table's latest partition, which may be compacted
+fn latest_dictionary(name: &str, column: &str) -> Vec<String> {
+  let mut table = match Table::open(name) {
+    Ok(table) => table,
+    Err(_) => return Vec::new()
+  };
+  let (latest, partition) = match table.partition_meta.iter().max_by_key(|(_p, meta)| meta.to_ts) {
+    Some((partition, meta)) => (meta.to_ts, partition.clone()),
+    None => return Vec::new()
+  };
+  let _thawed = thaw_partition(&mut table, name, &partition, &[column]);
+  match table.partition_iter(latest, latest, vec![column]).next() {
+    Some(partition) => partition[0].symbols.clone(),
+    None => Vec::new()
+  }
+}
+
+// Returns the dictionary of a symbol column. The us_equities dictionary is shared, so an empty
+// table's "sym" still starts with every symbol the other tables added.
+pub fn load_dictionary(name: &str, column: &str) -> Vec<String> {
+  if column != "sym" || !US_EQUITIES_TABLES.contains(&name) {
+    return latest_dictionary(name, column);
+  }
+  // Dictionaries only ever grow, so the longest is the latest
+  US_EQUITIES_TABLES
+    .iter()
+    .map(|name| latest_dictionary(name, column))
+    .max_by_key(|dictionary| dictionary.len())
+    .unwrap_or_default()
+}
+
+// Exits before writing `values` would overflow `column`'s `dictionary` instead of letting the
+// indexes wrap and silently point at the wrong symbols. Adds the new symbols to `dictionary`.
+pub fn check_capacity<'a, I>(
+  dictionary: &mut HashSet<String>,
+  column: &str,
+  capacity: usize,
+  values: I
+) where
+  I: Iterator<Item = &'a str>
+{
+  let new_symbols = values.filter(|v| !dictionary.contains(*v)).collect::<HashSet<_>>();
+  let total = dictionary.len() + new_symbols.len();
+  if total > capacity {
+    error!(
+      column,
+      "{} new symbols would overflow its dictionary ({} + {} > {}). Store this column in a StringHeap or a wider symbol type.",
+      new_symbols.len(),
+      dictionary.len(),
+      new_symbols.len(),
+      capacity
+    );
+    process::exit(1);
+  }
+  if total > capacity / 10 * 9 {
+    warn!(column, "Dictionary is {} / {} full", total, capacity);
+  }
+  dictionary.extend(new_symbols.into_iter().map(String::from));
+}
+
+// The symbols rows reference in the order the tables first do, which is a dictionary without
+// the unused or orphaned ones
+#[derive(Default)]
+struct Rebuilt {
+  symbols: Vec<String>,
+  seen:    HashSet<String>
+}
+
+impl Rebuilt {
+  fn add(&mut self, sym: &str) {
+    if !self.seen.contains(sym) {
+      self.seen.insert(sym.to_string());
+      self.symbols.push(sym.to_string());
+    }
+  }
+
+  fn write(&self, path: &Path) {
+    match fs::write(path, self.symbols.join("\n")) {
+      Ok(_) => info!(?path, "Wrote {} symbols", self.symbols.len()),
+      Err(e) => {
+        error!(?path, error = %e, "Could not write rebuilt dictionary");
+        process::exit(1);
+      }
+    }
+  }
+}
+
+// Validates every us_equities table's symbol indexes against the shared dictionary. Returns
+// whether every index was valid. With `rebuild` also writes a dictionary of only the
+// referenced symbols to us_equities.rebuilt.
+pub fn check_symbols(rebuild: bool) -> bool {
+  let mut ok = true;
+  let mut dictionary = Vec::<String>::new();
+  let mut used = vec![false; SYMBOL16_CAPACITY + 1];
+  let mut rebuilt = Rebuilt::default();
+
+  for name in US_EQUITIES_TABLES.iter() {
+    let mut table = match Table::open(name) {
+      Ok(table) => table,
+      Err(_) => {
+        info!(table = name, "Table does not exist, skipping");
+        continue;
+      }
+    };
+    let mut partitions = table
+      .partition_meta
+      .iter()
+      .map(|(partition, meta)| (meta.to_ts, partition.clone()))
+      .collect::<Vec<_>>();
+    partitions.sort();
+    let mut num_rows = 0;
+    let mut num_bad = 0;
+    for (to_ts, partition_name) in partitions.iter() {
+      // One at a time so only one compacted partition is ever decompressed
+      let _thawed = thaw_partition(&mut table, name, partition_name, &["ts", "sym"]);
+      let partition = match table.partition_iter(*to_ts, *to_ts, vec!["ts", "sym"]).next() {
+        Some(partition) => partition,
+        None => continue
+      };
+      let symbols = &partition[1].symbols;
+      // Dictionaries only ever grow, so a shorter one must be a prefix of a longer one
+      if let Some(i) = dictionary.iter().zip(symbols.iter()).position(|(s1, s2)| s1 != s2) {
+        error!(
+          table = name,
+          "Dictionary disagrees at index {}: {} != {}",
+          i + 1,
+          symbols[i],
+          dictionary[i]
+        );
+        ok = false;
+      }
+      if symbols.len() > dictionary.len() {
+        dictionary = symbols.clone();
+      }
+
+      let ts = partition[0].get_i64();
+      for (i, sym_i) in partition[1].get_u16().iter().enumerate() {
+        num_rows += 1;
+        match lookup(symbols, *sym_i as usize) {
+          Some(sym) => {
+            used[*sym_i as usize] = true;
+            if rebuild {
+              rebuilt.add(sym);
+            }
+          }
+          None => {
+            if num_bad < 10 {
+              error!(
+                table = name,
+                ts = %ts[i].to_naive_date_time(),
+                "Bad symbol index {} (dictionary has {} symbols)",
+                sym_i,
+                symbols.len()
+              );
+            }
+            num_bad += 1;
+          }
+        }
+      }
+    }
+    if num_bad > 0 {
+      ok = false;
+    }
+    println!("{:13} {:12} rows {:8} bad indexes", name, num_rows, num_bad);
+  }
+
+  let unused = dictionary
+    .iter()
+    .enumerate()
+    .filter(|(i, _sym)| !used[i + 1])
+    .map(|(_i, sym)| sym.as_str())
+    .collect::<Vec<_>>();
+  println!(
+    "us_equities: {} / {} symbols used, {} unused",
+    dictionary.len() - unused.len(),
+    dictionary.len(),
+    unused.len()
+  );
+  if unused.len() > 0 {
+    warn!("Unused symbols: {}", unused.iter().take(20).cloned().collect::<Vec<_>>().join(" "));
+  }
+  if rebuild {
+    rebuilt.write(&table_dir("us_equities").with_extension("rebuilt"));
+  }
+
+  ok
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn rebuilds_in_reference_order() {
+    let dictionary =
+      ["AAPL", "MSFT", "ORPHAN", "TSLA"].iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    let mut rebuilt = Rebuilt::default();
+    for sym_i in [4, 1, 0, 4, 9, 2, 1].iter() {
+      if let Some(sym) = lookup(&dictionary, *sym_i) {
+        rebuilt.add(sym);
+      }
+    }
+    assert_eq!(rebuilt.symbols, vec!["TSLA", "AAPL", "MSFT"]);
+
+    let path = std::env::temp_dir().join(format!("polyzdb-rebuilt-{}", process::id()));
+    rebuilt.write(&path);
+    assert_eq!(fs::read_to_string(&path).unwrap(), "TSLA\nAAPL\nMSFT");
+    fs::remove_file(&path).ok();
+  }
+}
@@ -0,0 +1,357 @@
+use crate::{
+  batch::{batch, table_spec, Values},
+  layout::check_layout,
+  sink::{open_sink, SinkKind},
+  strings::StringHeapReader,
+  symbols::lookup
+};
+use chrono::{Duration, NaiveDate};
+use std::{
+  collections::{BTreeSet, HashMap, HashSet},
+  time::Instant
+};
+use tracing::{debug, error, info, info_span};
+use zdb::{
+  calendar::ToNaiveDateTime,
+  schema::Schema,
+  table::Table
+};
+
+const TICKER_COLS: [&str; 8] = [
+  "ts",
+  "sym",
+  "name",
+  "primary_exchange",
+  "type",
+  "cik",
+  "composite_figi",
+  "share_class_figi"
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TickerSnapshot {
+  pub name:             String,
+  pub primary_exchange: String,
+  pub r#type:           String,
+  pub cik:              String,
+  pub composite_figi:   String,
+  pub share_class_figi: String
+}
+
+type Snapshot = HashMap<String, TickerSnapshot>;
+
+struct TickerEvent {
+  sym:   String,
+  event: &'static str,
+  old:   String,
+  new:   String
+}
+
+pub fn ticker_events_schema() -> Schema { table_spec("ticker_events").unwrap().schema(Vec::new()) }
+
+// Calls `f` once per snapshot day in [from_ts, to_ts] with every ticker listed that day.
+// Relies on tickers being written sorted by (ts, sym).
+fn for_each_snapshot<F>(tickers: &Table, from_ts: i64, to_ts: i64, mut f: F)
+where
+  F: FnMut(i64, Snapshot)
+{
+  let mut cur_ts: Option<i64> = None;
+  let mut cur = Snapshot::new();
+  let heap = |col: &str| StringHeapReader::open("tickers", col).expect("Could not open string heap");
+  let name_heap = heap("name");
+  let cik_heap = heap("cik");
+  let composite_figi_heap = heap("composite_figi");
+  let share_class_figi_heap = heap("share_class_figi");
+  let get = |heap: &StringHeapReader, offset: u64| heap.get(offset).unwrap_or("").to_string();
+  let partitions = tickers.partition_iter(from_ts, to_ts, TICKER_COLS.to_vec());
+  for partition in partitions {
+    let sym16 = |col: usize| -> Vec<String> {
+      partition[col]
+        .get_u16()
+        .iter()
+        .map(|sym_i| lookup(&partition[col].symbols, *sym_i as usize).cloned().unwrap_or(String::new()))
+        .collect()
+    };
+    let sym8 = |col: usize| -> Vec<String> {
+      partition[col]
+        .get_u8()
+        .iter()
+        .map(|sym_i| lookup(&partition[col].symbols, *sym_i as usize).cloned().unwrap_or(String::new()))
+        .collect()
+    };
+    let ts = partition[0].get_i64();
+    let syms = sym16(1);
+    let names = partition[2].get_u64();
+    let primary_exchanges = sym8(3);
+    let types = sym8(4);
+    let ciks = partition[5].get_u64();
+    let composite_figis = partition[6].get_u64();
+    let share_class_figis = partition[7].get_u64();
+    for i in 0..ts.len() {
+      if cur_ts != Some(ts[i]) {
+        if let Some(day) = cur_ts {
+          f(day, std::mem::take(&mut cur));
+        }
+        cur_ts = Some(ts[i]);
+      }
+      cur.insert(syms[i].clone(), TickerSnapshot {
+        name:             get(&name_heap, names[i]),
+        primary_exchange: primary_exchanges[i].clone(),
+        r#type:           types[i].clone(),
+        cik:              get(&cik_heap, ciks[i]),
+        composite_figi:   get(&composite_figi_heap, composite_figis[i]),
+        share_class_figi: get(&share_class_figi_heap, share_class_figis[i])
+      });
+    }
+  }
+  if let Some(day) = cur_ts {
+    f(day, cur);
+  }
+}
+
+fn diff_snapshots(prev: &Snapshot, cur: &Snapshot) -> Vec<TickerEvent> {
+  let mut res = Vec::<TickerEvent>::new();
+
+  // A FIGI that disappears under one symbol and shows up under another is a ticker change,
+  // not a delisting plus a new listing. Several symbols can share a FIGI, so each disappeared
+  // (figi, sym) pair is matched at most once.
+  let mut delisted = BTreeSet::<(&str, &str)>::new();
+  for (sym, prev_ticker) in prev.iter() {
+    if !cur.contains_key(sym) && !prev_ticker.composite_figi.is_empty() {
+      delisted.insert((&prev_ticker.composite_figi, sym));
+    }
+  }
+  let mut renamed_syms = HashSet::<&str>::new();
+
+  let mut cur_syms = cur.iter().collect::<Vec<_>>();
+  cur_syms.sort_unstable_by(|t1, t2| t1.0.cmp(t2.0));
+  for (sym, ticker) in cur_syms {
+    match prev.get(sym) {
+      None => {
+        let figi = ticker.composite_figi.as_str();
+        let old = if figi.is_empty() {
+          None
+        } else {
+          delisted.range((figi, "")..).next().filter(|(f, _)| *f == figi).cloned()
+        };
+        match old {
+          Some((figi, old_sym)) => {
+            delisted.remove(&(figi, old_sym));
+            renamed_syms.insert(old_sym);
+            res.push(TickerEvent {
+              sym:   sym.clone(),
+              event: "symbol_changed",
+              old:   old_sym.to_string(),
+              new:   sym.clone()
+            });
+          }
+          None => res.push(TickerEvent {
+            sym:   sym.clone(),
+            event: "listed",
+            old:   String::new(),
+            new:   sym.clone()
+          })
+        }
+      }
+      Some(prev_ticker) => {
+        let mut changed = |event: &'static str, old: &String, new: &String| {
+          if old != new {
+            res.push(TickerEvent {
+              sym: sym.clone(),
+              event,
+              old: old.clone(),
+              new: new.clone()
+            });
+          }
+        };
+        changed("renamed", &prev_ticker.name, &ticker.name);
+        changed("figi_changed", &prev_ticker.composite_figi, &ticker.composite_figi);
+        changed("share_class_figi_changed", &prev_ticker.share_class_figi, &ticker.share_class_figi);
+        changed("cik_changed", &prev_ticker.cik, &ticker.cik);
+        changed("exchange_moved", &prev_ticker.primary_exchange, &ticker.primary_exchange);
+        changed("type_changed", &prev_ticker.r#type, &ticker.r#type);
+      }
+    }
+  }
+
+  for sym in prev.keys() {
+    if !cur.contains_key(sym) && !renamed_syms.contains(sym.as_str()) {
+      res.push(TickerEvent {
+        sym:   sym.clone(),
+        event: "delisted",
+        old:   sym.clone(),
+        new:   String::new()
+      });
+    }
+  }
+
+  res.sort_unstable_by(|e1, e2| e1.sym.cmp(&e2.sym).then(e1.event.cmp(e2.event)));
+  res
+}
+
+pub fn build_ticker_events() {
+  let now = Instant::now();
+  let _span = info_span!("build", dataset = "ticker_events").entered();
+  check_layout("tickers");
+  let tickers = Table::open("tickers").expect("Table tickers must exist to build ticker_events");
+  // Through a sink for its journal, heaps and dictionary checks
+  let mut ticker_events =
+    open_sink(SinkKind::Zdb, ticker_events_schema(), "ticker_events", None, &[]);
+
+  // Events for the last day we wrote are already in the table, so that day's snapshot only
+  // serves as the baseline for the next one.
+  let last_ts = Table::open("ticker_events")
+    .ok()
+    .and_then(|table| table.partition_meta.values().map(|meta| meta.to_ts).max());
+  let from_ts = last_ts.unwrap_or(0);
+  info!("Building from {}", from_ts.to_naive_date_time().date());
+
+  // On the first build every ticker in the first snapshot is a listing
+  let mut prev: Option<Snapshot> = match last_ts {
+    Some(_) => None,
+    None => Some(Snapshot::new())
+  };
+  let mut events = Vec::<(i64, TickerEvent)>::new();
+  for_each_snapshot(&tickers, from_ts, i64::MAX, |ts, cur| {
+    if let Some(prev) = &prev {
+      events.extend(diff_snapshots(prev, &cur).into_iter().map(|e| (ts, e)));
+    }
+    prev = Some(cur);
+  });
+  let num_events = events.len();
+  debug!("Writing {} ticker_events", num_events);
+  let mut ts = Vec::with_capacity(num_events);
+  let mut syms = Vec::with_capacity(num_events);
+  let mut kinds = Vec::with_capacity(num_events);
+  let mut olds = Vec::with_capacity(num_events);
+  let mut news = Vec::with_capacity(num_events);
+  for (event_ts, e) in events {
+    ts.push(event_ts);
+    syms.push(e.sym);
+    kinds.push(e.event.to_string());
+    olds.push(e.old);
+    news.push(e.new);
+  }
+  ticker_events.append(batch("ticker_events", vec![
+    ("ts", Values::Timestamp(ts)),
+    ("sym", Values::Str(syms)),
+    ("event", Values::Str(kinds)),
+    ("old", Values::Str(olds)),
+    ("new", Values::Str(news)),
+  ]));
+  debug!("Flushing {} ticker_events", num_events);
+  ticker_events.flush();
+
+  info!("Built in {}s", now.elapsed().as_secs());
+}
+
+// Returns every ticker listed as of `date` using the latest snapshot on or before it.
+// Snapshots only exist on market days, so look back far enough to cover long weekends.
+pub fn universe(tickers: &Table, date: NaiveDate) -> Snapshot {
+  let from_ts = (date - Duration::days(7)).and_hms(0, 0, 0).timestamp_nanos();
+  let to_ts = date.and_hms(0, 0, 0).timestamp_nanos();
+  let mut res = Snapshot::new();
+  for_each_snapshot(tickers, from_ts, to_ts, |_ts, cur| res = cur);
+
+  res
+}
+
+pub fn print_universe(date: NaiveDate, types: Vec<&str>) {
+  check_layout("tickers");
+  let tickers = Table::open("tickers").expect("Table tickers must exist to query universe");
+  let listed = universe(&tickers, date);
+  if listed.len() == 0 {
+    error!(%date, "No tickers snapshot");
+    return;
+  }
+  let mut syms = listed
+    .into_iter()
+    .filter(|(_sym, ticker)| types.len() == 0 || types.contains(&ticker.r#type.as_str()))
+    .collect::<Vec<_>>();
+  syms.sort_unstable_by(|t1, t2| t1.0.cmp(&t2.0));
+  info!(%date, "{} tickers", syms.len());
+  for (sym, ticker) in syms {
+    println!("{}\t{}\t{}\t{}", sym, ticker.r#type, ticker.primary_exchange, ticker.composite_figi);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn ticker(name: &str, figi: &str) -> TickerSnapshot {
+    TickerSnapshot {
+      name:             name.to_string(),
+      primary_exchange: "XNAS".to_string(),
+      r#type:           "CS".to_string(),
+      cik:              String::new(),
+      composite_figi:   figi.to_string(),
+      share_class_figi: String::new()
+    }
+  }
+
+  fn snapshot(tickers: &[(&str, TickerSnapshot)]) -> Snapshot {
+    tickers.iter().map(|(sym, t)| (sym.to_string(), t.clone())).collect()
+  }
+
+  type Event = (String, &'static str, String, String);
+
+  fn events(prev: &Snapshot, cur: &Snapshot) -> Vec<Event> {
+    diff_snapshots(prev, cur).into_iter().map(|e| (e.sym, e.event, e.old, e.new)).collect()
+  }
+
+  fn event(sym: &str, event: &'static str, old: &str, new: &str) -> Event {
+    (sym.to_string(), event, old.to_string(), new.to_string())
+  }
+
+  #[test]
+  fn first_snapshot_lists_everything() {
+    let cur = snapshot(&[("AAPL", ticker("Apple", "F1")), ("MSFT", ticker("Microsoft", "F2"))]);
+    assert_eq!(events(&Snapshot::new(), &cur), vec![
+      event("AAPL", "listed", "", "AAPL"),
+      event("MSFT", "listed", "", "MSFT"),
+    ]);
+  }
+
+  #[test]
+  fn changes() {
+    let prev = snapshot(&[("FB", ticker("Facebook", "F1")), ("X", ticker("X Corp", "F2"))]);
+    let mut renamed = ticker("X Corporation", "F2");
+    renamed.primary_exchange = "XNYS".to_string();
+    let cur = snapshot(&[("META", ticker("Meta", "F1")), ("X", renamed)]);
+    assert_eq!(events(&prev, &cur), vec![
+      event("META", "symbol_changed", "FB", "META"),
+      event("X", "exchange_moved", "XNAS", "XNYS"),
+      event("X", "renamed", "X Corp", "X Corporation"),
+    ]);
+  }
+
+  #[test]
+  fn delisted() {
+    let prev = snapshot(&[("A", ticker("A", "F1")), ("B", ticker("B", ""))]);
+    let cur = snapshot(&[("A", ticker("A", "F1"))]);
+    assert_eq!(events(&prev, &cur), vec![event("B", "delisted", "B", "")]);
+  }
+
+  #[test]
+  fn shared_figi() {
+    // Both symbols of a FIGI disappear but only one comes back under a new symbol
+    let prev =
+      snapshot(&[("BRK.A", ticker("Berkshire", "F1")), ("BRK.B", ticker("Berkshire", "F1"))]);
+    let cur = snapshot(&[("BRK", ticker("Berkshire", "F1"))]);
+    assert_eq!(events(&prev, &cur), vec![
+      event("BRK", "symbol_changed", "BRK.A", "BRK"),
+      event("BRK.B", "delisted", "BRK.B", ""),
+    ]);
+  }
+
+  #[test]
+  fn empty_figis_never_match() {
+    let prev = snapshot(&[("A", ticker("A", ""))]);
+    let cur = snapshot(&[("B", ticker("B", ""))]);
+    assert_eq!(events(&prev, &cur), vec![
+      event("A", "delisted", "A", ""),
+      event("B", "listed", "", "B"),
+    ]);
+  }
+}
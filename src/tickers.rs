@@ -1,138 +1,172 @@
-use crate::util::MarketDays;
-use chrono::{Datelike, Duration, NaiveDate, Utc, FixedOffset, TimeZone};
+use crate::{
+  auth::Clients,
+  batch::{batch, table_spec, Values},
+  config::DatasetConfig,
+  logging::Progress,
+  metrics,
+  report::{self, Phase},
+  retry::{give_up, retry},
+  shutdown,
+  sink::Sink,
+  util::MarketDays
+};
+use chrono::{Datelike, Duration, NaiveDate, Utc};
 use polygon_io::{
-  client::Client,
   reference::tickers::Ticker
 };
 use std::{
-  cmp, process,
+  cmp,
   sync::{Arc, Mutex},
   time::Instant
 };
 use threadpool::ThreadPool;
+use tracing::{debug, info, info_span, Span};
 use zdb::{
   calendar::ToNaiveDateTime,
-  schema::{Column, ColumnType, PartitionBy, Schema},
-  table::Table
+  schema::Schema
 };
-use std::sync::atomic::{AtomicUsize, Ordering};
 
 fn download_tickers_year(
   year: i32,
   thread_pool: &ThreadPool,
-  tickers: &mut Table,
-  client: &mut Client
+  tickers: &mut dyn Sink,
+  clients: &Clients,
+  config: &DatasetConfig
 ) {
   let now = Instant::now();
-  let from = match tickers.partition_meta.get(&year.to_string()) {
-    Some(meta) => meta.to_ts.to_naive_date_time().date() + Duration::days(1),
+  let _span = info_span!("partition", partition = year).entered();
+  metrics::set_partition("tickers", &year.to_string());
+  let from = match tickers.partition_to_ts(&year.to_string()) {
+    Some(to_ts) => to_ts.to_naive_date_time().date() + Duration::days(1),
     None => NaiveDate::from_ymd(year, 1, 1)
   };
+  let from = cmp::max(from, config.from);
   let to = cmp::min(
     NaiveDate::from_ymd(year + 1, 1, 1),
-    Utc::now().naive_utc().date()
+    config.until(Utc::now().naive_utc().date())
   );
   let market_days = (MarketDays { from, to }).collect::<Vec<_>>();
   if from >= to || market_days.len() == 0 {
-    eprintln!("Already downloaded tickers until {}!", from - Duration::days(1));
+    info!("Already downloaded until {}", from - Duration::days(1));
     return;
   }
 
-  let tickers_year = Arc::new(Mutex::new(Vec::<Ticker>::new()));
-  eprintln!("Downloading tickers in {}..{}", from, to);
-  let market_days = (MarketDays { from, to }).collect::<Vec<_>>();
-  let num_days = market_days.len();
-  let counter = Arc::new(AtomicUsize::new(0));
-  eprintln!("{:3} / {} days", 0, num_days);
+  // Tickers are snapshots, so each is stored alongside the market day it was listed on
+  let tickers_year = Arc::new(Mutex::new(Vec::<(NaiveDate, Ticker)>::new()));
+  info!("Downloading {}..{}", from, to);
+  let fetch_start = Instant::now();
+  let progress = Progress::new("days", market_days.len());
+  let span = Span::current();
   for day in market_days.into_iter() {
     let tickers_year = Arc::clone(&tickers_year);
-    let mut client = client.clone();
-    let counter = counter.clone();
+    let mut client = clients.next();
+    let progress = progress.clone();
+    let span = span.clone();
+    let policy = config.retry;
     thread_pool.execute(move || {
-      // Retry up to 10 times
-      for j in 0..10 {
-        match client.get_all_tickers(&day) {
-          Ok(mut results) => {
-            for ticker in results.iter_mut() {
-              // Hijack this mostly useless field to put current date
-              ticker.last_updated_utc = FixedOffset::east(0)
-                .ymd(day.year(), day.month(), day.day())
-                .and_hms(0, 0, 0);
-              assert!(ticker.last_updated_utc.timestamp_nanos() == day.and_hms(0, 0, 0).timestamp_nanos());
-            }
-            tickers_year.lock().unwrap().append(&mut results);
-            counter.fetch_add(1, Ordering::Relaxed);
-            println!("\x1b[1A\x1b[K{:3} / {} days [{}]", counter.load(Ordering::Relaxed), num_days, day);
-            return;
-          }
-          Err(e) => {
-            eprintln!("{}: get_all_tickers retry {}: {}\n", day, j + 1, e.to_string());
-            std::thread::sleep(std::time::Duration::from_secs(j + 1));
-          }
+      let _span = info_span!(parent: &span, "fetch", %day).entered();
+      if shutdown::requested() {
+        return;
+      }
+      match retry("tickers", "get_all_tickers", &policy, || client.get_all_tickers(&day)) {
+        Ok(results) => {
+          tickers_year
+            .lock()
+            .unwrap()
+            .extend(results.into_iter().map(|ticker| (day, ticker)));
+          progress.inc(&day.to_string());
         }
+        Err(e) => give_up("tickers", "get_all_tickers", &e)
       }
-      eprintln!("{}: failure", &day);
-      process::exit(1);
     });
   }
   thread_pool.join();
+  report::phase("tickers", Phase::Fetch, fetch_start.elapsed());
+  // Writing part of a partition would leave gaps that look downloaded
+  if !progress.is_done() {
+    shutdown::discard("tickers", &year.to_string());
+    return;
+  }
 
   // Sort by ts, symbol
   let mut tickers_year = tickers_year.lock().unwrap();
   let num_candles = tickers_year.len();
-  eprintln!("{}: Sorting {} tickers", year, num_candles);
-  tickers_year.sort_unstable_by(|c1, c2| {
-    if c1.last_updated_utc == c2.last_updated_utc {
-      c1.symbol.cmp(&c2.symbol)
-    } else {
-      c1.last_updated_utc.cmp(&c2.last_updated_utc)
-    }
+  debug!("Sorting {} tickers", num_candles);
+  report::timed("tickers", Phase::Sort, || {
+    tickers_year.sort_unstable_by(|(d1, c1), (d2, c2)| {
+      if d1 == d2 {
+        c1.symbol.cmp(&c2.symbol)
+      } else {
+        d1.cmp(d2)
+      }
+    })
   });
-  eprintln!("{}: Writing {} tickers", year, num_candles);
-  for c in tickers_year.drain(..) {
-    tickers.put_timestamp(c.last_updated_utc.timestamp_nanos());
-    tickers.put_symbol(c.symbol);
-    tickers.put_symbol(c.name);
-    tickers.put_symbol(c.primary_exchange.unwrap_or(String::new()));
-    tickers.put_symbol(c.r#type.unwrap_or(String::new()));
-    assert!(c.currency_name.is_some());
-    tickers.put_symbol(c.currency_name.unwrap_or(String::new()));
-    tickers.put_symbol(c.cik.unwrap_or(String::new()));
-    tickers.put_symbol(c.composite_figi.unwrap_or(String::new()));
-    tickers.put_symbol(c.share_class_figi.unwrap_or(String::new()));
-    tickers.write();
-  }
-  eprintln!("{}: Flushing {} candles", year, num_candles);
-  tickers.flush();
+  debug!("Writing {} tickers", num_candles);
+  let strings = |tickers_year: &mut Vec<(NaiveDate, Ticker)>, f: &dyn Fn(&mut Ticker) -> Option<String>| {
+    Values::Str(tickers_year.iter_mut().map(|(_, c)| f(c).unwrap_or(String::new())).collect())
+  };
+  assert!(tickers_year.iter().all(|(_, c)| c.currency_name.is_some()));
+  // name, cik and the FIGIs are high cardinality free text which zdb sinks put in StringHeaps
+  let columns = vec![
+    ("ts", Values::Timestamp(
+      tickers_year.iter().map(|(day, _)| day.and_hms(0, 0, 0).timestamp_nanos()).collect()
+    )),
+    ("sym", strings(&mut tickers_year, &|c| Some(std::mem::take(&mut c.symbol)))),
+    ("name", strings(&mut tickers_year, &|c| Some(std::mem::take(&mut c.name)))),
+    ("primary_exchange", strings(&mut tickers_year, &|c| c.primary_exchange.take())),
+    ("type", strings(&mut tickers_year, &|c| c.r#type.take())),
+    ("currency_name", strings(&mut tickers_year, &|c| c.currency_name.take())),
+    ("cik", strings(&mut tickers_year, &|c| c.cik.take())),
+    ("composite_figi", strings(&mut tickers_year, &|c| c.composite_figi.take())),
+    ("share_class_figi", strings(&mut tickers_year, &|c| c.share_class_figi.take())),
+    ("market", strings(&mut tickers_year, &|c| Some(std::mem::take(&mut c.market)))),
+    ("locale", strings(&mut tickers_year, &|c| Some(std::mem::take(&mut c.locale)))),
+    ("active", Values::U8(tickers_year.iter().map(|(_, c)| c.active as u8).collect())),
+    ("last_updated_utc", Values::I64(
+      tickers_year.iter().map(|(_, c)| c.last_updated_utc.timestamp_nanos()).collect()
+    )),
+    // 0 means still listed
+    ("delisted_utc", Values::I64(
+      tickers_year
+        .iter()
+        .map(|(_, c)| c.delisted_utc.map(|d| d.timestamp_nanos()).unwrap_or(0))
+        .collect()
+    )),
+  ];
+  tickers_year.clear();
+  report::timed("tickers", Phase::Write, || tickers.append(batch(&year.to_string(), columns)));
+  debug!("Flushing {} tickers", num_candles);
+  report::timed("tickers", Phase::Flush, || tickers.flush());
 
-  eprintln!("{}: done in {}s", year, now.elapsed().as_secs());
+  info!("Done in {}s", now.elapsed().as_secs());
 }
 
-pub fn download_tickers(thread_pool: &ThreadPool, client: &mut Client) {
-  let now = Instant::now();
-  // Setup DB
-  let schema = Schema::new("tickers")
-    .add_cols(vec![
-      Column::new("ts", ColumnType::Timestamp).with_resolution(24 * 60 * 60 * 1_000_000_000),
-      Column::new("sym", ColumnType::Symbol16).with_sym_name("us_equities"),
-      Column::new("name", ColumnType::Symbol16),
-      Column::new("primary_exchange", ColumnType::Symbol8),
-      Column::new("type", ColumnType::Symbol8),
-      Column::new("currency_name", ColumnType::Symbol8),
-      Column::new("cik", ColumnType::Symbol16),
-      Column::new("composite_figi", ColumnType::Symbol16),
-      Column::new("share_class_figi", ColumnType::Symbol16),
-    ])
-    .partition_by(PartitionBy::Year);
+pub fn tickers_schema() -> Schema { table_spec("tickers").unwrap().schema(Vec::new()) }
 
-  let mut tickers = Table::create_or_open(schema).expect("Could not open table");
-  let from = 2004;
-  let to = (Utc::now().date() - Duration::days(1)).year();
-  eprintln!("Downloading tickers");
-  for i in (from..=to).rev() {
-    if tickers.partition_meta.get(&format!("{}", i)).is_none() || i == to {
-      download_tickers_year(i, &thread_pool, &mut tickers, client);
+pub fn download_tickers(
+  thread_pool: &ThreadPool,
+  clients: &Clients,
+  tickers: &mut dyn Sink,
+  config: &DatasetConfig
+) {
+  let now = Instant::now();
+  let from = config.from.year();
+  let to = (config.until(Utc::now().naive_utc().date()) - Duration::days(1)).year();
+  let _span = info_span!("download", dataset = "tickers").entered();
+  info!("Downloading");
+  let mut years = (from..=to).rev().collect::<Vec<_>>();
+  // Streams must be in ts order
+  if tickers.is_stream() {
+    years.reverse();
+  }
+  for i in years {
+    if shutdown::requested() {
+      break;
+    }
+    if tickers.partition_to_ts(&format!("{}", i)).is_none() || i == to {
+      download_tickers_year(i, &thread_pool, tickers, clients, config);
     }
   }
-  eprintln!("Downloaded tickers in {}s", now.elapsed().as_secs());
+  tickers.finish();
+  info!("Downloaded in {}s", now.elapsed().as_secs());
 }
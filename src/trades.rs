@@ -1,178 +1,214 @@
 extern crate polygon_io;
-use crate::util::MarketDays;
+use crate::{
+  agg1d::agg1d_symbols,
+  auth::Clients,
+  batch::{batch, table_spec, Values},
+  conditions::from_polygon,
+  config::DatasetConfig,
+  logging::Progress,
+  metrics,
+  report::{self, Phase},
+  retry::{give_up, retry, ErrorClass},
+  shutdown,
+  sink::Sink,
+  util::MarketDays
+};
 use chrono::{NaiveDate, Utc, Duration};
 use polygon_io::{
-  client::Client,
   equities::trades::Trade
 };
 use std::{
-  collections::HashSet,
-  io::ErrorKind,
-  process,
   sync::{Arc, Mutex},
   time::Instant
 };
 use threadpool::ThreadPool;
+use tracing::{debug, info, info_span, Span};
 use zdb::{
-  schema::{Column, ColumnType, PartitionBy, Schema},
+  schema::Schema,
   table::Table
 };
-use std::sync::atomic::{AtomicUsize, Ordering};
+
+// A trade from either the REST API or flat files
+pub struct TradeRow {
+  pub ts:             i64,
+  pub ts_participant: i64,
+  pub id:             u64,
+  pub seq_id:         u64,
+  pub symbol:         String,
+  pub size:           u32,
+  pub price:          f64,
+  pub conditions:     u32,
+  pub error:          u8,
+  pub exchange:       u8,
+  pub tape:           u8
+}
+
+impl From<Trade> for TradeRow {
+  fn from(t: Trade) -> TradeRow {
+    TradeRow {
+      ts:             t.ts,
+      ts_participant: t.ts_participant.unwrap_or(0),
+      id:             t.id,
+      seq_id:         t.seq_id,
+      symbol:         t.symbol,
+      size:           t.size,
+      price:          t.price,
+      conditions:     from_polygon(t.conditions),
+      error:          t.error,
+      exchange:       t.exchange,
+      tape:           t.tape
+    }
+  }
+}
 
 fn download_trades_day(
   date: NaiveDate,
   thread_pool: &ThreadPool,
   agg1d: &Table,
-  trades_table: &mut Table,
-  client: &mut Client
+  trades_table: &mut dyn Sink,
+  clients: &Clients,
+  config: &DatasetConfig
 ) {
   let now = Instant::now();
+  let _span = info_span!("partition", partition = %date).entered();
+  metrics::set_partition("trades", &date.to_string());
   let from = date.clone();
-  eprintln!(
-    "{}: Scanning agg1d for symbols",
-    date
-  );
-  let mut symbols = HashSet::<String>::default();
-  let partitions = agg1d.partition_iter(
-    from.and_hms(0, 0, 0).timestamp_nanos(),
-    from.and_hms(0, 0, 0).timestamp_nanos(),
-    vec!["sym"]
-  );
-  for partition in partitions {
-    for sym_index in partition[0].get_u16() {
-      let sym = partition[0].symbols[*sym_index as usize - 1].clone();
-      symbols.insert(sym);
-    }
+  info!("Scanning agg1d for symbols");
+  let mut symbols = agg1d_symbols(agg1d, from, from, false);
+
+  if let Some(only) = &config.symbols {
+    symbols.retain(|sym| only.contains(sym));
   }
 
-  eprintln!(
-    "{}: Downloading trades for {} symbols",
-    date,
-    symbols.len()
-  );
-  let trades = Arc::new(Mutex::new(Vec::<Trade>::new()));
-  let counter = Arc::new(AtomicUsize::new(0));
-  let num_syms = symbols.len();
-  eprintln!("{:5} / {:5} symbols", 0, num_syms);
+  info!("Downloading trades for {} symbols", symbols.len());
+  let trades = Arc::new(Mutex::new(Vec::<TradeRow>::new()));
+  let fetch_start = Instant::now();
+  let progress = Progress::new("symbols", symbols.len());
+  let span = Span::current();
   for sym in symbols.iter() {
-    let day_format = date.clone();
     let sym = sym.clone();
     let trades_day = Arc::clone(&trades);
-    let mut client = client.clone();
-    let counter = counter.clone();
+    let mut client = clients.next();
+    let progress = progress.clone();
+    let span = span.clone();
+    let policy = config.retry;
     thread_pool.execute(move || {
-      // Retry up to 50 times
-      for j in 0..50 {
-        match client.get_all_trades(&sym, date) {
-          Ok(mut resp) => {
-            // println!("{} {:6}: {} candles", month_format, sym, candles.len());
-            trades_day.lock().unwrap().append(&mut resp);
-            counter.fetch_add(1, Ordering::Relaxed);
-            println!("\x1b[1A\x1b[K{:5} / {:5} symbols [{}]", counter.load(Ordering::Relaxed), num_syms, sym);
-            return;
-          }
-          Err(e) => {
-            match e.kind() {
-              ErrorKind::UnexpectedEof => {
-                eprintln!("{} {:6}: No data\n", day_format, sym);
-                return;
-              }
-              _ => {
-                eprintln!(
-                  "{} {:6}: get_trades retry {}: {}\n",
-                  day_format,
-                  sym,
-                  j + 1,
-                  e.to_string()
-                );
-                std::thread::sleep(std::time::Duration::from_secs(j + 1));
-              }
-            }
-          }
+      let _span = info_span!(parent: &span, "fetch", symbol = %sym).entered();
+      if shutdown::requested() {
+        return;
+      }
+      match retry("trades", "get_all_trades", &policy, || client.get_all_trades(&sym, date)) {
+        Ok(mut resp) => {
+          trades_day.lock().unwrap().extend(resp.drain(..).map(TradeRow::from));
+          progress.inc(&sym);
+        }
+        // Give up if there's no data. We'll get the ticks later.
+        Err(e) if e.class == ErrorClass::NoData => {
+          debug!("No data");
+          report::no_data("trades", &date.to_string(), &sym);
+          progress.inc(&sym);
         }
+        Err(e) => give_up("trades", "get_all_trades", &e)
       }
-      eprintln!("{} {:6}: failure", day_format, sym);
-      process::exit(1);
     });
   }
 
   thread_pool.join();
+  report::phase("trades", Phase::Fetch, fetch_start.elapsed());
+  // Writing part of a partition would leave gaps that look downloaded
+  if !progress.is_done() {
+    shutdown::discard("trades", &date.to_string());
+    return;
+  }
 
   let mut trades = trades.lock().unwrap();
-  let num_trades = trades.len();
-  // Sort by seq_id which is also ts
-  eprintln!("{}: Sorting {} trades", date, num_trades);
-  trades.sort_unstable_by(|t1, t2| t1.seq_id.cmp(&t2.seq_id));
+  write_trades(trades_table, &mut trades, date);
 
-  eprintln!("{}: Writing {} trades", date, num_trades);
-  for t in trades.drain(..) {
-    trades_table.put_timestamp(t.ts);
-    trades_table.put_i64(t.ts_participant.unwrap_or(0));
-    trades_table.put_u64(t.id);
-    trades_table.put_u64(t.seq_id);
-    trades_table.put_symbol(t.symbol.clone());
-    trades_table.put_u32(t.size);
-    trades_table.put_f64(t.price);
-    trades_table.put_u32(t.conditions);
-    trades_table.put_u8(t.error);
-    trades_table.put_u8(t.exchange);
-    trades_table.put_u8(t.tape);
-    trades_table.write();
-  }
-  eprintln!("{}: Flushing {} trades", date, num_trades);
-  trades_table.flush();
-  assert_eq!(trades_table.cur_partition_meta.row_count, num_trades);
-  let num_rows_inserted = match trades_table.partition_meta.get(&date.to_string()) {
-    Some(meta) => meta.row_count,
-    None => 0
+  info!("Downloaded in {}s", now.elapsed().as_secs())
+}
+
+// Sorts and writes a day of trades then flushes. Shared by the REST and flat file importers.
+pub fn write_trades(trades_table: &mut dyn Sink, trades: &mut Vec<TradeRow>, date: NaiveDate) {
+  let num_trades = trades.len();
+  // Like quotes, sort by ts first since live and flat file sequence numbers needn't follow it
+  debug!(%date, "Sorting {} trades", num_trades);
+  report::timed("trades", Phase::Sort, || {
+    trades.sort_unstable_by(|t1, t2| t1.ts.cmp(&t2.ts).then(t1.seq_id.cmp(&t2.seq_id)))
+  });
+  // Live ingestion appends to the same partitions many times a day. A New York day's trades
+  // after midnight UTC go in the next day's.
+  let partitions = match (trades.first(), trades.last()) {
+    (Some(first), Some(last)) => table_spec("trades").unwrap().partitions(first.ts, last.ts),
+    _ => Vec::new()
   };
-  assert_eq!(num_rows_inserted, num_trades);
+  let num_rows_before = partitions
+    .iter()
+    .map(|p| trades_table.partition_row_count(p).unwrap_or(0))
+    .sum::<usize>();
+  debug!(%date, "Writing {} trades", num_trades);
+  let rows = batch(&date.to_string(), vec![
+    ("ts", Values::Timestamp(trades.iter().map(|t| t.ts).collect())),
+    ("ts_participant", Values::I64(trades.iter().map(|t| t.ts_participant).collect())),
+    ("id", Values::U64(trades.iter().map(|t| t.id).collect())),
+    ("seq_id", Values::U64(trades.iter().map(|t| t.seq_id).collect())),
+    ("sym", Values::Str(trades.iter_mut().map(|t| std::mem::take(&mut t.symbol)).collect())),
+    ("size", Values::U32(trades.iter().map(|t| t.size).collect())),
+    ("price", Values::F64(trades.iter().map(|t| t.price).collect())),
+    ("cond", Values::U32(trades.iter().map(|t| t.conditions).collect())),
+    ("err", Values::U8(trades.iter().map(|t| t.error).collect())),
+    ("exchange", Values::U8(trades.iter().map(|t| t.exchange).collect())),
+    ("tape", Values::U8(trades.iter().map(|t| t.tape).collect())),
+  ]);
+  trades.clear();
+  report::timed("trades", Phase::Write, || trades_table.append(rows));
+  debug!(%date, "Flushing {} trades", num_trades);
+  report::timed("trades", Phase::Flush, || trades_table.flush());
+  // Streams don't keep row counts
+  let counts = partitions.iter().map(|p| trades_table.partition_row_count(p));
+  if let Some(num_rows_inserted) = counts.sum::<Option<usize>>() {
+    assert_eq!(num_rows_inserted, num_rows_before + num_trades);
+  }
+}
 
-  eprintln!(
-    "{}: downloaded in {}s",
-    date,
-    now.elapsed().as_secs()
-  )
+pub fn trades_schema(partition_dirs: Vec<&str>) -> Schema {
+  table_spec("trades").unwrap().schema(partition_dirs)
 }
 
-pub fn download_trades(thread_pool: &ThreadPool, client: &mut Client, partition_dirs: Vec<&str>) {
+pub fn download_trades(
+  thread_pool: &ThreadPool,
+  clients: &Clients,
+  trades: &mut dyn Sink,
+  config: &DatasetConfig
+) {
+  let _span = info_span!("download", dataset = "trades").entered();
   // Get existing symbols
   let agg1d =
     Table::open("agg1d").expect("Table agg1d must exist to load symbols to download in trades");
-  // Setup DB
-  let schema = Schema::new("trades")
-    .add_cols(vec![
-      Column::new("ts", ColumnType::Timestamp),
-      Column::new("ts_participant", ColumnType::I64),
-      Column::new("id", ColumnType::U64),
-      Column::new("seq_id", ColumnType::U64),
-      Column::new("sym", ColumnType::Symbol16).with_sym_name("us_equities"),
-      Column::new("size", ColumnType::U32),
-      Column::new("price", ColumnType::F64),
-      Column::new("cond", ColumnType::U32),
-      Column::new("err", ColumnType::U8),
-      Column::new("exchange", ColumnType::U8),
-      Column::new("tape", ColumnType::U8),
-    ])
-    .partition_dirs(partition_dirs)
-    .partition_by(PartitionBy::Day);
-
-  let mut trades = Table::create_or_open(schema).expect("Could not open table");
-  let from = NaiveDate::from_ymd(2004, 1, 1);
-  let to = Utc::now().naive_utc().date();
-  let market_days = (MarketDays { from, to }).collect::<Vec<NaiveDate>>();
-  for day in market_days.into_iter().rev() {
-    if trades.partition_meta.get(&format!("{}", day.format("%Y-%m-%d"))).is_none() {
-      eprintln!("Downloading {}", day);
+  let from = config.from;
+  let to = config.until(Utc::now().naive_utc().date());
+  let mut market_days = (MarketDays { from, to }).collect::<Vec<NaiveDate>>();
+  // Streams must be in ts order
+  if !trades.is_stream() {
+    market_days.reverse();
+  }
+  for day in market_days.into_iter() {
+    if shutdown::requested() {
+      break;
+    }
+    let partition = day.format("%Y-%m-%d").to_string();
+    // Replaced partitions lost the rows a live session streamed
+    if trades.partition_to_ts(&partition).is_none() || trades.replaced().contains(&partition) {
       download_trades_day(
         day,
         &thread_pool,
         &agg1d,
-        &mut trades,
-        client
+        trades,
+        clients,
+        config
       );
     } else if day == to - Duration::days(1) {
-      eprintln!("Already downloaded trades for {}!", day);
+      info!("Already downloaded {}", day);
     }
   }
+  trades.finish();
 }
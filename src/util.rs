@@ -1,6 +1,14 @@
-use chrono::{Duration, NaiveDate};
+use chrono::{DateTime, Duration, NaiveDate, NaiveTime, TimeZone, Utc};
+use chrono_tz::America::New_York;
+use lazy_static::lazy_static;
+use polygon_io::core::Candle;
+use std::{path::PathBuf, sync::RwLock};
 use zdb::calendar::us_equity::is_market_open;
 
+lazy_static! {
+  static ref DATA_ROOT: RwLock<PathBuf> = RwLock::new(PathBuf::from("data"));
+}
+
 pub struct MarketDays {
   pub from: NaiveDate,
   pub to:   NaiveDate
@@ -22,3 +30,104 @@ impl Iterator for MarketDays {
     None
   }
 }
+
+// A candle from either the REST API or flat files
+pub struct Bar {
+  pub ts:     i64,
+  pub symbol: String,
+  pub open:   f64,
+  pub high:   f64,
+  pub low:    f64,
+  pub close:  f64,
+  pub volume: u64
+}
+
+impl From<Candle> for Bar {
+  fn from(c: Candle) -> Bar {
+    Bar {
+      ts:     c.ts,
+      symbol: c.symbol,
+      open:   c.open,
+      high:   c.high,
+      low:    c.low,
+      close:  c.close,
+      volume: c.volume
+    }
+  }
+}
+
+// Sorts by ts, symbol
+pub fn sort_bars(bars: &mut Vec<Bar>) {
+  bars.sort_unstable_by(|c1, c2| {
+    if c1.ts == c2.ts {
+      c1.symbol.cmp(&c2.symbol)
+    } else {
+      c1.ts.cmp(&c2.ts)
+    }
+  });
+}
+
+// Takes the bars from `day` on, in New York, out of `bars`
+pub fn split_provisional(bars: &mut Vec<Bar>, day: NaiveDate) -> Vec<Bar> {
+  let from_ts = new_york(day, NaiveTime::from_hms(0, 0, 0)).timestamp_nanos();
+  let (provisional, settled) = bars.drain(..).partition(|b| b.ts >= from_ts);
+  *bars = settled;
+
+  provisional
+}
+
+// Must match where zdb keeps tables' metadata since polyzdb keeps each table's heaps, locks
+// and journals next to it. Its own state like reports and resume.json goes there too.
+pub fn set_data_root(root: &str) { *DATA_ROOT.write().unwrap() = PathBuf::from(root); }
+
+pub fn data_root() -> PathBuf { DATA_ROOT.read().unwrap().clone() }
+
+// A scratch data root shared by every test in this process. Tests use their own table names.
+#[cfg(test)]
+pub fn test_data_root() -> PathBuf {
+  let root = std::env::temp_dir().join(format!("polyzdb-test-{}", std::process::id()));
+  set_data_root(root.to_str().unwrap());
+  root
+}
+
+// Where zdb keeps a table's metadata and symbol files
+pub fn table_dir(table: &str) -> PathBuf { data_root().join(table) }
+
+// When it's `time` on `day` in New York, where the market's hours are set
+pub fn new_york(day: NaiveDate, time: NaiveTime) -> DateTime<Utc> {
+  New_York.from_local_datetime(&day.and_time(time)).earliest().unwrap().with_timezone(&Utc)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn bar(ts: DateTime<Utc>, symbol: &str) -> Bar {
+    Bar {
+      ts:     ts.timestamp_nanos(),
+      symbol: symbol.to_string(),
+      open:   1.0,
+      high:   1.0,
+      low:    1.0,
+      close:  1.0,
+      volume: 1
+    }
+  }
+
+  #[test]
+  fn splits_provisional_in_new_york() {
+    let day = NaiveDate::from_ymd(2021, 3, 4);
+    let mut bars = vec![
+      bar(new_york(day.pred(), NaiveTime::from_hms(19, 59, 0)), "AAPL"),
+      // 00:30 UTC on the 4th is still the 3rd in New York
+      bar(new_york(day.pred(), NaiveTime::from_hms(19, 30, 0)), "MSFT"),
+      bar(new_york(day, NaiveTime::from_hms(0, 0, 0)), "AAPL"),
+      bar(new_york(day, NaiveTime::from_hms(16, 0, 0)), "MSFT"),
+    ];
+    let provisional = split_provisional(&mut bars, day);
+    assert_eq!(bars.iter().map(|b| b.symbol.as_str()).collect::<Vec<_>>(), ["AAPL", "MSFT"]);
+    assert_eq!(provisional.len(), 2);
+    let from_ts = new_york(day, NaiveTime::from_hms(0, 0, 0)).timestamp_nanos();
+    assert!(provisional.iter().all(|b| b.ts >= from_ts));
+  }
+}